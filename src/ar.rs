@@ -0,0 +1,228 @@
+//! The command line of `orange-ar`, which packs objects and routines into archives for
+//! `orange-ld` to take members from.
+
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use crate::archive::{Archive, Member};
+use crate::object::{self, Binding, Object};
+use crate::{is_stdio, parse_define, read_input, write_output, Args, Assembler};
+
+pub const USAGE: &str = "\
+Usage: orange-ar [OPTIONS] ARCHIVE INPUT...
+       orange-ar -t ARCHIVE
+
+Packs each INPUT into ARCHIVE with an index of the global symbols its members define, so
+that orange-ld only links the members a program uses. An object written by
+orange_assembler -c becomes one member named after its file. A source is assembled and
+split into a member per routine, each starting at a .global label in .text.
+
+Options:
+  -D NAME[=VALUE]      Define NAME before the first line of each source (VALUE defaults to 1)
+  -I DIR               Search DIR for .include files
+  -t                   List the members of ARCHIVE and the global symbols they define
+  -h, --help           Print this help
+  -V, --version        Print the version
+";
+
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Create,
+    List,
+    Help,
+    Version,
+}
+
+#[derive(Debug)]
+pub struct Config {
+    pub action: Action,
+    /// `-` writes standard output, or reads standard input when listing.
+    pub archive_path: PathBuf,
+    pub input_paths: Vec<PathBuf>,
+    pub include_paths: Vec<PathBuf>,
+    pub defines: Vec<(String, i64)>,
+}
+
+impl Config {
+    pub fn new(args: &[String]) -> Result<Config, Box<dyn Error>> {
+        let mut action = Action::Create;
+        let mut paths = Vec::new();
+        let mut include_paths = Vec::new();
+        let mut defines = Vec::new();
+
+        let mut args = Args::new(args);
+        while let Some(arg) = args.next_option() {
+            match arg {
+                "-h" | "--help" => return Ok(Config::help(Action::Help)),
+                "-V" | "--version" => return Ok(Config::help(Action::Version)),
+                "-t" => action = Action::List,
+                "-I" => include_paths.push(PathBuf::from(args.value(arg)?)),
+                "-D" => defines.push(parse_define(&args.value(arg)?)?),
+                "-" => paths.push(PathBuf::from(arg)),
+                x if x.starts_with("-I") => include_paths.push(PathBuf::from(&x[2..])),
+                x if x.starts_with("-D") => defines.push(parse_define(&x[2..])?),
+                x if x.starts_with('-') => bail!(format!("unknown option \"{}\"", arg)),
+                x => paths.push(PathBuf::from(x)),
+            }
+        }
+
+        let mut paths = paths.into_iter();
+        let archive_path = match paths.next() {
+            Some(x) => x,
+            None => bail!("no archive given"),
+        };
+        let input_paths: Vec<PathBuf> = paths.collect();
+        match action {
+            Action::List if !input_paths.is_empty() => bail!("-t takes only the archive"),
+            Action::Create if input_paths.is_empty() => bail!("no inputs given"),
+            _ => (),
+        }
+        if input_paths.iter().any(|x| is_stdio(x)) {
+            bail!("inputs must be files, as members are named after them");
+        }
+
+        Ok(Config {
+            action,
+            archive_path,
+            input_paths,
+            include_paths,
+            defines,
+        })
+    }
+
+    fn help(action: Action) -> Config {
+        Config {
+            action,
+            archive_path: PathBuf::new(),
+            input_paths: Vec::new(),
+            include_paths: Vec::new(),
+            defines: Vec::new(),
+        }
+    }
+}
+
+pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
+    match config.action {
+        Action::Help => {
+            print!("{}", USAGE);
+            return Ok(());
+        },
+        Action::Version => {
+            println!("orange-ar {}", env!("CARGO_PKG_VERSION"));
+            return Ok(());
+        },
+        Action::List => {
+            let archive = read_archive(&config.archive_path)?;
+            return write_output(Path::new("-"), list(&archive).as_bytes());
+        },
+        Action::Create => (),
+    }
+
+    let mut archive = Archive::new();
+    for path in &config.input_paths {
+        for member in members(&config, path)? {
+            if let Err(e) = archive.add(member) {
+                bail!(format!("{}: {}", path.display(), e));
+            }
+        }
+    }
+    write_output(&config.archive_path, archive.write().as_bytes())
+}
+
+/// The members `path` adds: the object it holds, or the routines of the source it holds.
+fn members(config: &Config, path: &Path) -> Result<Vec<Member>, Box<dyn Error>> {
+    let text = read_input(path)?;
+    let name = match path.file_stem() {
+        Some(x) => x.to_string_lossy().to_string(),
+        None => bail!(format!("Cannot name a member after \"{}\"", path.display())),
+    };
+    if text.lines().next().map(str::trim) == Some(object::MAGIC) {
+        return match Object::read(&text) {
+            Ok(object) => Ok(vec![Member { name, object }]),
+            Err(e) => bail!(format!("Could not read object \"{}\": {}", path.display(), e)),
+        };
+    }
+
+    let mut assembler = Assembler::new();
+    assembler.source(path, &text);
+    for path in &config.include_paths {
+        assembler.include_path(path);
+    }
+    for (name, value) in &config.defines {
+        assembler.define(name, *value);
+    }
+    let assembly = assembler.assemble_object()?;
+    for warning in &assembly.diagnostics {
+        eprintln!("Warning: {}", warning);
+    }
+    assembly.members(&name)
+}
+
+fn read_archive(path: &Path) -> Result<Archive, Box<dyn Error>> {
+    match Archive::read(&read_input(path)?) {
+        Ok(x) => Ok(x),
+        Err(e) => bail!(format!("Could not read archive \"{}\": {}", path.display(), e)),
+    }
+}
+
+/// One line per member: its name and the global symbols it defines.
+fn list(archive: &Archive) -> String {
+    let mut s = String::new();
+    for member in archive.members() {
+        let globals: Vec<&str> = member.object.symbols.iter()
+            .filter(|x| x.binding == Binding::Global)
+            .map(|x| x.name.as_str())
+            .collect();
+        s.push_str(&format!("{}: {}\n", member.name, globals.join(" ")));
+    }
+    s
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn config(args: &str) -> Result<Config, Box<dyn Error>> {
+        let args: Vec<String> = args.split_whitespace().map(String::from).collect();
+        Config::new(&args)
+    }
+
+    #[test]
+    fn config_test() {
+        let c = config("orange-ar lib.a uart.asm print.o").unwrap();
+        assert_eq!(c.action, Action::Create);
+        assert_eq!(c.archive_path, PathBuf::from("lib.a"));
+        assert_eq!(c.input_paths, vec![PathBuf::from("uart.asm"), PathBuf::from("print.o")]);
+
+        let c = config("orange-ar -I inc -DBOARD=2 lib.a uart.asm").unwrap();
+        assert_eq!(c.include_paths, vec![PathBuf::from("inc")]);
+        assert_eq!(c.defines, vec![(String::from("BOARD"), 2)]);
+
+        let c = config("orange-ar -t lib.a").unwrap();
+        assert_eq!(c.action, Action::List);
+        assert!(c.input_paths.is_empty());
+
+        let invalid_tests = [
+            "orange-ar",
+            "orange-ar lib.a",
+            "orange-ar -t lib.a uart.o",
+            "orange-ar lib.a -",
+            "orange-ar -x lib.a uart.o",
+            "orange-ar -I",
+        ];
+        for test in &invalid_tests {
+            assert!(config(test).is_err(), "failed with [{}]", test);
+        }
+    }
+
+    #[test]
+    fn list_test() {
+        let prog = crate::prog::Prog::new("uart.asm", ".global RX, TX\nRX: ret\nHELPER: ret\nTX: ret").unwrap();
+        let mut archive = Archive::new();
+        for member in prog.members("uart").unwrap() {
+            archive.add(member).unwrap();
+        }
+        assert_eq!(list(&archive), "RX: RX\nTX: TX\n");
+    }
+}
@@ -0,0 +1,163 @@
+//! Archives of relocatable objects, such as a library of shared routines, from which a link
+//! only takes the members defining symbols it still needs.
+//!
+//! Archives are written as text: an `orange-archive 1` heading, an index naming the member
+//! that defines each global symbol, and then each member as a `member` line followed by its
+//! object:
+//!
+//! ```text
+//! orange-archive 1
+//! index RX RX
+//! index TX TX
+//! member RX
+//! orange-object 1
+//! ...
+//! member TX
+//! orange-object 1
+//! ...
+//! ```
+
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use crate::lexer;
+use crate::object::{Binding, Object};
+
+/// The first line of every archive.
+pub const MAGIC: &str = "orange-archive 1";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Member {
+    pub name: String,
+    pub object: Object,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Archive {
+    members: Vec<Member>,
+    /// The member defining each global symbol.
+    index: BTreeMap<String, usize>,
+}
+
+impl Archive {
+    pub fn new() -> Archive {
+        Archive::default()
+    }
+
+    pub fn members(&self) -> &[Member] {
+        &self.members
+    }
+
+    /// Adds `member`, which may not share its name or a global symbol with another member.
+    pub fn add(&mut self, member: Member) -> Result<(), Box<dyn Error>> {
+        if self.members.iter().any(|x| x.name == member.name) {
+            bail!(format!("Member \"{}\" added more than once", member.name));
+        }
+        for symbol in member.object.symbols.iter().filter(|x| x.binding == Binding::Global) {
+            if let Some(x) = self.index.get(&symbol.name) {
+                bail!(format!("Symbol \"{}\" defined by both {} and {}", symbol.name, self.members[*x].name, member.name));
+            }
+        }
+        for symbol in member.object.symbols.iter().filter(|x| x.binding == Binding::Global) {
+            self.index.insert(symbol.name.clone(), self.members.len());
+        }
+        self.members.push(member);
+        Ok(())
+    }
+
+    /// The member defining global symbol `name`.
+    pub fn find(&self, name: &str) -> Option<&Member> {
+        self.index.get(name).map(|x| &self.members[*x])
+    }
+
+    pub fn write(&self) -> String {
+        let mut s = format!("{}\n", MAGIC);
+        for (name, member) in &self.index {
+            s.push_str(&format!("index {} {}\n", name, self.members[*member].name));
+        }
+        for member in &self.members {
+            s.push_str(&format!("member {}\n", member.name));
+            s.push_str(&member.object.write());
+        }
+        s
+    }
+
+    pub fn read(text: &str) -> Result<Archive, Box<dyn Error>> {
+        let mut lines = text.lines().enumerate();
+        match lines.next() {
+            Some((_, x)) if x.trim() == MAGIC => (),
+            _ => bail!(format!("Not an archive (expected \"{}\" first)", MAGIC)),
+        }
+
+        // The index, then the text of each member's object with the line it starts on.
+        let mut index = BTreeMap::new();
+        let mut members: Vec<(String, usize, String)> = Vec::new();
+        for (number, line) in lines {
+            let (fields, rest) = lexer::split_fields(line, 3);
+            match (fields.as_slice(), members.last_mut()) {
+                (["member", name], _) if rest.is_empty() => members.push((name.to_string(), number + 2, String::new())),
+                (_, Some(x)) => {
+                    x.2.push_str(line);
+                    x.2.push('\n');
+                },
+                ([], None) => (),
+                (["index", symbol, member], None) if rest.is_empty() => {
+                    index.insert(symbol.to_string(), member.to_string());
+                },
+                _ => bail!(format!("line {}: Could not interpret \"{}\"", number + 1, line.trim())),
+            }
+        }
+
+        let mut archive = Archive::new();
+        for (name, first, text) in members {
+            let object = match Object::read(&text) {
+                Ok(x) => x,
+                Err(e) => bail!(format!("member {} (from line {}): {}", name, first, e)),
+            };
+            archive.add(Member { name, object })?;
+        }
+        let actual: BTreeMap<String, String> = archive.index.iter()
+            .map(|(symbol, member)| (symbol.clone(), archive.members[*member].name.clone()))
+            .collect();
+        if index != actual {
+            bail!("The index does not match the members; rebuild the archive");
+        }
+        Ok(archive)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::prog::Prog;
+
+    #[test]
+    fn write_read_test() {
+        let prog = Prog::new("uart.asm", ".global RX, TX\n.equ UART, -32\nRX: ld r1, UART\n ret\nTX: st r1, UART + 4\n ret").unwrap();
+        let mut archive = Archive::new();
+        for member in prog.members("uart").unwrap() {
+            archive.add(member).unwrap();
+        }
+        let text = archive.write();
+        assert!(text.starts_with("orange-archive 1\nindex RX RX\nindex TX TX\nmember RX\norange-object 1\n"), "{}", text);
+        assert_eq!(Archive::read(&text).unwrap(), archive);
+        assert_eq!(archive.find("TX").unwrap().name, "TX");
+        assert!(archive.find("UART").is_none());
+
+        let duplicate = archive.members()[0].clone();
+        assert!(archive.add(duplicate.clone()).is_err());
+        assert!(archive.add(Member { name: String::from("copy"), ..duplicate }).is_err());
+
+        let invalid_tests = [
+            "orange-object 1",
+            "orange-archive 1\nbogus",
+            "orange-archive 1\nindex TX TX",
+            "orange-archive 1\nmember TX\nsection .text",
+            "orange-archive 1\nmember TX\norange-object 1\nmember TX\norange-object 1",
+        ];
+        for test in &invalid_tests {
+            assert!(Archive::read(test).is_err(), "failed with [{}]", test);
+        }
+    }
+}
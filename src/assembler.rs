@@ -0,0 +1,500 @@
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::archive::Member;
+use crate::checksum::{self, crc32, Request};
+use crate::elf;
+use crate::expr::Expr;
+use crate::map::{self, MapFormat};
+use crate::object::Object;
+use crate::output::{self, OutputFormat};
+use crate::prog::{self, Options, Prog, Symbol};
+use crate::source::{Source, SourceRef};
+use crate::text;
+
+/// Assembles a program from sources held in memory or on disk, without writing any files.
+///
+/// Sources are assembled in the order given as a single program, sharing their symbols.
+/// Include files given with `include_file` are found by `.include` before any file on disk.
+#[derive(Default)]
+pub struct Assembler {
+    /// Each source with its contents, or `None` to read it from disk.
+    sources: Vec<(PathBuf, Option<String>)>,
+    files: HashMap<PathBuf, String>,
+    include_paths: Vec<PathBuf>,
+    options: Options,
+    format: OutputFormat,
+    entry: Option<String>,
+    checksums: Vec<Request>,
+    debug_lines: bool,
+}
+
+/// An assembled program.
+pub struct Assembly {
+    /// Every emitted word, by address.
+    pub words: BTreeMap<usize, u32>,
+    /// Every label, `.equ` and define; local labels appear under their full `GLOBAL.local` name
+    /// and numeric labels are left out.
+    pub symbols: BTreeMap<String, usize>,
+    /// The same symbols with their kind and where they were defined, along with register
+    /// aliases and imports, in the order defined.
+    pub symbol_table: Vec<Symbol>,
+    /// Warnings, each starting with the line it concerns.
+    pub diagnostics: Vec<String>,
+    /// The line each word was assembled from, by address.
+    pub line_map: BTreeMap<usize, SourceRef>,
+    /// The start address, from `entry` or else `.entry`, written by the formats that have a
+    /// record for it.
+    pub entry: Option<usize>,
+    /// Each source given with the CRC-32 of its contents, which text output records.
+    pub sources: Vec<(PathBuf, u32)>,
+    pub format: OutputFormat,
+    /// Whether ELF output holds the line of each word.
+    pub debug_lines: bool,
+    /// The listing lines of the checksums given to the assembler.
+    checksums: String,
+    /// The address of each word in the order the source emits it, then any stored by a
+    /// checksum given to the assembler; the compatibility text layout keeps this order.
+    order: Vec<usize>,
+    prog: Prog,
+}
+
+/// A program assembled into a relocatable object.
+pub struct ObjectAssembly {
+    pub object: Object,
+    /// Warnings, each starting with the line it concerns.
+    pub diagnostics: Vec<String>,
+    prog: Prog,
+}
+
+impl Assembler {
+    pub fn new() -> Assembler {
+        Assembler::default()
+    }
+
+    /// Adds a source held in memory; `name` is used in messages and to resolve its includes.
+    pub fn source<P: AsRef<Path>>(&mut self, name: P, contents: &str) -> &mut Assembler {
+        self.sources.push((name.as_ref().to_path_buf(), Some(contents.to_string())));
+        self
+    }
+
+    /// Adds a source read from disk when assembling.
+    pub fn file<P: AsRef<Path>>(&mut self, path: P) -> &mut Assembler {
+        self.sources.push((path.as_ref().to_path_buf(), None));
+        self
+    }
+
+    /// Adds a file `.include` finds at `path`, as if it were on disk.
+    pub fn include_file<P: AsRef<Path>>(&mut self, path: P, contents: &str) -> &mut Assembler {
+        self.files.insert(path.as_ref().to_path_buf(), contents.to_string());
+        self
+    }
+
+    /// Adds a directory `.include` searches after the directory of the including file.
+    pub fn include_path<P: AsRef<Path>>(&mut self, path: P) -> &mut Assembler {
+        self.include_paths.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Defines `name` before the first line, as `-D name=value` does.
+    pub fn define(&mut self, name: &str, value: i64) -> &mut Assembler {
+        self.options.defines.push((name.to_string(), value));
+        self
+    }
+
+    /// Defines symbols and register aliases from another program, as read by `map::read`.
+    /// The program may define the same names itself.
+    pub fn import(&mut self, symbols: &[Symbol]) -> &mut Assembler {
+        self.options.imports.extend_from_slice(symbols);
+        self
+    }
+
+    /// Places section `name` at `address`, as a linker script line does.
+    pub fn section(&mut self, name: &str, address: usize) -> &mut Assembler {
+        self.options.sections.push((name.to_string(), address));
+        self
+    }
+
+    pub fn format(&mut self, format: OutputFormat) -> &mut Assembler {
+        self.format = format;
+        self
+    }
+
+    /// Adds the source line of each word to ELF output, for debuggers.
+    pub fn debug_lines(&mut self, debug_lines: bool) -> &mut Assembler {
+        self.debug_lines = debug_lines;
+        self
+    }
+
+    /// Sets the start address to an expression of numbers and symbols, such as `START`, in
+    /// place of any given by `.entry`.
+    pub fn entry(&mut self, entry: &str) -> &mut Assembler {
+        self.entry = Some(entry.to_string());
+        self
+    }
+
+    /// Stores a checksum once every word is known, after those of `.crc32` and `.checksum`.
+    pub fn checksum(&mut self, request: Request) -> &mut Assembler {
+        self.checksums.push(request);
+        self
+    }
+
+    pub fn assemble(&self) -> Result<Assembly, Box<dyn Error>> {
+        let sources = self.read_sources()?;
+        let prog = self.prog(&sources)?;
+
+        let mut words = BTreeMap::new();
+        let mut line_map = BTreeMap::new();
+        let mut order = Vec::new();
+        for word in prog.words()? {
+            order.push(word.address);
+            words.insert(word.address, word.value);
+            line_map.insert(word.address, prog.source.source_ref(word.loc));
+        }
+        let symbols = prog.symbols().iter()
+            .filter(|(name, _)| !name.starts_with('.'))
+            .map(|(name, value)| (name.clone(), *value))
+            .collect();
+        let symbol_table = prog.definitions().iter()
+            .filter(|x| !x.name.starts_with('.'))
+            .cloned()
+            .collect();
+        let lookup = |s: &str| prog.symbols().get(s).map(|x| *x as i64);
+        let checksums = checksum::apply(&self.checksums, &mut words, &lookup)?;
+        order.extend(words.keys().filter(|x| !line_map.contains_key(*x)));
+        let entry = match &self.entry {
+            Some(x) => match Expr::parse(x).and_then(|e| e.eval(&lookup)).and_then(prog::entry_address) {
+                Ok(x) => Some(x),
+                Err(e) => bail!(format!("Invalid entry point \"{}\": {}", x, e)),
+            },
+            None => prog.entry()?,
+        };
+        if let Some(x) = entry {
+            // An entry point from `.entry` is reported at its line.
+            let line = self.entry.as_ref().map_or_else(|| prog.entry_line(), |_| None);
+            if let Err(e) = prog::check_entry(x, &words, &prog.sections) {
+                match line {
+                    Some(line) => bail!(format!("{}: {}", line, e)),
+                    None => return Err(e),
+                }
+            }
+        }
+
+        Ok(Assembly {
+            words,
+            symbols,
+            symbol_table,
+            diagnostics: prog.warnings.clone(),
+            line_map,
+            entry,
+            sources: sources.iter().map(|(path, contents)| (path.clone(), crc32(contents.as_bytes()))).collect(),
+            format: self.format,
+            debug_lines: self.debug_lines,
+            checksums,
+            order,
+            prog,
+        })
+    }
+
+    /// Assembles into a relocatable object for a linker to place. Symbols declared `.extern`
+    /// may be left undefined.
+    pub fn assemble_object(&self) -> Result<ObjectAssembly, Box<dyn Error>> {
+        let prog = self.prog(&self.read_sources()?)?;
+        Ok(ObjectAssembly {
+            object: prog.object()?,
+            diagnostics: prog.warnings.clone(),
+            prog,
+        })
+    }
+
+    /// Each source with its contents, reading those not held in memory.
+    fn read_sources(&self) -> Result<Vec<(PathBuf, String)>, Box<dyn Error>> {
+        if self.sources.is_empty() {
+            bail!("No sources to assemble");
+        }
+        let mut sources = Vec::new();
+        for (path, contents) in &self.sources {
+            let contents = match contents {
+                Some(x) => x.clone(),
+                None => match fs::read_to_string(path) {
+                    Ok(x) => x,
+                    Err(e) => bail!(format!("Could not read \"{}\": {}", path.display(), e)),
+                },
+            };
+            sources.push((path.clone(), contents));
+        }
+        Ok(sources)
+    }
+
+    fn prog(&self, sources: &[(PathBuf, String)]) -> Result<Prog, Box<dyn Error>> {
+        let source = Source::from_sources(sources, &self.include_paths, &self.files, &self.options.known_symbols())?;
+        Prog::from_source(source, &self.options)
+    }
+}
+
+impl Assembly {
+    /// The words written in `format`, with the sources in text output and the symbols in
+    /// ELF output.
+    pub fn output(&self) -> Vec<u8> {
+        match self.format {
+            OutputFormat::Text => text::write(&self.words, self.entry, &self.sources).into_bytes(),
+            OutputFormat::TextCompat => {
+                let words: Vec<(usize, u32)> = self.order.iter().map(|x| (*x, self.words[x])).collect();
+                text::write_compat(&words).into_bytes()
+            },
+            OutputFormat::Elf => elf::write(&elf::Image {
+                words: &self.words,
+                entry: self.entry,
+                symbols: &self.symbol_table,
+                globals: self.prog.globals(),
+                sections: &self.prog.sections,
+                lines: Some(&self.line_map).filter(|_| self.debug_lines),
+            }),
+            x => output::write(&self.words, self.entry, x),
+        }
+    }
+
+    /// The symbol table written as a map file in `format`, followed in the text format by
+    /// where each section went.
+    pub fn map(&self, format: MapFormat) -> String {
+        let mut s = map::write(&self.symbol_table, format);
+        if format == MapFormat::Text {
+            s.push_str(&self.prog.placements());
+        }
+        s
+    }
+
+    /// See `Prog::listing`, followed by the checksums given to the assembler.
+    pub fn listing(&self) -> Result<String, Box<dyn Error>> {
+        Ok(self.prog.listing()? + &self.checksums)
+    }
+
+    /// A make rule for `target` listing every file read, as `Source::make_rule` builds.
+    pub fn make_rule(&self, target: &Path) -> String {
+        self.prog.source.make_rule(target)
+    }
+}
+
+impl ObjectAssembly {
+    /// The program split into a member per routine, as `Prog::members` splits it; lines
+    /// before the first routine form a member called `name`.
+    pub fn members(&self, name: &str) -> Result<Vec<Member>, Box<dyn Error>> {
+        self.prog.members(name)
+    }
+
+    /// See `Assembly::make_rule`.
+    pub fn make_rule(&self, target: &Path) -> String {
+        self.prog.source.make_rule(target)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn assemble_test() {
+        let assembly = Assembler::new()
+            .source("main.asm", ".include \"uart.inc\"\nSTART: la r1, UART\n.org 16\nEND: stop")
+            .source("second.asm", "\tadd r1, r2, r3 ; after END")
+            .include_file("lib/uart.inc", ".equ UART, BOARD * 4")
+            .include_path("lib")
+            .define("BOARD", 2)
+            .format(OutputFormat::IntelHex)
+            .assemble()
+            .unwrap();
+
+        let words: Vec<(usize, u32)> = assembly.words.iter().map(|(a, w)| (*a, *w)).collect();
+        assert_eq!(words, vec![(0, 0x28400008), (16, 0xf8000000), (20, 0x60443000)]);
+        let symbols: Vec<(&str, usize)> = assembly.symbols.iter().map(|(s, v)| (s.as_str(), *v)).collect();
+        assert_eq!(symbols, vec![("BOARD", 2), ("END", 16), ("START", 0), ("UART", 8)]);
+        assert_eq!(assembly.line_map[&0], SourceRef { file: PathBuf::from("main.asm"), line: 2 });
+        assert_eq!(assembly.line_map[&20], SourceRef { file: PathBuf::from("second.asm"), line: 1 });
+        assert!(assembly.diagnostics.is_empty());
+        assert_eq!(String::from_utf8(assembly.output()).unwrap(),
+            ":04000000284000088C\n:08001000F8000000604430001C\n:00000001FF\n");
+        assert!(assembly.make_rule(Path::new("main.bin")).contains("lib/uart.inc"));
+        assert_eq!(assembly.map(MapFormat::Text), "\
+; value  kind   section name  source
+00000000 label  .text   START main.asm:2
+00000002 define *ABS*   BOARD -
+00000008 equ    *ABS*   UART  lib/uart.inc:1
+00000010 label  .text   END   main.asm:4
+; .text (0x00000000-0x00000017)
+");
+        assert_eq!(assembly.entry, None);
+        let sources: Vec<(&Path, u32)> = assembly.sources.iter().map(|(p, c)| (p.as_path(), *c)).collect();
+        assert_eq!(sources, vec![
+            (Path::new("main.asm"), crc32(b".include \"uart.inc\"\nSTART: la r1, UART\n.org 16\nEND: stop")),
+            (Path::new("second.asm"), crc32(b"\tadd r1, r2, r3 ; after END")),
+        ]);
+    }
+
+    #[test]
+    fn assemble_default_format_test() {
+        // Programs are written in the layout existing loaders read unless asked otherwise.
+        let assembly = Assembler::new().source("test-program.asm", include_str!("../test-program.asm")).assemble().unwrap();
+        assert_eq!(assembly.output(), include_bytes!("../test-program.bin").to_vec());
+
+        // Words keep their source order, as they always have in this layout.
+        let assembly = Assembler::new().source("main.asm", ".org 16\nnop\n.org 0\nstop").assemble().unwrap();
+        assert_eq!(String::from_utf8(assembly.output()).unwrap(), "00000000\n00000010\t00000000\n00000000\tf8000000\n");
+    }
+
+    #[test]
+    fn assemble_entry_test() {
+        let tests = [
+            ("START", Some(8)),
+            ("START - 4", Some(4)),
+            ("0x8", Some(8)),
+        ];
+        for test in &tests {
+            let assembly = Assembler::new()
+                .source("main.asm", "nop\nnop\nSTART: stop\n.data\nVALUE: .word 1")
+                .entry(test.0)
+                .assemble()
+                .unwrap();
+            assert_eq!(assembly.entry, test.1, "failed with [{}]", test.0);
+        }
+
+        // `.entry` gives the start address unless one is given to the assembler.
+        let source = "nop\nSTART: stop\n.entry START";
+        let assembly = Assembler::new().source("main.asm", source).format(OutputFormat::TextCompat).assemble().unwrap();
+        assert_eq!(assembly.entry, Some(4));
+        assert_eq!(String::from_utf8(assembly.output()).unwrap(), "00000000\n00000000\t00000000\n00000004\tf8000000\n");
+        assert_eq!(Assembler::new().source("main.asm", source).entry("0").assemble().unwrap().entry, Some(0));
+
+        let invalid_tests = [
+            ("stop", "MISSING"),
+            ("stop", "2"),
+            ("stop", "0x10"),
+            ("stop\n.data\nVALUE: .word 1", "VALUE"),
+            ("stop\n.dw 2\nEND: stop", "END - 4"),
+            ("stop", "0x100000000"),
+            ("stop", "-4"),
+        ];
+        for test in &invalid_tests {
+            let result = Assembler::new().source("main.asm", test.0).entry(test.1).assemble();
+            assert!(result.is_err(), "failed with [{}]", test.1);
+        }
+        let invalid_tests = [
+            ".entry MISSING\nstop",
+            ".entry\nstop",
+            ".entry 0\n.entry 0\nstop",
+            ".entry VALUE\nstop\n.data\nVALUE: .word 1",
+            ".entry 0x100000000\nstop",
+            ".entry -4\nstop",
+        ];
+        for test in &invalid_tests {
+            let result = Assembler::new().source("main.asm", test).assemble();
+            assert!(result.is_err(), "failed with [{}]", test);
+        }
+        let message = Assembler::new().source("main.asm", "stop\n.entry VALUE\n.data\nVALUE: .word 1").assemble().err().unwrap().to_string();
+        assert_eq!(message, "main.asm:2: Entry point 0x00000004 is in section .data, not .text");
+    }
+
+    #[test]
+    fn assemble_checksum_test() {
+        let assembly = Assembler::new()
+            .source("main.asm", "START: .word 1, 2\nEND: .checksum START, END\nCRC: .dw 1")
+            .checksum(Request::parse(checksum::Kind::Sum, "CRC=START,CRC").unwrap())
+            .assemble()
+            .unwrap();
+        assert_eq!(assembly.words[&8], 3);
+        assert_eq!(assembly.words[&12], 6);
+        assert!(assembly.listing().unwrap().ends_with("\
+; checksum of 0x00000000-0x00000007 at 0x00000008: 0x00000003
+main.asm:3               0000000c          CRC: .dw 1
+; .text (0x00000000-0x0000002b)
+; checksum of 0x00000000-0x0000000b at 0x0000000c: 0x00000006
+"));
+
+        let result = Assembler::new()
+            .source("main.asm", "START: .word 1, 2\nEND: .checksum START, END")
+            .checksum(Request::parse(checksum::Kind::Sum, "END=START,END").unwrap())
+            .assemble();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn assemble_import_test() {
+        let monitor = Assembler::new()
+            .source("monitor.asm", "RESET: br r0\nRX: ret\nTX: ret\nchar .reg r3\n.equ STACK, 0x800\n1: stop")
+            .assemble()
+            .unwrap();
+        let imports = map::read(&monitor.map(MapFormat::Json)).unwrap();
+        assert_eq!(imports.len(), 5);
+
+        let assembly = Assembler::new()
+            .source("user.asm", ".org 4096\nRESET: la char, 'A'\ncall TX\nla sp, STACK")
+            .import(&imports)
+            .assemble()
+            .unwrap();
+        assert_eq!(assembly.words[&4096], 0x28c00041);
+        assert_eq!(assembly.symbols["TX"], 8);
+        assert_eq!(assembly.symbols["RESET"], 4096);
+        let table: Vec<(&str, usize)> = assembly.symbol_table.iter().map(|x| (x.name.as_str(), x.value)).collect();
+        assert_eq!(table, vec![("char", 3), ("RX", 4), ("TX", 8), ("STACK", 0x800), ("RESET", 4096)]);
+
+        let result = Assembler::new().source("user.asm", "stop").import(&imports).import(&imports).assemble();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn assemble_negative_import_test() {
+        let monitor = Assembler::new()
+            .source("monitor.asm", ".equ DOWN, -32\nstop")
+            .define("BACK", -4)
+            .assemble()
+            .unwrap();
+        let imports = map::read(&monitor.map(MapFormat::Text)).unwrap();
+        let assembly = Assembler::new()
+            .source("user.asm", "addi r1, r1, DOWN\naddi r2, r2, BACK")
+            .import(&imports)
+            .assemble()
+            .unwrap();
+        let expected = Assembler::new().source("user.asm", "addi r1, r1, -32\naddi r2, r2, -4").assemble().unwrap();
+        assert_eq!(assembly.words, expected.words);
+    }
+
+    #[test]
+    fn assemble_object_test() {
+        let assembly = Assembler::new()
+            .source("uart.asm", ".include \"uart.inc\"\n.global TX\n.extern BUSY\nTX: la r1, UART\n lar r2, BUSY")
+            .include_file("uart.inc", ".equ UART, -32")
+            .assemble_object()
+            .unwrap();
+        let section = &assembly.object.sections[0];
+        assert_eq!(section.words.len(), 2);
+        assert_eq!(section.relocations[0].symbol, "BUSY");
+        assert!(assembly.make_rule(Path::new("uart.o")).contains("uart.inc"));
+        let members: Vec<String> = assembly.members("uart").unwrap().into_iter().map(|x| x.name).collect();
+        assert_eq!(members, vec!["TX"]);
+
+        let result = Assembler::new().source("uart.asm", "lar r2, BUSY").assemble_object();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn assemble_diagnostics_test() {
+        let assembly = Assembler::new()
+            .source("main.asm", ".org 0xFFFFFFE0\nnop")
+            .assemble()
+            .unwrap();
+        assert_eq!(assembly.diagnostics.len(), 1);
+
+        let invalid_tests = [
+            Assembler::new().source("main.asm", ".include \"missing.inc\"").assemble(),
+            Assembler::new().source("main.asm", "la r1, MISSING").assemble(),
+            Assembler::new().file("/nonexistent/main.asm").assemble(),
+            Assembler::new().assemble(),
+        ];
+        for (i, test) in invalid_tests.iter().enumerate() {
+            assert!(test.is_err(), "failed with [{}]", i);
+        }
+    }
+}
@@ -0,0 +1,363 @@
+//! A syntax tree of one source file, for tools that inspect programs without assembling them.
+//!
+//! Parsing is purely syntactic: includes are not followed, macros, `.rept` and `.irp` are not
+//! expanded, conditionals are not evaluated and register aliases such as `sp` appear as
+//! symbols. Macro invocations look like instructions named after the macro.
+
+use std::error::Error;
+use std::fmt;
+
+pub use crate::expr::Expr;
+use crate::inst;
+use crate::lexer::{self, Kind, Token};
+use crate::macros;
+
+/// A byte range of the parsed text.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Ast {
+    /// Every line with a label, instruction, directive or comment, in order.
+    pub statements: Vec<Statement>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Statement {
+    /// 1-based line number.
+    pub line: usize,
+    pub span: Span,
+    pub label: Option<Label>,
+    pub body: Option<Body>,
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Label {
+    /// The label as written, so local labels keep their leading dot.
+    pub name: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum Body {
+    Instruction {
+        mnemonic: String,
+        span: Span,
+        operands: Vec<Operand>,
+    },
+    /// A directive with its leading dot. `NAME .reg rN` becomes `.reg` with `NAME` as its
+    /// first operand.
+    Directive {
+        name: String,
+        span: Span,
+        operands: Vec<Operand>,
+    },
+    /// A line of a `.macro` definition or `.irp` body, kept as written without its comment
+    /// since it is only parsed once its parameters are substituted.
+    Raw(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Operand {
+    pub value: Value,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum Value {
+    Register(usize),
+    /// `offset(rN)`, as in `ld r1, 4(r2)`.
+    Indexed {
+        offset: Option<Expr>,
+        base: usize,
+    },
+    Expr(Expr),
+    Str(String),
+}
+
+/// A line that could not be parsed.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Diagnostic {
+    /// 1-based line number.
+    pub line: usize,
+    pub span: Span,
+    pub message: String,
+}
+
+/// Every line of a file that could not be parsed.
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Diagnostics(pub Vec<Diagnostic>);
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let lines: Vec<String> = self.0.iter()
+            .map(|d| format!("line {}: {}", d.line, d.message))
+            .collect();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+impl Error for Diagnostics {}
+
+/// Parses `text` as one source file, reporting every line that fails rather than the first.
+pub fn parse(text: &str) -> Result<Ast, Diagnostics> {
+    let mut statements = Vec::new();
+    let mut diagnostics = Vec::new();
+    let mut blocks = Vec::new();
+    let mut start = 0;
+
+    for (index, raw) in text.split('\n').enumerate() {
+        let line = raw.strip_suffix('\r').unwrap_or(raw);
+        let span = Span { start, end: start + line.len() };
+        let offset = start;
+        start += raw.len() + 1;
+
+        let word = macros::split_label(line).1.split_whitespace().next().unwrap_or("");
+        if word == ".endm" || word == ".endr" {
+            blocks.pop();
+        }
+        let unexpanded = word == ".macro" || blocks.iter().any(|b| *b == ".macro" || *b == ".irp");
+        if word == ".macro" || word == ".rept" || word == ".irp" {
+            blocks.push(word);
+        }
+
+        let statement = match unexpanded {
+            true => Ok(raw_statement(line, index + 1, span)),
+            false => parse_statement(line, index + 1, span, offset),
+        };
+        match statement {
+            Ok(Some(x)) => statements.push(x),
+            Ok(None) => (),
+            Err(e) => diagnostics.push(Diagnostic {
+                line: index + 1,
+                span,
+                message: e.to_string(),
+            }),
+        }
+    }
+
+    match diagnostics.is_empty() {
+        true => Ok(Ast { statements }),
+        false => Err(Diagnostics(diagnostics)),
+    }
+}
+
+fn raw_statement(line: &str, number: usize, span: Span) -> Option<Statement> {
+    let code = lexer::strip_comment(line);
+    let comment = line.get((code.len() + 1)..).map(|x| x.trim().to_string());
+    let body = match code.trim() {
+        "" if comment.is_none() => return None,
+        "" => None,
+        x => Some(Body::Raw(x.to_string())),
+    };
+    Some(Statement {
+        line: number,
+        span,
+        label: None,
+        body,
+        comment,
+    })
+}
+
+fn parse_statement(line: &str, number: usize, span: Span, offset: usize) -> Result<Option<Statement>, Box<dyn Error>> {
+    let statement = lexer::Statement::parse(line)?;
+    let shift = |x: &std::ops::Range<usize>| Span { start: offset + x.start, end: offset + x.end };
+
+    let label = statement.label.map(|(name, span)| Label { name, span: shift(&span) });
+    let mut operands = statement.operands.iter();
+    let body = match statement.mnemonic {
+        None => None,
+        Some((name, span)) if name.starts_with('.') => Some(Body::Directive {
+            name,
+            span: shift(&span),
+            operands: operands.map(|x| process_operand(x, offset)).collect::<Result<_, _>>()?,
+        }),
+        Some((name, span)) => match operands.next().map(|x| x.split_first()) {
+            Some(Some((Token { kind: Kind::Ident(directive), span: directive_span }, reg))) if directive == ".reg" => {
+                if reg.is_empty() || operands.next().is_some() {
+                    bail!("Expected NAME .reg rN");
+                }
+                Some(Body::Directive {
+                    name: directive.clone(),
+                    span: shift(directive_span),
+                    operands: vec![
+                        Operand { value: Value::Expr(Expr::Sym(name)), span: shift(&span) },
+                        process_operand(reg, offset)?,
+                    ],
+                })
+            },
+            _ => Some(Body::Instruction {
+                mnemonic: name,
+                span: shift(&span),
+                operands: statement.operands.iter().map(|x| process_operand(x, offset)).collect::<Result<_, _>>()?,
+            }),
+        },
+    };
+
+    if label.is_none() && body.is_none() && statement.comment.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(Statement {
+        line: number,
+        span,
+        label,
+        body,
+        comment: statement.comment,
+    }))
+}
+
+fn process_operand(tokens: &[Token], offset: usize) -> Result<Operand, Box<dyn Error>> {
+    let span = match (tokens.first(), tokens.last()) {
+        (Some(first), Some(last)) => Span { start: offset + first.span.start, end: offset + last.span.end },
+        _ => bail!("Empty operand"),
+    };
+    let register = |x: &str| inst::register_string_parse(x).ok();
+    let value = match tokens {
+        [Token { kind: Kind::Ident(x), .. }] if register(x).is_some() => Value::Register(register(x).unwrap()),
+        [Token { kind: Kind::Str(x), .. }] => Value::Str(x.clone()),
+        [prefix @ .., Token { kind: Kind::Op("("), .. }, Token { kind: Kind::Ident(x), .. }, Token { kind: Kind::Op(")"), .. }]
+            if register(x).is_some() => Value::Indexed {
+                offset: match prefix.is_empty() {
+                    true => None,
+                    false => Some(Expr::parse_tokens(prefix)?),
+                },
+                base: register(x).unwrap(),
+            },
+        _ => Value::Expr(Expr::parse_tokens(tokens)?),
+    };
+    Ok(Operand { value, span })
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn span(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+
+    #[test]
+    fn parse_test() {
+        let text = "\t.org 0\nL1:\tld r1, -24(r2) ; Load\n\n; note\nRX .reg r1\n\tjz RX, .x + 4\n";
+        let ast = parse(text).unwrap();
+        assert_eq!(ast.statements, vec![
+            Statement {
+                line: 1,
+                span: span(0, 7),
+                label: None,
+                body: Some(Body::Directive {
+                    name: String::from(".org"),
+                    span: span(1, 5),
+                    operands: vec![Operand { value: Value::Expr(Expr::Num(0)), span: span(6, 7) }],
+                }),
+                comment: None,
+            },
+            Statement {
+                line: 2,
+                span: span(8, 33),
+                label: Some(Label { name: String::from("L1"), span: span(8, 10) }),
+                body: Some(Body::Instruction {
+                    mnemonic: String::from("ld"),
+                    span: span(12, 14),
+                    operands: vec![
+                        Operand { value: Value::Register(1), span: span(15, 17) },
+                        Operand {
+                            value: Value::Indexed { offset: Some(Expr::Unary("-", Box::new(Expr::Num(24)))), base: 2 },
+                            span: span(19, 26),
+                        },
+                    ],
+                }),
+                comment: Some(String::from("Load")),
+            },
+            Statement {
+                line: 4,
+                span: span(35, 41),
+                label: None,
+                body: None,
+                comment: Some(String::from("note")),
+            },
+            Statement {
+                line: 5,
+                span: span(42, 52),
+                label: None,
+                body: Some(Body::Directive {
+                    name: String::from(".reg"),
+                    span: span(45, 49),
+                    operands: vec![
+                        Operand { value: Value::Expr(Expr::Sym(String::from("RX"))), span: span(42, 44) },
+                        Operand { value: Value::Register(1), span: span(50, 52) },
+                    ],
+                }),
+                comment: None,
+            },
+            Statement {
+                line: 6,
+                span: span(53, 67),
+                label: None,
+                body: Some(Body::Instruction {
+                    mnemonic: String::from("jz"),
+                    span: span(54, 56),
+                    operands: vec![
+                        Operand { value: Value::Expr(Expr::Sym(String::from("RX"))), span: span(57, 59) },
+                        Operand {
+                            value: Value::Expr(Expr::Binary("+", Box::new(Expr::Sym(String::from(".x"))), Box::new(Expr::Num(4)))),
+                            span: span(61, 67),
+                        },
+                    ],
+                }),
+                comment: None,
+            },
+        ]);
+
+        let ast = parse(include_str!("../monitor.asm")).unwrap();
+        assert!(ast.statements.len() > 100);
+    }
+
+    #[test]
+    fn parse_block_test() {
+        let text = ".macro PUSH reg=r1 ; push\n\\@loop: st \\reg, 0(sp)\n.endm\n.irp r, r1, r2\nclr \\r\n.endr\n.rept 2\nnop\n.endr\n";
+        let ast = parse(text).unwrap();
+        let bodies: Vec<&Body> = ast.statements.iter().filter_map(|x| x.body.as_ref()).collect();
+        assert_eq!(bodies[0], &Body::Raw(String::from(".macro PUSH reg=r1")));
+        assert_eq!(ast.statements[0].comment.as_deref(), Some("push"));
+        assert_eq!(bodies[1], &Body::Raw(String::from("\\@loop: st \\reg, 0(sp)")));
+        assert!(matches!(bodies[2], Body::Directive { name, .. } if name == ".endm"));
+        assert!(matches!(bodies[3], Body::Directive { name, operands, .. } if name == ".irp" && operands.len() == 3));
+        assert_eq!(bodies[4], &Body::Raw(String::from("clr \\r")));
+        assert!(matches!(bodies[5], Body::Directive { name, .. } if name == ".endr"));
+        assert!(matches!(bodies[7], Body::Instruction { mnemonic, .. } if mnemonic == "nop"));
+    }
+
+    #[test]
+    fn parse_diagnostics_test() {
+        let text = "add r1,, r2\nstop\nla r1, 12a\nX .reg\n";
+        let result = parse(text).unwrap_err();
+        let lines: Vec<usize> = result.0.iter().map(|x| x.line).collect();
+        assert_eq!(lines, vec![1, 3, 4]);
+        assert_eq!(result.0[1].span, span(17, 27));
+        assert!(result.to_string().starts_with("line 1: Empty operand\nline 3: "));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialize_test() {
+        let ast = parse("L: la r1, 4(r2)").unwrap();
+        let json = serde_json::to_string(&ast).unwrap();
+        assert!(json.contains("\"label\":{\"name\":\"L\",\"span\":{\"start\":0,\"end\":1}}"), "{}", json);
+        assert!(json.contains("\"Indexed\":{\"offset\":{\"Num\":4},\"base\":2}"), "{}", json);
+    }
+}
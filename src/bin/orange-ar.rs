@@ -0,0 +1,20 @@
+use std::env;
+use std::process;
+
+use orange_assembler::ar::{self, Config};
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    let config = Config::new(&args).unwrap_or_else(|err| {
+        eprintln!("Problem parsing arguments: {}", err);
+        eprintln!("Try --help for usage.");
+        process::exit(1);
+    });
+    
+    if let Err(e) = ar::run(config) {
+        eprintln!("Application Error: {}", e);
+
+        process::exit(1);
+    }
+}
@@ -0,0 +1,217 @@
+//! Checksums of assembled words and sources, such as those `.crc32` and `.checksum` store for
+//! a bootloader to verify an upload with.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use crate::expr::Expr;
+
+/// How a checksum is computed over a range of addresses.
+#[derive(Debug, Clone, Copy, PartialEq, EnumString, Display)]
+pub enum Kind {
+    /// The CRC-32 of the bytes of the range; see `crc32`.
+    #[strum(serialize = "crc32")]
+    Crc32,
+    /// The sum of the words of the range, wrapping at 32 bits.
+    #[strum(serialize = "checksum")]
+    Sum,
+}
+
+/// A checksum of `start..end` to store at `address` once every word is known, each given as
+/// an expression, as `--crc32` and `--checksum` take them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub kind: Kind,
+    pub address: String,
+    pub start: String,
+    pub end: String,
+}
+
+impl Request {
+    /// Parses `AT=START,END`, where AT is the address.
+    pub fn parse(kind: Kind, text: &str) -> Result<Request, Box<dyn Error>> {
+        let (address, range) = match text.split_once('=') {
+            Some(x) => x,
+            None => bail!(format!("expected AT=START,END, found \"{}\"", text)),
+        };
+        match range.split_once(',') {
+            Some((start, end)) if [address, start, end].iter().all(|x| !x.trim().is_empty()) => Ok(Request {
+                kind,
+                address: address.trim().to_string(),
+                start: start.trim().to_string(),
+                end: end.trim().to_string(),
+            }),
+            _ => bail!(format!("expected AT=START,END, found \"{}\"", text)),
+        }
+    }
+}
+
+/// The CRC-32 of `bytes`, as used by zip and Ethernet (reflected polynomial 0xedb88320).
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !crc32_update(!0, bytes)
+}
+
+/// Feeds `bytes` through the CRC-32 register `crc`, which starts at `!0` and is inverted once
+/// every byte is fed.
+fn crc32_update(mut crc: u32, bytes: &[u8]) -> u32 {
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0xedb8_8320,
+                _ => crc >> 1,
+            };
+        }
+    }
+    crc
+}
+
+/// Feeds `count` zero bytes through the CRC-32 register `crc`, a block at a time.
+fn crc32_zeros(mut crc: u32, mut count: usize) -> u32 {
+    let zeros = [0; 4096];
+    while count > 0 {
+        let n = count.min(zeros.len());
+        crc = crc32_update(crc, &zeros[..n]);
+        count -= n;
+    }
+    crc
+}
+
+/// The `kind` checksum of the words from `start` up to but not including `end`, as
+/// big-endian bytes, counting addresses without a word as zero.
+pub fn compute(kind: Kind, words: &BTreeMap<usize, u32>, start: usize, end: usize) -> Result<u32, Box<dyn Error>> {
+    if start >= end || !start.is_multiple_of(4) || !end.is_multiple_of(4) || end > 1 << 32 {
+        bail!(format!("Range {:#x}-{:#x} is not a range of whole words", start, end));
+    }
+    let range = words.range(start..end);
+    match kind {
+        Kind::Crc32 => {
+            let mut crc = !0;
+            let mut next = start;
+            for (address, word) in range {
+                crc = crc32_update(crc32_zeros(crc, address - next), &word.to_be_bytes());
+                next = address + 4;
+            }
+            Ok(!crc32_zeros(crc, end - next))
+        },
+        Kind::Sum => Ok(range.fold(0u32, |sum, x| sum.wrapping_add(*x.1))),
+    }
+}
+
+/// A listing line for the `kind` checksum of `start..end` stored at `address`.
+pub fn describe(kind: Kind, start: usize, end: usize, address: usize, value: u32) -> String {
+    format!("; {} of {:#010x}-{:#010x} at {:#010x}: {:#010x}\n", kind, start, end - 1, address, value)
+}
+
+/// Computes each of `requests` in order over `words` and stores it, evaluating addresses with
+/// `lookup`. A checksum may cover those stored before it but not after. Returns the listing
+/// lines `describe` gives.
+pub fn apply(requests: &[Request], words: &mut BTreeMap<usize, u32>, lookup: &dyn Fn(&str) -> Option<i64>) -> Result<String, Box<dyn Error>> {
+    let eval = |text: &str| match Expr::parse(text).and_then(|e| e.eval(lookup)) {
+        Ok(x) if (0..=1 << 32).contains(&x) => Ok(x as usize),
+        Ok(x) => bail!(format!("{:#x} is not an address", x)),
+        Err(e) => Err(e),
+    };
+    let describe_request = |x: &Request| format!("--{} {}={},{}", x.kind, x.address, x.start, x.end);
+
+    // Where each goes and what it covers, as (address, start, end).
+    let mut places: Vec<(usize, usize, usize)> = Vec::new();
+    for request in requests {
+        let place = match (eval(&request.address), eval(&request.start), eval(&request.end)) {
+            (Ok(address), Ok(start), Ok(end)) => (address, start, end),
+            (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => bail!(format!("{}: {}", describe_request(request), e)),
+        };
+        match place.0 {
+            x if !x.is_multiple_of(4) || x >= 1 << 32 => bail!(format!("{}: {:#x} is not a word address", describe_request(request), x)),
+            x if words.contains_key(&x) => bail!(format!("{}: {:#010x} already holds a word; reserve it with .dw instead", describe_request(request), x)),
+            x if places.iter().any(|y| y.0 == x) => bail!(format!("{}: {:#010x} already holds a checksum", describe_request(request), x)),
+            _ => places.push(place),
+        }
+    }
+
+    let mut s = String::new();
+    for (index, (request, (address, start, end))) in requests.iter().zip(&places).enumerate() {
+        match places[index..].iter().find(|x| (*start..*end).contains(&x.0)) {
+            Some(x) if x.0 == *address => bail!(format!("{}: The range holds the checksum itself", describe_request(request))),
+            Some(x) => bail!(format!("{}: The range holds the checksum at {:#010x}, which is not known yet", describe_request(request), x.0)),
+            None => (),
+        }
+        let value = match compute(request.kind, words, *start, *end) {
+            Ok(x) => x,
+            Err(e) => bail!(format!("{}: {}", describe_request(request), e)),
+        };
+        words.insert(*address, value);
+        s.push_str(&describe(request.kind, *start, *end, *address, value));
+    }
+    Ok(s)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn crc32_test() {
+        let tests: [(&[u8], u32); 3] = [(b"", 0), (b"123456789", 0xcbf43926), (&[0xf8, 0, 0, 0], 0x6296e154)];
+        for test in &tests {
+            assert_eq!(crc32(test.0), test.1, "failed with [{:?}]", test.0);
+        }
+    }
+
+    #[test]
+    fn compute_test() {
+        let words: BTreeMap<usize, u32> = vec![(0, 0xf8000000), (8, 0x12345678), (12, 0xffffffff)].into_iter().collect();
+        assert_eq!(compute(Kind::Crc32, &words, 0, 4).unwrap(), 0x6296e154);
+        assert_eq!(compute(Kind::Crc32, &words, 0, 12).unwrap(), crc32(&[0xf8, 0, 0, 0, 0, 0, 0, 0, 0x12, 0x34, 0x56, 0x78]));
+        assert_eq!(compute(Kind::Sum, &words, 0, 16).unwrap(), 0x0a345677);
+        assert_eq!(compute(Kind::Sum, &words, 4, 8).unwrap(), 0);
+
+        // Gaps longer than a block of zeros, before, between and after the words.
+        let sparse: BTreeMap<usize, u32> = vec![(0x3000, 0xf8000000), (0x5004, 0x12345678)].into_iter().collect();
+        let mut bytes = vec![0; 0x8000];
+        bytes[0x3000] = 0xf8;
+        bytes[0x5004..0x5008].copy_from_slice(&[0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(compute(Kind::Crc32, &sparse, 0, 0x8000).unwrap(), crc32(&bytes));
+        assert_eq!(compute(Kind::Crc32, &sparse, 0x3000, 0x5008).unwrap(), crc32(&bytes[0x3000..0x5008]));
+
+        let invalid_tests = [(0, 0), (8, 4), (2, 8), (0, 6)];
+        for test in &invalid_tests {
+            assert!(compute(Kind::Sum, &words, test.0, test.1).is_err(), "failed with [{:?}]", test);
+        }
+    }
+
+    #[test]
+    fn apply_test() {
+        assert_eq!(Request::parse(Kind::Crc32, "CRC = START, END").unwrap(), Request {
+            kind: Kind::Crc32,
+            address: String::from("CRC"),
+            start: String::from("START"),
+            end: String::from("END"),
+        });
+        for test in &["CRC", "CRC=START", "=0,4", "CRC=0,"] {
+            assert!(Request::parse(Kind::Sum, test).is_err(), "failed with [{}]", test);
+        }
+
+        let lookup = |s: &str| match s {
+            "END" => Some(8),
+            _ => None,
+        };
+        let requests = [Request::parse(Kind::Sum, "0x10=0,END").unwrap(), Request::parse(Kind::Sum, "0x14=0,0x14").unwrap()];
+        let mut words: BTreeMap<usize, u32> = vec![(0, 1), (4, 2)].into_iter().collect();
+        let listing = apply(&requests, &mut words, &lookup).unwrap();
+        assert_eq!(words[&0x10], 3);
+        assert_eq!(words[&0x14], 6);
+        assert_eq!(listing, "\
+; checksum of 0x00000000-0x00000007 at 0x00000010: 0x00000003
+; checksum of 0x00000000-0x00000013 at 0x00000014: 0x00000006
+");
+
+        let invalid_tests = ["4=0,END", "0x12=0,END", "0x10=0,0x20", "0x10=0,MISSING", "0x20=0,0x20\n0x10=0,8", "0x10=0,4\n0x10=0,8"];
+        for test in &invalid_tests {
+            let requests: Vec<Request> = test.lines().map(|x| Request::parse(Kind::Crc32, x).unwrap()).collect();
+            let mut words: BTreeMap<usize, u32> = vec![(0, 1), (4, 2)].into_iter().collect();
+            assert!(apply(&requests, &mut words, &lookup).is_err(), "failed with [{}]", test);
+        }
+    }
+}
@@ -0,0 +1,308 @@
+use std::collections::HashMap;
+use std::error::Error;
+use crate::expr::Expr;
+use crate::macros;
+use crate::source::{self, Loc};
+
+/// The value of a condition, or `None` when it cannot be decided yet.
+type Evaluation = Result<Option<bool>, Box<dyn Error>>;
+
+struct Frame {
+    active: bool,
+    taken: bool,
+    parent_active: bool,
+    /// Whether the block is certain to be assembled when active, rather than only possibly.
+    certain: bool,
+    parent_certain: bool,
+    /// Whether a condition of an earlier block could not be decided.
+    undecided: bool,
+    else_seen: bool,
+    loc: Loc,
+}
+
+/// Nested `.if`/`.ifdef`/`.ifndef` blocks seen so far in the first pass.
+#[derive(Default)]
+pub struct Conditions {
+    stack: Vec<Frame>,
+}
+
+impl Conditions {
+    /// Whether lines at the current position should be assembled.
+    pub fn active(&self) -> bool {
+        self.stack.last().is_none_or(|f| f.active)
+    }
+
+    /// Whether lines at the current position are certain to be assembled when active.
+    fn certain(&self) -> bool {
+        self.stack.last().is_none_or(|f| f.certain)
+    }
+
+    /// Location of the innermost `.if` still missing its `.endif`.
+    pub fn unterminated(&self) -> Option<Loc> {
+        self.stack.last().map(|f| f.loc)
+    }
+
+    /// Handles `directive` if it is a conditional one and returns whether it was. Conditions
+    /// inside a skipped block are not evaluated, so they may refer to undefined symbols.
+    pub fn process(&mut self, directive: &str, arg: &str, loc: Loc, lookup: &dyn Fn(&str) -> Option<i64>) -> Result<bool, Box<dyn Error>> {
+        self.update(directive, arg, loc, &|directive, arg| Ok(Some(evaluate(directive, arg, lookup)?)))
+    }
+
+    /// Handles `directive` as `process` does, with `evaluate` giving `None` for a condition
+    /// it cannot decide. Such a block counts as active, and so do those after it.
+    fn update(&mut self, directive: &str, arg: &str, loc: Loc, evaluate: &dyn Fn(&str, &str) -> Evaluation) -> Result<bool, Box<dyn Error>> {
+        match directive {
+            ".if" | ".ifdef" | ".ifndef" => {
+                let (parent_active, parent_certain) = (self.active(), self.certain());
+                let value = match parent_active {
+                    true => evaluate(directive, arg)?,
+                    false => Some(false),
+                };
+                self.stack.push(Frame {
+                    active: value != Some(false),
+                    taken: value == Some(true) || !parent_active,
+                    parent_active,
+                    certain: parent_certain && value.is_some(),
+                    parent_certain,
+                    undecided: value.is_none(),
+                    else_seen: false,
+                    loc,
+                });
+            },
+            ".elseif" => {
+                let frame = match self.stack.last_mut() {
+                    Some(x) if !x.else_seen => x,
+                    Some(_) => bail!(".elseif after .else"),
+                    None => bail!(".elseif without .if"),
+                };
+                let value = match frame.taken {
+                    true => Some(false),
+                    false => evaluate(".if", arg)?,
+                };
+                frame.active = value != Some(false);
+                frame.taken |= value == Some(true);
+                frame.certain = frame.parent_certain && !frame.undecided && value.is_some();
+                frame.undecided |= value.is_none();
+            },
+            ".else" => {
+                let frame = match self.stack.last_mut() {
+                    Some(x) if !x.else_seen => x,
+                    Some(_) => bail!("Duplicate .else"),
+                    None => bail!(".else without .if"),
+                };
+                expect_no_argument(directive, arg)?;
+                frame.active = frame.parent_active && !frame.taken;
+                frame.certain = frame.parent_certain && !frame.undecided;
+                frame.taken = true;
+                frame.else_seen = true;
+            },
+            ".endif" => {
+                expect_no_argument(directive, arg)?;
+                if self.stack.pop().is_none() {
+                    bail!(".endif without .if");
+                }
+            },
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+/// What is known of a symbol before the program is laid out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Known {
+    Value(i64),
+    /// Defined, as a label is, with a value only known once laid out.
+    Defined,
+    /// Defined in a block that may not be assembled.
+    Maybe,
+}
+
+/// Conditions as the passes that read includes and expand macros see them, so that `.include`
+/// and `.macro` in a skipped block are left alone. Those passes run before symbols have their
+/// values and know only the `-D` defines and imported symbols they start with, the `.equ`
+/// symbols seen so far and which labels have been seen. A condition needing anything else
+/// counts as true, leaving the choice of block to `Conditions::process` once the program is
+/// laid out.
+pub struct Early {
+    conditions: Conditions,
+    symbols: HashMap<String, Known>,
+}
+
+impl Early {
+    pub fn new(known: &[(String, i64)]) -> Early {
+        Early {
+            conditions: Conditions::default(),
+            symbols: known.iter().map(|(name, value)| (name.clone(), Known::Value(*value))).collect(),
+        }
+    }
+
+    /// Whether `.include`, `.macro` and the like should be acted on at the current position.
+    pub fn active(&self) -> bool {
+        self.conditions.active()
+    }
+
+    /// The value of `name` when known this early.
+    pub fn value(&self, name: &str) -> Option<i64> {
+        match self.symbols.get(name) {
+            Some(Known::Value(x)) => Some(*x),
+            _ => None,
+        }
+    }
+
+    /// Follows `text`, handling it if it is a conditional directive and otherwise noting any
+    /// `.equ` or label it defines in an active block. Returns whether it was conditional.
+    pub fn process(&mut self, text: &str, loc: Loc) -> Result<bool, Box<dyn Error>> {
+        let directive = source::split_directive(text);
+        if let Some((directive, arg)) = directive {
+            let symbols = &self.symbols;
+            if self.conditions.update(directive, arg, loc, &|directive, arg| Ok(evaluate_early(directive, arg, symbols)))? {
+                return Ok(true);
+            }
+        }
+        if !self.conditions.active() {
+            return Ok(false);
+        }
+        let (name, value) = match (directive, macros::split_label(text).0) {
+            (Some((".equ", arg)), _) => match arg.split_once(',') {
+                Some((name, value)) => {
+                    let lookup = |s: &str| self.value(s);
+                    (name.trim(), Expr::parse(value).and_then(|e| e.eval(&lookup)).ok())
+                },
+                None => return Ok(false),
+            },
+            (_, Some(label)) if !label.is_empty() => (label, None),
+            _ => return Ok(false),
+        };
+        let known = match (self.conditions.certain(), value) {
+            (true, Some(x)) => Known::Value(x),
+            (true, None) => Known::Defined,
+            (false, _) => Known::Maybe,
+        };
+        match known {
+            Known::Maybe => self.symbols.entry(name.to_string()).or_insert(known),
+            _ => self.symbols.entry(name.to_string()).and_modify(|x| *x = known).or_insert(known),
+        };
+        Ok(false)
+    }
+}
+
+/// Evaluates a condition with what is known early, or gives `None` when that is not enough.
+/// Conditions that are not valid are left for `Conditions::process` to report.
+fn evaluate_early(directive: &str, arg: &str, symbols: &HashMap<String, Known>) -> Option<bool> {
+    let expr = match directive {
+        ".ifdef" | ".ifndef" => Expr::Defined(arg.trim().to_string()),
+        _ => Expr::parse(arg).ok()?,
+    };
+    let decided = expr.names().iter().all(|name| symbols.get(*name) != Some(&Known::Maybe))
+        && expr.symbols().iter().all(|name| symbols.get(*name) != Some(&Known::Defined));
+    let lookup = |s: &str| match symbols.get(s) {
+        Some(Known::Value(x)) => Some(*x),
+        Some(_) => Some(0),
+        None => None,
+    };
+    match decided {
+        true => evaluate(directive, arg, &lookup).ok(),
+        false => None,
+    }
+}
+
+fn evaluate(directive: &str, arg: &str, lookup: &dyn Fn(&str) -> Option<i64>) -> Result<bool, Box<dyn Error>> {
+    match directive {
+        ".ifdef" | ".ifndef" => {
+            let name = arg.trim();
+            if name.is_empty() || name.contains(char::is_whitespace) {
+                bail!(format!("{} expects a single symbol name", directive));
+            }
+            Ok(lookup(name).is_some() == (directive == ".ifdef"))
+        },
+        _ => Ok(Expr::parse(arg)?.eval(lookup)? != 0),
+    }
+}
+
+fn expect_no_argument(directive: &str, arg: &str) -> Result<(), Box<dyn Error>> {
+    match arg.trim().is_empty() {
+        true => Ok(()),
+        false => bail!(format!("Unexpected argument after {}", directive)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn run(lines: &[&str]) -> Result<Vec<bool>, Box<dyn Error>> {
+        let lookup = |s: &str| match s {
+            "BOARD" => Some(2),
+            _ => None,
+        };
+        let mut conditions = Conditions::default();
+        let mut active = Vec::new();
+        for (index, line) in lines.iter().enumerate() {
+            let (directive, arg) = match line.find(' ') {
+                Some(x) => (&line[..x], &line[(x+1)..]),
+                None => (*line, ""),
+            };
+            let loc = Loc { file: 0, line: index + 1 };
+            if !conditions.process(directive, arg, loc, &lookup)? {
+                active.push(conditions.active());
+            }
+        }
+        if conditions.unterminated().is_some() {
+            bail!("unterminated");
+        }
+        Ok(active)
+    }
+
+    #[test]
+    fn conditions_test() {
+        let tests: [(&[&str], &[bool]); 5] = [
+            (&[".if BOARD == 2", "a", ".else", "b", ".endif", "c"], &[true, false, true]),
+            (&[".if BOARD == 1", "a", ".elseif BOARD == 2", "b", ".elseif 1", "c", ".else", "d", ".endif"],
+                &[false, true, false, false]),
+            (&[".ifdef BOARD", "a", ".endif", ".ifndef BOARD", "b", ".endif"], &[true, false]),
+            (&[".if 0", ".if MISSING", "a", ".else", "b", ".endif", "c", ".else", "d", ".endif"],
+                &[false, false, false, true]),
+            (&[".ifndef MISSING", ".if 1", "a", ".endif", ".endif"], &[true]),
+        ];
+        for test in &tests {
+            let result = run(test.0).unwrap();
+            assert_eq!(result, test.1, "failed with {:?}", test.0);
+        }
+
+        let invalid_tests: [&[&str]; 7] = [
+            &[".if 1"],
+            &[".endif"],
+            &[".else"],
+            &[".if 1", ".else", ".else", ".endif"],
+            &[".if 1", ".else", ".elseif 1", ".endif"],
+            &[".if MISSING", ".endif"],
+            &[".ifdef A B", ".endif"],
+        ];
+        for test in &invalid_tests {
+            let result = run(test);
+            assert!(result.is_err(), "failed with {:?}", test);
+        }
+    }
+
+    #[test]
+    fn early_test() {
+        let lines = [
+            ".equ N, BOARD + 1", ".if N == 3", "a", ".else", "b", ".endif",
+            "START: nop", ".if START == 0", "c", ".elseif 1", "LATE: nop", ".else", "d", ".endif",
+            ".ifdef START", "e", ".endif", ".ifdef LATE", "f", ".endif", ".ifdef MISSING", "g", ".endif",
+        ];
+        let mut early = Early::new(&[(String::from("BOARD"), 2)]);
+        let mut active = Vec::new();
+        for (index, line) in lines.iter().enumerate() {
+            if !early.process(line, Loc { file: 0, line: index + 1 }).unwrap() {
+                active.push(early.active());
+            }
+        }
+        assert_eq!(active, vec![true, true, false, true, true, true, false, true, true, false]);
+        assert_eq!(early.value("N"), Some(3));
+        assert_eq!(early.value("START"), None);
+        assert!(early.process(".endif", Loc { file: 0, line: 1 }).is_err());
+    }
+}
@@ -0,0 +1,56 @@
+use std::error::Error;
+use crate::inst;
+use crate::text;
+
+/// Turns text output, in either layout `text::read` takes, back into assembly, one
+/// `address<TAB>word<TAB>instruction` line per word. Words that are not instructions, such as
+/// data, are shown as a comment rather than failing the whole file.
+pub fn disassemble(text: &str) -> Result<String, Box<dyn Error>> {
+    let mut s = String::new();
+    for (pc, word) in text::read(text)?.words {
+        let text = match inst::disassemble(word as usize, pc) {
+            Ok(x) => x,
+            Err(_) => String::from("; not an instruction"),
+        };
+        s.push_str(&format!("{:08x}\t{:08x}\t{}\n", pc, word, text));
+    }
+    Ok(s)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::prog::Prog;
+
+    #[test]
+    fn disassemble_test() {
+        let source = "START: la r26, 5\n brlzr r25, r26, r3\n jmp START\n shl r1, r2, 4\n.org 32\n brl r31, r2";
+        let encoded = Prog::new("file", source).unwrap().encode().unwrap();
+        let result = disassemble(&encoded).unwrap();
+        let result: Vec<&str> = result.lines().collect();
+        assert_eq!(result, vec![
+            "00000000\t2e800005\tla r26, 5",
+            "00000004\t4e743002\tbrlzr r25, r26, r3",
+            "00000008\t36bffff4\tlar r26, 0",
+            "0000000c\t40340001\tbr r26",
+            "00000010\te0440004\tshl r1, r2, 4",
+            "00000020\t4fc40001\tbrl r31, r2",
+        ]);
+
+        let result = disassemble("00000000\n00000000\tffffffff\n").unwrap();
+        assert_eq!(result, "00000000\tffffffff\t; not an instruction\n");
+        let result = disassemble("orange-text 1\nwords 1\nchecksum 6296e154\n00000000\tf8000000\n").unwrap();
+        assert_eq!(result, "00000000\tf8000000\tstop\n");
+
+        let invalid_tests = [
+            "00000000\tzz",
+            "00000000\t1\t2",
+            "00000000\t100000000",
+        ];
+        for test in &invalid_tests {
+            let result = disassemble(test);
+            assert!(result.is_err(), "failed with [{}]", test);
+        }
+    }
+}
@@ -0,0 +1,401 @@
+//! ELF32 executables, so that `readelf`, `objdump` and debuggers can read our programs.
+//!
+//! Each run of consecutive words becomes a loadable segment with a section of the same
+//! bytes, named after the program section it starts in. Symbols go in `.symtab`, and the
+//! source line of each word can be added as a DWARF 2 line table in `.debug_line`, with a
+//! compile unit in `.debug_info` pointing at it. Words are big-endian, as in the other byte
+//! formats.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use crate::prog::{ProgSection, Symbol, SymbolKind, TEXT_SECTION};
+use crate::source::SourceRef;
+
+/// Machine number in the ELF header. Ours has no registered number, so this one is taken
+/// from the range nobody uses: "OR" in ASCII.
+pub const EM_ORANGE: u16 = 0x4f52;
+
+const HEADER_SIZE: usize = 52;
+const PROGRAM_HEADER_SIZE: usize = 32;
+const SECTION_HEADER_SIZE: usize = 40;
+const SYMBOL_SIZE: usize = 16;
+
+const ET_EXEC: u16 = 2;
+const PT_LOAD: u32 = 1;
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHF_WRITE: u32 = 1;
+const SHF_ALLOC: u32 = 2;
+const SHF_EXECINSTR: u32 = 4;
+const SHN_ABS: u16 = 0xfff1;
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+
+/// Everything written to an ELF file.
+pub struct Image<'a> {
+    pub words: &'a BTreeMap<usize, u32>,
+    pub entry: Option<usize>,
+    /// Symbols for `.symtab`; register aliases are left out.
+    pub symbols: &'a [Symbol],
+    /// The names declared `.global`; every other symbol is local.
+    pub globals: &'a [String],
+    /// The sections of the program, which name the segments starting in them.
+    pub sections: &'a [ProgSection],
+    /// The source line of each word by address, written as a line table when given.
+    pub lines: Option<&'a BTreeMap<usize, SourceRef>>,
+}
+
+/// A section to write: name, type, flags, address and contents.
+struct Part {
+    name: String,
+    kind: u32,
+    flags: u32,
+    address: usize,
+    bytes: Vec<u8>,
+    /// The section `sh_link` refers to and `sh_info`, for `.symtab`.
+    link: u32,
+    info: u32,
+    entry_size: u32,
+}
+
+impl Part {
+    fn new(name: &str, kind: u32, bytes: Vec<u8>) -> Part {
+        Part { name: name.to_string(), kind, flags: 0, address: 0, bytes, link: 0, info: 0, entry_size: 0 }
+    }
+}
+
+pub fn write(image: &Image) -> Vec<u8> {
+    // One segment per run of consecutive words in one section.
+    let section = |address: usize| match image.sections.iter().find(|x| x.address <= address && address < x.address + x.size) {
+        Some(x) => x.name.as_str(),
+        None => TEXT_SECTION,
+    };
+    let mut runs: Vec<(usize, Vec<u8>)> = Vec::new();
+    for (address, word) in image.words {
+        match runs.last_mut() {
+            Some((start, bytes)) if *start + bytes.len() == *address && section(*start) == section(*address)
+                => bytes.extend_from_slice(&word.to_be_bytes()),
+            _ => runs.push((*address, word.to_be_bytes().to_vec())),
+        }
+    }
+
+    let mut parts = Vec::new();
+    for (address, bytes) in &runs {
+        let name = section(*address);
+        let flags = match name {
+            TEXT_SECTION => SHF_ALLOC | SHF_EXECINSTR,
+            _ => SHF_ALLOC | SHF_WRITE,
+        };
+        parts.push(Part { flags, address: *address, ..Part::new(name, SHT_PROGBITS, bytes.clone()) });
+    }
+
+    // Symbols are in the segment holding their address, or absolute. Local symbols must
+    // come before global ones, and sh_info is the index of the first global.
+    let (globals, locals): (Vec<&Symbol>, Vec<&Symbol>) = image.symbols.iter()
+        .filter(|x| x.kind != SymbolKind::Reg)
+        .partition(|x| image.globals.contains(&x.name));
+    let mut strings = vec![0];
+    let mut symbols = vec![0; SYMBOL_SIZE];
+    for symbol in locals.iter().chain(&globals) {
+        let value = symbol.value as u32;
+        let index = match symbol.kind {
+            SymbolKind::Label => runs.iter().position(|(start, bytes)| *start <= symbol.value && symbol.value < start + bytes.len()),
+            _ => None,
+        };
+        push_u32(&mut symbols, add_string(&mut strings, &symbol.name));
+        push_u32(&mut symbols, value);
+        push_u32(&mut symbols, 0);
+        symbols.push(match image.globals.contains(&symbol.name) {
+            true => STB_GLOBAL << 4,
+            false => STB_LOCAL << 4,
+        });
+        symbols.push(0);
+        push_u16(&mut symbols, index.map_or(SHN_ABS, |x| x as u16 + 1));
+    }
+    let symtab = parts.len() as u32 + 1;
+    parts.push(Part { link: symtab + 1, info: locals.len() as u32 + 1, entry_size: SYMBOL_SIZE as u32, ..Part::new(".symtab", SHT_SYMTAB, symbols) });
+    parts.push(Part::new(".strtab", SHT_STRTAB, strings));
+
+    if let Some(lines) = image.lines.filter(|x| !x.is_empty()) {
+        let (abbrev, info, line) = debug_sections(lines);
+        parts.push(Part::new(".debug_abbrev", SHT_PROGBITS, abbrev));
+        parts.push(Part::new(".debug_info", SHT_PROGBITS, info));
+        parts.push(Part::new(".debug_line", SHT_PROGBITS, line));
+    }
+
+    let mut names = vec![0];
+    let mut name_offsets: Vec<u32> = parts.iter().map(|x| add_string(&mut names, &x.name)).collect();
+    name_offsets.push(add_string(&mut names, ".shstrtab"));
+    parts.push(Part::new(".shstrtab", SHT_STRTAB, names));
+
+    // The headers, then the contents of each section, then the section headers.
+    let mut offsets = Vec::new();
+    let mut offset = HEADER_SIZE + PROGRAM_HEADER_SIZE * runs.len();
+    for part in &parts {
+        offsets.push(offset);
+        offset = (offset + part.bytes.len() + 3) & !3;
+    }
+    let section_headers = offset;
+
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&[0x7f, b'E', b'L', b'F', 1, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    push_u16(&mut bytes, ET_EXEC);
+    push_u16(&mut bytes, EM_ORANGE);
+    push_u32(&mut bytes, 1);
+    push_u32(&mut bytes, image.entry.unwrap_or(0) as u32);
+    push_u32(&mut bytes, match runs.is_empty() {
+        true => 0,
+        false => HEADER_SIZE as u32,
+    });
+    push_u32(&mut bytes, section_headers as u32);
+    push_u32(&mut bytes, 0);
+    push_u16(&mut bytes, HEADER_SIZE as u16);
+    push_u16(&mut bytes, PROGRAM_HEADER_SIZE as u16);
+    push_u16(&mut bytes, runs.len() as u16);
+    push_u16(&mut bytes, SECTION_HEADER_SIZE as u16);
+    push_u16(&mut bytes, parts.len() as u16 + 1);
+    push_u16(&mut bytes, parts.len() as u16);
+
+    for (i, part) in parts.iter().take(runs.len()).enumerate() {
+        push_u32(&mut bytes, PT_LOAD);
+        push_u32(&mut bytes, offsets[i] as u32);
+        push_u32(&mut bytes, part.address as u32);
+        push_u32(&mut bytes, part.address as u32);
+        push_u32(&mut bytes, part.bytes.len() as u32);
+        push_u32(&mut bytes, part.bytes.len() as u32);
+        push_u32(&mut bytes, match part.flags & SHF_EXECINSTR {
+            0 => 6,
+            _ => 5,
+        });
+        push_u32(&mut bytes, 4);
+    }
+
+    for (i, part) in parts.iter().enumerate() {
+        bytes.resize(offsets[i], 0);
+        bytes.extend_from_slice(&part.bytes);
+    }
+    bytes.resize(section_headers, 0);
+
+    bytes.extend_from_slice(&[0; SECTION_HEADER_SIZE]);
+    for (i, part) in parts.iter().enumerate() {
+        push_u32(&mut bytes, name_offsets[i]);
+        push_u32(&mut bytes, part.kind);
+        push_u32(&mut bytes, part.flags);
+        push_u32(&mut bytes, part.address as u32);
+        push_u32(&mut bytes, offsets[i] as u32);
+        push_u32(&mut bytes, part.bytes.len() as u32);
+        push_u32(&mut bytes, part.link);
+        push_u32(&mut bytes, part.info);
+        push_u32(&mut bytes, match part.kind {
+            SHT_STRTAB => 1,
+            _ => 4,
+        });
+        push_u32(&mut bytes, part.entry_size);
+    }
+    bytes
+}
+
+fn push_u16(bytes: &mut Vec<u8>, x: u16) {
+    bytes.extend_from_slice(&x.to_be_bytes());
+}
+
+fn push_u32(bytes: &mut Vec<u8>, x: u32) {
+    bytes.extend_from_slice(&x.to_be_bytes());
+}
+
+fn push_uleb(bytes: &mut Vec<u8>, mut x: u64) {
+    loop {
+        let byte = (x & 0x7f) as u8;
+        x >>= 7;
+        match x {
+            0 => return bytes.push(byte),
+            _ => bytes.push(byte | 0x80),
+        }
+    }
+}
+
+fn push_sleb(bytes: &mut Vec<u8>, mut x: i64) {
+    loop {
+        let byte = (x & 0x7f) as u8;
+        x >>= 7;
+        match (x, byte & 0x40) {
+            (0, 0) | (-1, 0x40) => return bytes.push(byte),
+            _ => bytes.push(byte | 0x80),
+        }
+    }
+}
+
+/// Adds `name` to a string table, returning its offset.
+fn add_string(table: &mut Vec<u8>, name: &str) -> u32 {
+    let offset = table.len() as u32;
+    table.extend_from_slice(name.as_bytes());
+    table.push(0);
+    offset
+}
+
+/// `.debug_abbrev`, `.debug_info` and `.debug_line` for the line of each word.
+fn debug_sections(lines: &BTreeMap<usize, SourceRef>) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+    let mut files: Vec<&PathBuf> = Vec::new();
+    for line in lines.values() {
+        if !files.contains(&&line.file) {
+            files.push(&line.file);
+        }
+    }
+
+    // A compile unit without children: name, line table, first and last address.
+    let abbrev = vec![1, 0x11, 0, 0x03, 0x08, 0x10, 0x06, 0x11, 0x01, 0x12, 0x01, 0, 0, 0];
+    let (low, high) = match (lines.keys().next(), lines.keys().next_back()) {
+        (Some(low), Some(high)) => (*low, high + 4),
+        _ => (0, 0),
+    };
+    let mut unit = Vec::new();
+    push_u16(&mut unit, 2);
+    push_u32(&mut unit, 0);
+    unit.push(4);
+    push_uleb(&mut unit, 1);
+    add_string(&mut unit, &files[0].display().to_string());
+    push_u32(&mut unit, 0);
+    push_u32(&mut unit, low as u32);
+    push_u32(&mut unit, high as u32);
+    let mut info = Vec::new();
+    push_u32(&mut info, unit.len() as u32);
+    info.extend_from_slice(&unit);
+
+    // Header after `header_length`: instructions are 4 bytes, and the standard opcodes
+    // are those of DWARF 2.
+    let mut header = vec![4, 1, (-5i8) as u8, 14, 13, 0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1, 0];
+    for file in &files {
+        add_string(&mut header, &file.display().to_string());
+        header.extend_from_slice(&[0, 0, 0]);
+    }
+    header.push(0);
+
+    // One sequence per run of consecutive words, with a row for each word.
+    let mut program = Vec::new();
+    let mut state: Option<(usize, usize, usize)> = None;
+    for (address, line) in lines {
+        let file = files.iter().position(|x| **x == line.file).unwrap_or(0) + 1;
+        let (last_address, last_file, last_line) = match state {
+            Some((x, file, line)) if x + 4 == *address => (x, file, line),
+            _ => {
+                if state.is_some() {
+                    end_sequence(&mut program);
+                }
+                program.extend_from_slice(&[0, 5, 2]);
+                push_u32(&mut program, *address as u32);
+                (*address, 1, 1)
+            },
+        };
+        if file != last_file {
+            program.push(4);
+            push_uleb(&mut program, file as u64);
+        }
+        if line.line != last_line {
+            program.push(3);
+            push_sleb(&mut program, line.line as i64 - last_line as i64);
+        }
+        if *address != last_address {
+            program.push(2);
+            push_uleb(&mut program, ((address - last_address) / 4) as u64);
+        }
+        program.push(1);
+        state = Some((*address, file, line.line));
+    }
+    if state.is_some() {
+        end_sequence(&mut program);
+    }
+
+    let mut line = Vec::new();
+    push_u32(&mut line, (2 + 4 + header.len() + program.len()) as u32);
+    push_u16(&mut line, 2);
+    push_u32(&mut line, header.len() as u32);
+    line.extend_from_slice(&header);
+    line.extend_from_slice(&program);
+    (abbrev, info, line)
+}
+
+/// Ends a line table sequence after the last word: advances the address by one word, then
+/// `DW_LNE_end_sequence`.
+fn end_sequence(program: &mut Vec<u8>) {
+    program.extend_from_slice(&[2, 1, 0, 1, 1]);
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::Assembler;
+
+    fn u16_at(bytes: &[u8], offset: usize) -> usize {
+        u16::from_be_bytes([bytes[offset], bytes[offset + 1]]) as usize
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> usize {
+        u32::from_be_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]) as usize
+    }
+
+    /// The name, address, offset and size of each section.
+    fn section_headers(bytes: &[u8]) -> Vec<(String, usize, usize, usize)> {
+        let (offset, count, names) = (u32_at(bytes, 32), u16_at(bytes, 48), u16_at(bytes, 50));
+        let header = |i: usize| offset + SECTION_HEADER_SIZE * i;
+        let strings = u32_at(bytes, header(names) + 16);
+        (1..count).map(|i| {
+            let name = &bytes[(strings + u32_at(bytes, header(i)))..];
+            let name = String::from_utf8(name[..name.iter().position(|x| *x == 0).unwrap()].to_vec()).unwrap();
+            (name, u32_at(bytes, header(i) + 12), u32_at(bytes, header(i) + 16), u32_at(bytes, header(i) + 20))
+        }).collect()
+    }
+
+    #[test]
+    fn write_test() {
+        let assembly = Assembler::new()
+            .source("main.asm", ".equ UART, -32\nSTART: la r1, 5\n stop\n.data\nVALUE: .word 7\nFLAG .reg r3\n.global START")
+            .format(crate::OutputFormat::Elf)
+            .entry("START + 4")
+            .debug_lines(true)
+            .assemble()
+            .unwrap();
+        let bytes = assembly.output();
+        assert_eq!(bytes[..6], [0x7f, b'E', b'L', b'F', 1, 2]);
+        assert_eq!(u16_at(&bytes, 18), EM_ORANGE as usize);
+        assert_eq!(u32_at(&bytes, 24), 4);
+        assert_eq!(u16_at(&bytes, 44), 2);
+
+        let sections = section_headers(&bytes);
+        let names: Vec<&str> = sections.iter().map(|x| x.0.as_str()).collect();
+        assert_eq!(names, vec![".text", ".data", ".symtab", ".strtab", ".debug_abbrev", ".debug_info", ".debug_line", ".shstrtab"]);
+        assert_eq!((sections[1].1, sections[1].3), (8, 4));
+        assert_eq!(bytes[sections[0].2..(sections[0].2 + 8)], [0x28, 0x40, 0x00, 0x05, 0xf8, 0x00, 0x00, 0x00]);
+
+        // The null symbol, then the locals UART and VALUE and the global START; the register
+        // alias is left out. sh_info is the index of the first global.
+        let symbols = &bytes[sections[2].2..(sections[2].2 + sections[2].3)];
+        assert_eq!(symbols.len(), 4 * SYMBOL_SIZE);
+        assert_eq!(u32_at(&bytes, u32_at(&bytes, 32) + SECTION_HEADER_SIZE * 3 + 28), 3);
+        assert_eq!((u32_at(symbols, 16 + 4), symbols[16 + 12], u16_at(symbols, 16 + 14)), (0xffffffe0, STB_LOCAL << 4, SHN_ABS as usize));
+        assert_eq!((u32_at(symbols, 32 + 4), symbols[32 + 12], u16_at(symbols, 32 + 14)), (8, STB_LOCAL << 4, 2));
+        assert_eq!((u32_at(symbols, 48 + 4), symbols[48 + 12], u16_at(symbols, 48 + 14)), (0, STB_GLOBAL << 4, 1));
+
+        let line = &bytes[sections[6].2..(sections[6].2 + sections[6].3)];
+        assert_eq!(u32_at(line, 0), line.len() - 4);
+        assert!(line.ends_with(&[0, 5, 2, 0, 0, 0, 0, 3, 1, 1, 3, 1, 2, 1, 1, 3, 2, 2, 1, 1, 2, 1, 0, 1, 1]));
+
+        let without_lines = Assembler::new().source("main.asm", "stop").format(crate::OutputFormat::Elf).assemble().unwrap();
+        assert!(!section_headers(&without_lines.output()).iter().any(|x| x.0.starts_with(".debug")));
+    }
+
+    #[test]
+    fn leb128_test() {
+        let tests: [(i64, &[u8]); 5] = [(0, &[0]), (2, &[2]), (-1, &[0x7f]), (127, &[0xff, 0]), (-129, &[0xff, 0x7e])];
+        for test in &tests {
+            let mut bytes = Vec::new();
+            push_sleb(&mut bytes, test.0);
+            assert_eq!(bytes, test.1, "failed with [{}]", test.0);
+        }
+        let mut bytes = Vec::new();
+        push_uleb(&mut bytes, 624485);
+        assert_eq!(bytes, [0xe5, 0x8e, 0x26]);
+    }
+}
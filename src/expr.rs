@@ -0,0 +1,267 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::lexer::{self, Kind, Token};
+
+/// A constant expression as written in `.if`, `.equ` and `-D` definitions, or as an
+/// instruction operand where `.` stands for the address of the instruction. Symbols may
+/// contain dots, as in the local label `MAIN.loop`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum Expr {
+    Num(i64),
+    Sym(String),
+    Defined(String),
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+/// Binary operators from loosest to tightest binding.
+const PRECEDENCE: [&[&str]; 10] = [
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<=", ">=", "<", ">"],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Expr, Box<dyn Error>> {
+        match Expr::parse_tokens(&lexer::tokenize(text)?) {
+            Ok(x) => Ok(x),
+            Err(e) => bail!(format!("{} in \"{}\"", e, text.trim())),
+        }
+    }
+
+    /// Parses an expression making up all of `tokens`, such as an instruction operand.
+    pub fn parse_tokens(tokens: &[Token]) -> Result<Expr, Box<dyn Error>> {
+        let mut pos = 0;
+        let expr = parse_binary(tokens, &mut pos, 0)?;
+        match tokens.get(pos) {
+            None => Ok(expr),
+            Some(_) => bail!("Unexpected trailing input in expression"),
+        }
+    }
+
+    /// Every symbol whose value the expression needs, in order of appearance.
+    pub fn symbols(&self) -> Vec<&str> {
+        match self {
+            Expr::Num(_) | Expr::Defined(_) => Vec::new(),
+            Expr::Sym(s) => vec![s.as_str()],
+            Expr::Unary(_, a) => a.symbols(),
+            Expr::Binary(_, a, b) => {
+                let mut symbols = a.symbols();
+                symbols.extend(b.symbols());
+                symbols
+            },
+        }
+    }
+
+    /// Every symbol the expression names, including those only tested with `defined`.
+    pub fn names(&self) -> Vec<&str> {
+        match self {
+            Expr::Num(_) => Vec::new(),
+            Expr::Sym(s) | Expr::Defined(s) => vec![s.as_str()],
+            Expr::Unary(_, a) => a.names(),
+            Expr::Binary(_, a, b) => {
+                let mut names = a.names();
+                names.extend(b.names());
+                names
+            },
+        }
+    }
+
+    /// Evaluates with `lookup` giving the value of each symbol, or `None` when it is undefined.
+    pub fn eval(&self, lookup: &dyn Fn(&str) -> Option<i64>) -> Result<i64, Box<dyn Error>> {
+        match self {
+            Expr::Num(x) => Ok(*x),
+            Expr::Sym(s) => match lookup(s) {
+                Some(x) => Ok(x),
+                None => bail!(format!("Undefined symbol \"{}\"", s)),
+            },
+            Expr::Defined(s) => Ok(lookup(s).is_some() as i64),
+            Expr::Unary(op, a) => {
+                let a = a.eval(lookup)?;
+                match *op {
+                    "-" => overflow(a.checked_neg()),
+                    "~" => Ok(!a),
+                    _ => Ok((a == 0) as i64),
+                }
+            },
+            Expr::Binary(op, a, b) => {
+                let a = a.eval(lookup)?;
+                let b = b.eval(lookup)?;
+                match *op {
+                    "||" => Ok((a != 0 || b != 0) as i64),
+                    "&&" => Ok((a != 0 && b != 0) as i64),
+                    "|" => Ok(a | b),
+                    "^" => Ok(a ^ b),
+                    "&" => Ok(a & b),
+                    "==" => Ok((a == b) as i64),
+                    "!=" => Ok((a != b) as i64),
+                    "<=" => Ok((a <= b) as i64),
+                    ">=" => Ok((a >= b) as i64),
+                    "<" => Ok((a < b) as i64),
+                    ">" => Ok((a > b) as i64),
+                    "<<" | ">>" if !(0..64).contains(&b) => bail!(format!("Shift count {} in expression is not in 0..=63", b)),
+                    "<<" => Ok(a << b),
+                    ">>" => Ok(a >> b),
+                    "+" => overflow(a.checked_add(b)),
+                    "-" => overflow(a.checked_sub(b)),
+                    "*" => overflow(a.checked_mul(b)),
+                    _ if b == 0 => bail!("Division by zero in expression"),
+                    "/" => overflow(a.checked_div(b)),
+                    _ => overflow(a.checked_rem(b)),
+                }
+            },
+        }
+    }
+}
+
+/// The result of a checked operation, or an error when it overflowed 64 bits.
+fn overflow(result: Option<i64>) -> Result<i64, Box<dyn Error>> {
+    match result {
+        Some(x) => Ok(x),
+        None => bail!("Arithmetic overflow in expression"),
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let operand = |e: &Expr| match e {
+            Expr::Binary(..) => format!("({})", e),
+            _ => e.to_string(),
+        };
+        match self {
+            Expr::Num(x) => write!(f, "{}", x),
+            Expr::Sym(s) => write!(f, "{}", s),
+            Expr::Defined(s) => write!(f, "defined({})", s),
+            Expr::Unary(op, a) => write!(f, "{}{}", op, operand(a)),
+            Expr::Binary(op, a, b) => write!(f, "{} {} {}", operand(a), op, operand(b)),
+        }
+    }
+}
+
+fn parse_binary(tokens: &[Token], pos: &mut usize, level: usize) -> Result<Expr, Box<dyn Error>> {
+    if level == PRECEDENCE.len() {
+        return parse_unary(tokens, pos);
+    }
+    let mut expr = parse_binary(tokens, pos, level + 1)?;
+    while let Some(Kind::Op(op)) = tokens.get(*pos).map(|t| &t.kind) {
+        if !PRECEDENCE[level].contains(op) {
+            break;
+        }
+        *pos += 1;
+        let rhs = parse_binary(tokens, pos, level + 1)?;
+        expr = Expr::Binary(op, Box::new(expr), Box::new(rhs));
+    }
+    Ok(expr)
+}
+
+fn parse_unary(tokens: &[Token], pos: &mut usize) -> Result<Expr, Box<dyn Error>> {
+    let token = match tokens.get(*pos) {
+        Some(x) => &x.kind,
+        None => bail!("Unexpected end of expression"),
+    };
+    *pos += 1;
+    match token {
+        Kind::Num(x) => Ok(Expr::Num(*x)),
+        Kind::Ident(s) if s == "defined" => {
+            let kind = |i: usize| tokens.get(*pos + i).map(|t| &t.kind);
+            let name = match (kind(0), kind(1), kind(2)) {
+                (Some(Kind::Op("(")), Some(Kind::Ident(name)), Some(Kind::Op(")"))) => name,
+                _ => bail!("Expected defined(NAME)"),
+            };
+            *pos += 3;
+            Ok(Expr::Defined(name.clone()))
+        },
+        Kind::Ident(s) => Ok(Expr::Sym(s.clone())),
+        Kind::Op("(") => {
+            let expr = parse_binary(tokens, pos, 0)?;
+            match tokens.get(*pos).map(|t| &t.kind) {
+                Some(Kind::Op(")")) => {
+                    *pos += 1;
+                    Ok(expr)
+                },
+                _ => bail!("Expected ')' in expression"),
+            }
+        },
+        Kind::Op(op) if *op == "-" || *op == "~" || *op == "!" => {
+            let expr = parse_unary(tokens, pos)?;
+            Ok(Expr::Unary(op, Box::new(expr)))
+        },
+        Kind::Op(op) => bail!(format!("Unexpected '{}' in expression", op)),
+        Kind::Str(_) => bail!("Unexpected string in expression"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn lookup(sym: &str) -> Option<i64> {
+        match sym {
+            "BOARD" => Some(2),
+            "UART" => Some(0xFFFF_FFE0),
+            "MAIN.loop" => Some(4),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn expr_eval_test() {
+        let tests = [
+            ("1 + 2 * 3", 7),
+            ("(1 + 2) * 3", 9),
+            ("-4 + 10", 6),
+            ("BOARD == 2", 1),
+            ("BOARD == 2 && UART > 0x1000", 1),
+            ("BOARD != 2 || 0", 0),
+            ("1 << 4 | 1", 17),
+            ("~0 & 0xFF", 255),
+            ("!BOARD", 0),
+            ("defined(BOARD)", 1),
+            ("defined(MISSING) || 7 % 4 == 3", 1),
+            ("'?' - 63", 0),
+            ("10 - 4 - 3", 3),
+            ("MAIN.loop + 1", 5),
+            ("1 << 63 >> 63", -1),
+        ];
+        for test in &tests {
+            let result = Expr::parse(test.0).unwrap().eval(&lookup).unwrap();
+            assert_eq!(result, test.1, "failed with [{}]", test.0);
+        }
+
+        let invalid_tests = [
+            "",
+            "1 +",
+            "(1",
+            "1 2",
+            "MISSING + 1",
+            "4 / 0",
+            "defined(1)",
+            "1 $ 2",
+            "1 << 64",
+            "1 << -1",
+            "1 >> 64",
+            "9223372036854775807 + 1",
+            "-9223372036854775807 - 2",
+            "0x100000000 * 0x100000000",
+            "-(-9223372036854775807 - 1)",
+            "(-9223372036854775807 - 1) / -1",
+        ];
+        for test in &invalid_tests {
+            let result = Expr::parse(test).and_then(|e| e.eval(&lookup));
+            assert!(result.is_err(), "failed with [{}]", test);
+        }
+
+        let expr = Expr::parse("defined(A) + -(.x.1 * B) - .").unwrap();
+        assert_eq!(expr.symbols(), vec![".x.1", "B", "."]);
+    }
+}
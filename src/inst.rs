@@ -1,13 +1,120 @@
 use std::error::Error;
+use std::fmt;
 use std::str::FromStr;
 use std::collections::HashMap;
+use crate::checksum;
+use crate::expr::Expr;
+use crate::lexer::{self, Kind, Statement, Token};
+use crate::isa::{self, Format};
+use crate::object::{self, Lookup, RelocKind, Relocation};
+use crate::prog::ABSOLUTE_SECTION;
+
+/// Register holding the return address for `call` and `ret`.
+pub const LINK_REGISTER: usize = 25;
+
+/// Register `jmp`, `jz`, `jnz` and `call` load their target into unless told otherwise.
+pub const SCRATCH_REGISTER: usize = 26;
+
+/// Register aliases defined before the first line. `sp` and `fp` follow the MIPS convention.
+pub const BUILTIN_ALIASES: [(&str, usize); 4] = [
+    ("zero", 0),
+    ("lr", LINK_REGISTER),
+    ("sp", 29),
+    ("fp", 30),
+];
+
+/// Settings that affect how a line is parsed, carried from one line to the next.
+pub struct Context {
+    pub scratch: usize,
+    /// Names usable in place of a register, from `BUILTIN_ALIASES` and `NAME .reg rN` lines.
+    pub aliases: HashMap<String, usize>,
+    /// The last global label, which local labels such as `.loop` belong to.
+    scope: Option<String>,
+    /// How many times each numeric label such as `1:` has been defined so far.
+    numeric: HashMap<String, usize>,
+}
+
+impl Default for Context {
+    fn default() -> Context {
+        Context {
+            scratch: SCRATCH_REGISTER,
+            aliases: BUILTIN_ALIASES.iter().map(|(name, r)| (name.to_string(), *r)).collect(),
+            scope: None,
+            numeric: HashMap::new(),
+        }
+    }
+}
+
+impl Context {
+    /// Records the definition of `label` and returns the name it is stored under:
+    /// `MAIN.loop` for `.loop` after `MAIN:`, and a unique name for each numeric label.
+    fn define_label(&mut self, label: &str) -> Result<String, Box<dyn Error>> {
+        if let Some(name) = label.strip_prefix('.') {
+            if !is_symbol(name) {
+                bail!(format!("Invalid local label \"{}\"", label));
+            }
+            match &self.scope {
+                Some(scope) => Ok(format!("{}{}", scope, label)),
+                None => bail!(format!("Local label \"{}\" appears before any global label", label)),
+            }
+        } else if is_numeric_label(label) {
+            let count = self.numeric.entry(label.to_string()).or_insert(0);
+            *count += 1;
+            Ok(numeric_label(label, *count))
+        } else {
+            self.scope = Some(label.to_string());
+            Ok(label.to_string())
+        }
+    }
+
+    /// The stored name of a `.local`, `1b` or `1f` reference, or `None` for any other text.
+    fn resolve_label(&self, reference: &str) -> Result<Option<String>, Box<dyn Error>> {
+        if reference.starts_with('.') {
+            return match &self.scope {
+                Some(scope) => Ok(Some(format!("{}{}", scope, reference))),
+                None => bail!(format!("Local label \"{}\" referenced before any global label", reference)),
+            };
+        }
+        let (number, direction) = match reference.char_indices().last() {
+            Some((x, _)) => reference.split_at(x),
+            None => return Ok(None),
+        };
+        if !is_numeric_label(number) {
+            return Ok(None);
+        }
+        let count = self.numeric.get(number).copied().unwrap_or(0);
+        match direction {
+            "b" if count == 0 => bail!(format!("No earlier definition of numeric label \"{}\" for \"{}\"", number, reference)),
+            "b" => Ok(Some(numeric_label(number, count))),
+            "f" => Ok(Some(numeric_label(number, count + 1))),
+            _ => Ok(None),
+        }
+    }
+}
+
+fn is_numeric_label(label: &str) -> bool {
+    !label.is_empty() && label.chars().all(|c| c.is_ascii_digit())
+}
+
+/// The stored name of the `count`th definition of numeric label `number`.
+fn numeric_label(number: &str, count: usize) -> String {
+    format!(".{}.{}", number, count)
+}
 
-pub struct InstLine<'a> {
-    pub raw: &'a str,
-    pub label: Option<&'a str>,
-    inst: Option<Inst<'a>>,
+pub struct InstLine {
+    pub label: Option<String>,
+    insts: Vec<Inst>,
+    /// Words emitted by `.word`, after any instructions.
+    data: Vec<Con>,
+    /// The kind and range of a `.crc32` or `.checksum`, whose word is left as zero in `data`
+    /// until every other word is known.
+    checksum: Option<(checksum::Kind, Con, Con)>,
+    pseudo: bool,
+    /// Register overwritten behind the programmer's back by a branch pseudo-instruction.
+    clobbers: Option<usize>,
     pub offset: Offset,
-    comment: Option<&'a str>,
+    #[allow(dead_code)]
+    comment: Option<String>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -17,189 +124,212 @@ pub enum Offset {
 }
 
 #[derive(Debug, PartialEq)]
-struct Inst<'a> {
-    opcode: Opcode,
-    params: Params<'a>,
+struct Inst {
+    opcode: &'static isa::Def,
+    params: Params,
 }
 
 #[derive(Debug, PartialEq)]
-struct Params<'a> {
+struct Params {
     ra: Option<usize>,
     rb: Option<usize>,
     rc: Option<usize>,
-    c1: Option<Con<'a>>,
-    c2: Option<Con<'a>>,
-    c3: Option<Con<'a>>,
+    c1: Option<Con>,
+    c2: Option<Con>,
+    c3: Option<Con>,
 }
 
 #[derive(Debug, PartialEq)]
-enum Con<'a> {
+enum Con {
     C(usize),
-    S(&'a str),
+    S(String),
+    E(Expr),
 }
 
+/// Instructions that expand to one or more real instructions.
+#[allow(clippy::upper_case_acronyms)]
 #[derive(EnumString, Display, Debug, PartialEq)]
-enum Opcode {
-    // op
-    NOP, 
-    STOP,
-
-    // op_ra_rb_rc
-    ADD, 
-    SUB, 
-    AND, 
-    OR,
-
-    // op_ra_rb_c2
-    ADDI,
-    ANDI,
-    ORI,
-    LD,
-    ST,
-    LA,
-
-    // op_ra_c1
-    LDR,
-    STR,
-    LAR,
-    
-    // op_ra_rc
-    NEG,
-    NOT,
-
-    // op_ra_rb_rc_c3
-    BR,
-    BRNV,
-    BRZR,
-    BRNZ,
-    BRPL,
-    BRMI,
-
-    // op_ra_rb_n
-    SHR,
-    SHRA,
-    SHL,
-    SHC,
-}
-
-impl Opcode {
-    fn to_num (self: &Self) -> usize {
-        match self {
-            Opcode::NOP  => 0, 
-            Opcode::LD   => 1,
-            Opcode::LDR  => 2,
-            Opcode::ST   => 3,
-            Opcode::STR  => 4,
-            Opcode::LA   => 5,
-            Opcode::LAR  => 6,
-            Opcode::BR   => 8,
-            Opcode::BRNV => 8,
-            Opcode::BRZR => 8,
-            Opcode::BRNZ => 8,
-            Opcode::BRPL => 8,
-            Opcode::BRMI => 8,
-            Opcode::ADD  => 12, 
-            Opcode::ADDI => 13,
-            Opcode::SUB  => 14, 
-            Opcode::NEG  => 15,
-            Opcode::AND  => 20, 
-            Opcode::ANDI => 21,
-            Opcode::OR   => 22,
-            Opcode::ORI  => 23,
-            Opcode::NOT  => 24,
-            Opcode::SHR  => 26,
-            Opcode::SHRA => 27,
-            Opcode::SHL  => 28,
-            Opcode::SHC  => 29,
-            Opcode::STOP => 31,
-        }
-    }
-}
-
-impl<'a> InstLine<'a> {
-    pub fn new(inst: &'a str) -> Result<Option<InstLine<'a>>, Box<dyn Error>> {
-        let raw = inst;
-        let line = raw.trim();
-
-        let (line, comment) = match line.find(';') {
-            Some(x) => (line[..x].trim(), Some(line[(x+1)..].trim())),
-            None    => (line[..].trim(), None),
-        };
+enum Pseudo {
+    MOV,
+    CLR,
+    INC,
+    DEC,
+    LI,
+    JMP,
+    JZ,
+    JNZ,
+    CALL,
+    RET,
+}
 
-        let (line, label) = match line.find(':') {
-            Some(x) => (line[(x+1)..].trim(), Some(line[..x].trim())),
-            None    => (line[ ..].trim(), None)
+impl InstLine {
+    pub fn new(inst: &str, context: &mut Context) -> Result<Option<InstLine>, Box<dyn Error>> {
+        let Statement { label, mnemonic, mut operands, comment } = Statement::parse(inst)?;
+
+        let label = match label {
+            Some((x, _)) => Some(context.define_label(&x)?),
+            None => None,
         };
 
-        let (inst, offset) = match line.find('.') {
-            Some(x) => (None, process_directive(line)?),
-            None => match process_instruction(line)? {
-                Some(x) => (Some(x), Offset::Relative(4)),
-                None => (None, Offset::Relative(0)),
-            }, 
+        let mut data = Vec::new();
+        let mut checksum = None;
+        let (insts, pseudo, offset) = match mnemonic.map(|x| x.0) {
+            Some(x) if x == ".crc32" || x == ".checksum" => {
+                resolve_operands(&mut operands, context)?;
+                expect_operands(&operands, 2)?;
+                let kind = match x.as_str() {
+                    ".crc32" => checksum::Kind::Crc32,
+                    _ => checksum::Kind::Sum,
+                };
+                checksum = Some((kind, parse_constant(&operands[0])?, parse_constant(&operands[1])?));
+                data.push(Con::C(0));
+                (Vec::new(), false, Offset::Relative(4))
+            },
+            Some(x) if x == ".word" => {
+                resolve_operands(&mut operands, context)?;
+                data = process_word(&operands)?;
+                (Vec::new(), false, Offset::Relative(4 * data.len()))
+            },
+            Some(x) if x.starts_with('.') => (Vec::new(), false, process_directive(&x, &operands)?),
+            Some(x) => {
+                resolve_operands(&mut operands, context)?;
+                let (insts, pseudo) = process_instructions(&x, &operands, context)?;
+                let offset = Offset::Relative(4 * insts.len());
+                (insts, pseudo, offset)
+            },
+            None => (Vec::new(), false, Offset::Relative(0)),
+        };
+        let clobbers = match insts.first() {
+            Some(x) if pseudo && x.opcode.mnemonic == "lar" && x.params.ra == Some(context.scratch)
+                => Some(context.scratch),
+            _ => None,
         };
 
         Ok(Some(
             InstLine{
-                raw,
                 label,
-                inst,
+                insts,
+                data,
+                checksum,
+                pseudo,
+                clobbers,
                 offset,
                 comment,
             }
         ))
     }
 
-    pub fn encode_instruction(self: &Self, symbol_map: &HashMap<&'a str, usize>, pc: usize) -> Result<Option<usize>, Box<dyn Error>> {
-        match &self.inst.is_some() {
-            true => match &self.inst {
-                        Some(x) => Ok(Some(x.encode_instruction(symbol_map, pc)?)),
-                        None => bail!("No instruction"),
-                    },
-            false => Ok(None),
+    /// Whether the line emits any instructions or data words.
+    pub fn emits_words(&self) -> bool {
+        !self.insts.is_empty() || !self.data.is_empty()
+    }
+
+    /// Whether the line was a pseudo-instruction, so its expansion is worth showing.
+    pub fn is_pseudo(&self) -> bool {
+        self.pseudo
+    }
+
+    /// The scratch register when the line is a `jmp`, `jz`, `jnz` or `call`.
+    pub fn clobbers(&self) -> Option<usize> {
+        self.clobbers
+    }
+
+    /// Whether register `r` is named in the line itself, rather than only in its expansion.
+    pub fn uses_register(&self, r: usize) -> bool {
+        self.clobbers.is_none() && self.insts.iter()
+            .any(|x| x.params.ra == Some(r) || x.params.rb == Some(r) || x.params.rc == Some(r))
+    }
+
+    /// The instructions the line expands to, one per encoded word.
+    pub fn expansion(&self) -> Vec<String> {
+        self.insts.iter().map(|x| x.to_string()).collect()
+    }
+
+    /// The kind and range, from its start up to but not including its end, of a `.crc32` or
+    /// `.checksum` line placed at `pc`.
+    pub fn checksum_range(&self, symbol_map: &HashMap<String, usize>, pc: usize) -> Result<Option<ChecksumRange>, Box<dyn Error>> {
+        match &self.checksum {
+            Some((kind, start, end)) => Ok(Some((*kind, start.value(symbol_map, pc)?, end.value(symbol_map, pc)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Encodes every instruction and data word on the line, the first placed at `pc`. The word
+    /// of a `.crc32` or `.checksum` is left as zero; see `checksum_range`.
+    pub fn encode_instructions(&self, symbol_map: &HashMap<String, usize>, pc: usize) -> Result<Vec<usize>, Box<dyn Error>> {
+        let mut words = self.insts.iter()
+            .enumerate()
+            .map(|(i, x)| x.encode_instruction(symbol_map, pc + 4 * i))
+            .collect::<Result<Vec<usize>, Box<dyn Error>>>()?;
+        for x in &self.data {
+            words.push(word_value(x.value(symbol_map, pc + 4 * words.len())? as i64)? as usize);
+        }
+        Ok(words)
+    }
+
+    /// Encodes the line for an object, where `pc` is an offset into `section`. Fields holding
+    /// an address from another section or object are left as zero, and come back with the
+    /// relocation that fills them in.
+    pub fn encode_relocatable(&self, lookup: &Lookup, section: &str, pc: usize) -> Result<Vec<RelocatableWord>, Box<dyn Error>> {
+        if let Some((kind, _, _)) = &self.checksum {
+            bail!(format!(".{} cannot be computed before linking; give orange-ld --{} instead", kind, kind));
+        }
+        let mut words = Vec::new();
+        for (i, x) in self.insts.iter().enumerate() {
+            words.push(x.encode_relocatable(lookup, section, pc + 4 * i)?);
+        }
+        for x in &self.data {
+            let pc = pc + 4 * words.len();
+            words.push(match x.split(lookup, section, pc)? {
+                (value, None) => (word_value(value)? as usize, None),
+                (addend, Some(symbol)) => (0, Some(Relocation { offset: pc, kind: RelocKind::Word, symbol, addend })),
+            });
         }
+        Ok(words)
     }
 }
 
-impl<'a> Inst<'a> {
-    pub fn encode_instruction(self: &Self, symbol_map: &HashMap<&'a str, usize>, pc: usize) -> Result<usize, Box<dyn Error>> {
-        let op = self.opcode.to_num();
-        let ra = match self.params.ra {
-            Some(x) => x, 
-            None => 0,
-        };
-        let rb = match self.params.rb {
-            Some(x) => x, 
-            None => 0,
-        };
-        let rc = match self.params.rc {
-            Some(x) => x, 
-            None => 0,
-        };
-        let c1 = match self.params.c1 {
-            Some(Con::C(x)) => (x + (1<<22) - 4 - pc) % (1<<22),
-            Some(Con::S(s)) => match symbol_map.get(s) {
-                Some(x) => (*x + (1<<22) - 4 - pc) % (1<<22),
-                None => bail!("Undefined symbol"),
+/// An encoded word and the relocation that completes it, if any.
+pub type RelocatableWord = (usize, Option<Relocation>);
+
+/// The kind of a checksum and the start and end of the range it covers.
+pub type ChecksumRange = (checksum::Kind, usize, usize);
+
+impl Inst {
+    /// `.` in constants refers to `pc`, the address of this instruction.
+    pub fn encode_instruction(&self, symbol_map: &HashMap<String, usize>, pc: usize) -> Result<usize, Box<dyn Error>> {
+        let op = self.opcode.op;
+        let ra = self.params.ra.unwrap_or(0);
+        let rb = self.params.rb.unwrap_or(0);
+        let rc = self.params.rc.unwrap_or(0);
+        // Both are signed; a value given as an unsigned word, such as 0xFFFFFFE0, stands for
+        // the negative number it encodes.
+        let c1 = match &self.params.c1 {
+            Some(x) => {
+                let target = word_value(x.value(symbol_map, pc)? as i64)?;
+                match signed_field(target.wrapping_sub(pc as u32 + 4) as i32 as i64, 22) {
+                    Ok(x) => x as usize,
+                    Err(_) => bail!(format!("Address {:#010x} is out of reach of {} at {:#010x} (22-bit signed offset)",
+                        target, self.opcode.mnemonic, pc)),
+                }
             },
             None => 0,
         };
-    
-        let c2 = match self.params.c2 {
-            Some(Con::C(x)) => x % (1<<17),
-            Some(Con::S(s)) => match symbol_map.get(s) {
-                Some(x) => *x,
-                None => bail!("Undefined symbol"),
+        let c2 = match &self.params.c2 {
+            Some(x) => {
+                let value = word_value(x.value(symbol_map, pc)? as i64)?;
+                match signed_field(value as i32 as i64, 17) {
+                    Ok(x) => x as usize,
+                    Err(_) => bail!(format!("Constant {:#x} ({}) does not fit in the 17-bit signed constant of {}",
+                        value, value as i32, self.opcode.mnemonic)),
+                }
             },
             None => 0,
         };
-        let c3 = match self.params.c3 {
-            Some(Con::C(x)) => x % (1<<12),
-            Some(Con::S(s)) => match symbol_map.get(s) {
-                Some(x) => *x,
-                None => bail!("Undefined symbol"),
-            },
-            None => 0,
+        let c3 = match (&self.params.c3, self.opcode.format) {
+            (Some(x), isa::Format::Shift) => shift_count(x.value(symbol_map, pc)? as i64)?,
+            (Some(x), _) => x.value(symbol_map, pc)? % (1<<12),
+            (None, _) => 0,
         };
 
         let op = op << 27;
@@ -207,182 +337,616 @@ impl<'a> Inst<'a> {
         let rb = rb << 17;
         let rc = rc << 12;
 
-        print!("op:{}, r1:{}, r2:{}, r3:{}, c1:{}, c2:{}, c3:{}, sum:{}", op, ra, rb, rc, c1, c2, c3, op + ra + rb + rc + c1 + c2 + c3);
-
         Ok(op + ra + rb + rc + c1 + c2 + c3)
     }
+
+    /// Like `encode_instruction`, but leaving a c1 or c2 that is not known until linking to a
+    /// relocation. Branches within `section` need no relocation.
+    fn encode_relocatable(&self, lookup: &Lookup, section: &str, pc: usize) -> Result<RelocatableWord, Box<dyn Error>> {
+        let mut resolved = Params { ra: self.params.ra, rb: self.params.rb, rc: self.params.rc, c1: None, c2: None, c3: None };
+        let mut relocation = None;
+        if let Some(x) = &self.params.c1 {
+            resolved.c1 = match x.split(lookup, section, pc)? {
+                (target, Some(base)) if base == section => Some(Con::C(target as usize)),
+                (addend, base) => {
+                    let symbol = base.unwrap_or_else(|| String::from(ABSOLUTE_SECTION));
+                    relocation = Some(Relocation { offset: pc, kind: RelocKind::C1, symbol, addend });
+                    Some(Con::C(pc + 4))
+                },
+            };
+        }
+        if let Some(x) = &self.params.c2 {
+            resolved.c2 = match x.split(lookup, section, pc)? {
+                (value, None) => Some(Con::C(value as usize)),
+                (addend, Some(symbol)) => {
+                    relocation = Some(Relocation { offset: pc, kind: RelocKind::C2, symbol, addend });
+                    Some(Con::C(0))
+                },
+            };
+        }
+        if let Some(x) = &self.params.c3 {
+            resolved.c3 = match x.split(lookup, section, pc)? {
+                (value, None) => Some(Con::C(value as usize)),
+                _ => bail!(format!("\"{}\" must be a constant, not an address", x)),
+            };
+        }
+        let inst = Inst { opcode: self.opcode, params: resolved };
+        Ok((inst.encode_instruction(&HashMap::new(), pc)?, relocation))
+    }
+
+    /// Splits an encoded word back into an instruction, turning the PC-relative c1 of the
+    /// word at `pc` into the address it refers to.
+    fn decode(word: usize, pc: usize) -> Result<Inst, Box<dyn Error>> {
+        let field = |shift: usize, bits: usize| (word >> shift) & ((1 << bits) - 1);
+        let sign_extend = |x: usize, bits: usize| match x >> (bits - 1) {
+            0 => x,
+            _ => x.wrapping_sub(1 << bits),
+        };
+        let (op, ra, rb, rc) = (field(27, 5), field(22, 5), field(17, 5), field(12, 5));
+        let (c1, c2, c3) = (field(0, 22), field(0, 17), field(0, 12));
+
+        let opcode = match isa::decode(op, c3) {
+            Some(x) => x,
+            None => bail!(format!("Unknown opcode {} (condition {})", op, c3)),
+        };
+        let mut params = Params { ra: None, rb: None, rc: None, c1: None, c2: None, c3: None };
+        match opcode.format {
+            Format::Op => (),
+            Format::RaRbRc => {
+                params.ra = Some(ra);
+                params.rb = Some(rb);
+                params.rc = Some(rc);
+            },
+            Format::RaC2Rb | Format::RaRbC2 => {
+                params.ra = Some(ra);
+                params.rb = Some(rb);
+                params.c2 = Some(Con::C(sign_extend(c2, 17)));
+            },
+            Format::RaC1 => {
+                params.ra = Some(ra);
+                params.c1 = Some(Con::C((pc + 4).wrapping_add(sign_extend(c1, 22)) % (1 << 32)));
+            },
+            Format::RaRc => {
+                params.ra = Some(ra);
+                params.rc = Some(rc);
+            },
+            Format::RaRb => {
+                params.ra = Some(ra);
+                params.rb = Some(rb);
+            },
+            Format::Branch | Format::BranchLink => {
+                params.ra = match opcode.format {
+                    Format::BranchLink => Some(ra),
+                    _ => None,
+                };
+                params.rb = Some(rb);
+                params.rc = Some(rc);
+                params.c3 = Some(Con::C(c3));
+            },
+            Format::Shift => {
+                params.ra = Some(ra);
+                params.rb = Some(rb);
+                params.rc = Some(rc);
+                params.c3 = Some(Con::C(c3 % 32));
+            },
+        }
+        Ok(Inst { opcode, params })
+    }
+}
+
+/// The assembly for the word at `pc`, or an error when no instruction encodes to exactly `word`.
+pub fn disassemble(word: usize, pc: usize) -> Result<String, Box<dyn Error>> {
+    let inst = Inst::decode(word, pc)?;
+    match inst.encode_instruction(&HashMap::new(), pc)? {
+        x if x == word => Ok(inst.to_string()),
+        _ => bail!(format!("{:08x} is not a valid instruction", word)),
+    }
 }
 
-fn process_instruction<'a>(inst: &'a str) -> Result<Option<Inst<'a>>, Box<dyn Error>> {
-    if inst.is_empty() {
-        Ok(None)
-    } else {
-        let (opcode, inst) = match inst.find(' ') {
-            Some(x) => (&inst[ ..x], &inst[x.. ]),
-            None =>    (&inst[ .. ], ""),
+impl Con {
+    /// See `object::split`; `.` is `pc` in `section`.
+    fn split(&self, lookup: &Lookup, section: &str, pc: usize) -> Result<(i64, Option<String>), Box<dyn Error>> {
+        let expr = match self {
+            Con::C(x) => return Ok((*x as i64, None)),
+            Con::S(s) => Expr::Sym(s.clone()),
+            Con::E(e) => e.clone(),
         };
-        let opcode = match Opcode::from_str(&opcode.to_uppercase()) {
-            Ok(x) => x,
-            Err(_) => bail!("Opcode not found"),
+        object::split(&expr, &|s| match s {
+            "." => Some((Some(section.to_string()), pc as i64)),
+            _ => lookup(s),
+        })
+    }
+
+    fn value(&self, symbol_map: &HashMap<String, usize>, pc: usize) -> Result<usize, Box<dyn Error>> {
+        match self {
+            Con::C(x) => Ok(*x),
+            Con::S(s) => match symbol_map.get(s) {
+                Some(x) => Ok(*x),
+                None => bail!(format!("Undefined symbol \"{}\"", s)),
+            },
+            Con::E(e) => {
+                let missing = e.symbols().into_iter().find(|s| *s != "." && !symbol_map.contains_key(*s));
+                if let Some(s) = missing {
+                    match s.strip_prefix('.').and_then(|x| x.split('.').next()) {
+                        Some(number) if is_numeric_label(number) =>
+                            bail!(format!("No later definition of numeric label \"{}\" for \"{}f\"", number, number)),
+                        _ => bail!(format!("Undefined symbol \"{}\"", s)),
+                    }
+                }
+                Ok(e.eval(&|s| match s {
+                    "." => Some(pc as i64),
+                    _ => symbol_map.get(s).map(|x| *x as i64),
+                })? as usize)
+            },
+        }
+    }
+}
+
+impl fmt::Display for Con {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Con::C(x) => write!(f, "{}", *x as isize),
+            Con::S(s) => write!(f, "{}", s),
+            Con::E(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl fmt::Display for Inst {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = self.opcode.mnemonic;
+        let p = &self.params;
+        let reg = |r: Option<usize>| format!("r{}", r.unwrap_or(0));
+        let con = |c: &Option<Con>| match c {
+            Some(x) => x.to_string(),
+            None => String::from("0"),
         };
-        let params = match process_params(inst, &opcode) {
-            Ok(x) => x,
-            Err(x) => return Err(x),
+        match self.opcode.format {
+            Format::Op
+                => write!(f, "{}", op),
+            Format::RaRbRc
+                => write!(f, "{} {}, {}, {}", op, reg(p.ra), reg(p.rb), reg(p.rc)),
+            Format::RaC2Rb => match p.rb {
+                Some(0) | None => write!(f, "{} {}, {}", op, reg(p.ra), con(&p.c2)),
+                _ => write!(f, "{} {}, {}({})", op, reg(p.ra), con(&p.c2), reg(p.rb)),
+            },
+            Format::RaRbC2
+                => write!(f, "{} {}, {}, {}", op, reg(p.ra), reg(p.rb), con(&p.c2)),
+            Format::RaC1
+                => write!(f, "{} {}, {}", op, reg(p.ra), con(&p.c1)),
+            Format::RaRc
+                => write!(f, "{} {}, {}", op, reg(p.ra), reg(p.rc)),
+            Format::RaRb
+                => write!(f, "{} {}, {}", op, reg(p.ra), reg(p.rb)),
+            Format::Branch | Format::BranchLink => {
+                let mut operands = Vec::new();
+                if self.opcode.format == Format::BranchLink {
+                    operands.push(reg(p.ra));
+                }
+                match self.opcode.cond {
+                    Some(isa::NEVER) => (),
+                    Some(isa::ALWAYS) => operands.push(reg(p.rb)),
+                    _ => operands.extend(vec![reg(p.rb), reg(p.rc)]),
+                }
+                match operands.is_empty() {
+                    true => write!(f, "{}", op),
+                    false => write!(f, "{} {}", op, operands.join(", ")),
+                }
+            },
+            Format::Shift => match p.rc {
+                Some(0) | None => write!(f, "{} {}, {}, {}", op, reg(p.ra), reg(p.rb), con(&p.c3)),
+                _ => write!(f, "{} {}, {}, {}", op, reg(p.ra), reg(p.rb), reg(p.rc)),
+            },
+        }
+    }
+}
+
+/// Expands pseudo-instructions; real instructions come back as a single element.
+fn process_instructions(mnemonic: &str, ops: &[Vec<Token>], context: &Context) -> Result<(Vec<Inst>, bool), Box<dyn Error>> {
+    let upper = mnemonic.to_uppercase();
+    if let Ok(pseudo) = Pseudo::from_str(&upper) {
+        return Ok((process_pseudo(ops, &pseudo, context)?, true));
+    }
+    if upper == "NOP" && !ops.is_empty() {
+        return Ok((process_padding(ops)?, true));
+    }
+    Ok((vec![process_instruction(mnemonic, ops)?], false))
+}
+
+fn process_pseudo(ops: &[Vec<Token>], pseudo: &Pseudo, context: &Context) -> Result<Vec<Inst>, Box<dyn Error>> {
+    let scratch = context.scratch;
+    match pseudo {
+        Pseudo::MOV => {
+            let params = process_op_ra_rc(ops)?;
+            Ok(vec![inst_ra_rb_c2(isa::get("addi"), params.ra, params.rc, Con::C(0))])
+        },
+        Pseudo::CLR => {
+            expect_operands(ops, 1)?;
+            let ra = Some(register(&ops[0])?);
+            Ok(vec![inst_ra_rb_c2(isa::get("la"), ra, Some(0), Con::C(0))])
+        },
+        Pseudo::INC | Pseudo::DEC => {
+            expect_operands(ops, 1)?;
+            let ra = Some(register(&ops[0])?);
+            let step = match pseudo {
+                Pseudo::INC => 1,
+                _ => usize::MAX,
+            };
+            Ok(vec![inst_ra_rb_c2(isa::get("addi"), ra, ra, Con::C(step))])
+        },
+        Pseudo::LI => {
+            expect_operands(ops, 2)?;
+            let ra = register(&ops[0])?;
+            match parse_constant(&ops[1])? {
+                Con::C(x) => Ok(load_immediate(ra, word_value(x as i64)?)),
+                Con::S(s) => Ok(load_expression(ra, Expr::Sym(s))),
+                Con::E(e) => Ok(load_expression(ra, e)),
+            }
+        },
+        Pseudo::JMP => {
+            expect_operands(ops, 1)?;
+            Ok(vec![
+                inst_ra_c1(isa::get("lar"), scratch, parse_constant(&ops[0])?),
+                inst_branch(isa::get("br"), scratch, 0),
+            ])
+        },
+        Pseudo::JZ | Pseudo::JNZ => {
+            expect_operands(ops, 2)?;
+            let rc = register(&ops[0])?;
+            if rc == scratch {
+                bail!(format!("Condition register r{} is the scratch register and is overwritten by the branch target", rc));
+            }
+            let opcode = match pseudo {
+                Pseudo::JZ => isa::get("brzr"),
+                _ => isa::get("brnz"),
+            };
+            Ok(vec![
+                inst_ra_c1(isa::get("lar"), scratch, parse_constant(&ops[1])?),
+                inst_branch(opcode, scratch, rc),
+            ])
+        },
+        Pseudo::CALL => {
+            expect_operands(ops, 1)?;
+            let branch = inst_branch(isa::get("brl"), scratch, 0);
+            Ok(vec![
+                inst_ra_c1(isa::get("lar"), scratch, parse_constant(&ops[0])?),
+                Inst { opcode: branch.opcode, params: Params { ra: Some(LINK_REGISTER), ..branch.params } },
+            ])
+        },
+        Pseudo::RET => {
+            process_op(ops)?;
+            Ok(vec![inst_branch(isa::get("br"), LINK_REGISTER, 0)])
+        },
+    }
+}
+
+/// Parses `NAME .reg rN`, returning `None` for any other line.
+pub fn process_reg(line: &str) -> Result<Option<(String, usize)>, Box<dyn Error>> {
+    let tokens = lexer::tokenize(line)?;
+    let words: Vec<&Kind> = tokens.iter().map(|t| &t.kind).collect();
+    match words.as_slice() {
+        [Kind::Ident(name), Kind::Ident(directive), Kind::Ident(reg)] if directive == ".reg" => {
+            if !is_symbol(name) || register_string_parse(name).is_ok() {
+                bail!(format!("Invalid register alias \"{}\"", name));
+            }
+            Ok(Some((name.to_string(), register_string_parse(reg)?)))
+        },
+        [_, Kind::Ident(directive), ..] if directive == ".reg" => bail!("Expected NAME .reg rN"),
+        _ => Ok(None),
+    }
+}
+
+/// Rewrites the operands of an instruction: register aliases become the register they name,
+/// and `.local`, `1b` and `1f` label references the name the label is stored under.
+fn resolve_operands(ops: &mut [Vec<Token>], context: &Context) -> Result<(), Box<dyn Error>> {
+    for token in ops.iter_mut().flatten() {
+        let name = match &token.kind {
+            Kind::Ident(x) if x != "." => x,
+            _ => continue,
+        };
+        let resolved = match context.aliases.get(name) {
+            Some(r) => format!("r{}", r),
+            None => match context.resolve_label(name)? {
+                Some(x) => x,
+                None => continue,
+            },
         };
-        Ok(Some(Inst{
-            opcode,
-            params,
-        }))
+        token.kind = Kind::Ident(resolved);
     }
+    Ok(())
 }
 
-fn process_directive<'a>(line: &str) -> Result<Offset, Box<dyn Error>> {
-    let (dir, val) = match line.find(' ') {
-        Some(x) => (line[..x].trim(), line[(x+1)..].trim()),
-        None => bail!("Could not interpret directive (no parameter)"), 
-    };
-    match dir {
-        ".org" => Ok(Offset::Absolute(val.parse::<usize>()?)),
-        ".dw" => Ok(Offset::Relative(32 * val.parse::<usize>()?)),
-        _ => bail!("Could not interpret directive (invalid directive)"),
+/// Parses the register named by `.scratch`, which must leave r0 and the link register alone.
+pub fn process_scratch(arg: &str) -> Result<usize, Box<dyn Error>> {
+    match register_string_parse(arg)? {
+        0 => bail!("r0 cannot be the scratch register"),
+        LINK_REGISTER => bail!(format!("The link register r{} cannot be the scratch register", LINK_REGISTER)),
+        x => Ok(x),
     }
 }
 
-fn process_params<'a> (inst: &'a str, opcode: &Opcode) -> Result<Params<'a>, Box<dyn Error>> {
-    match opcode {
-        Opcode::NOP | Opcode::STOP 
-            => process_op(inst),
+/// `nop N` pads with N `nop` instructions.
+fn process_padding(ops: &[Vec<Token>]) -> Result<Vec<Inst>, Box<dyn Error>> {
+    expect_operands(ops, 1)?;
+    match parse_constant(&ops[0])? {
+        Con::C(x) if x > 0 && x <= 1<<20 => (0..x)
+            .map(|_| process_op(&[]).map(|params| Inst { opcode: isa::get("nop"), params }))
+            .collect(),
+        _ => bail!("nop padding expects a positive constant count"),
+    }
+}
 
-        Opcode::ADD | Opcode::SUB | Opcode::AND | Opcode::OR 
-            => process_op_ra_rb_rc(inst),
+/// `value` as a word, when it fits as either a signed or an unsigned 32-bit number.
+pub fn word_value(value: i64) -> Result<u32, Box<dyn Error>> {
+    match value {
+        x if (-(1 << 31)..(1 << 32)).contains(&x) => Ok(x as u32),
+        x => bail!(format!("{} does not fit in a word", x)),
+    }
+}
 
-        Opcode::LD | Opcode::ST | Opcode::LA
-            => process_op_ra_c2_rb(inst),
+/// `value` as a field of `bits` bits, when it fits as a signed number.
+pub fn signed_field(value: i64, bits: u32) -> Result<u32, Box<dyn Error>> {
+    match value {
+        x if (-(1 << (bits - 1))..(1 << (bits - 1))).contains(&x) => Ok((x as u32) & ((1 << bits) - 1)),
+        x => bail!(format!("{} does not fit in {} signed bits", x, bits)),
+    }
+}
 
-        Opcode::ADDI | Opcode::ANDI | Opcode::ORI
-            => process_op_ra_rb_c2(inst),
+/// The shortest `la`, `la`+`shl`, `la`+`addi` or `la`+`shl`+`ori` sequence loading `value`.
+fn load_immediate(ra: usize, value: u32) -> Vec<Inst> {
+    let fits = |x: i64| (-(1<<16)..(1<<16)).contains(&x);
+    let la = |x: i64| inst_ra_rb_c2(isa::get("la"), Some(ra), Some(0), Con::C(x as usize));
+    let signed = value as i32 as i64;
 
-        Opcode::LDR | Opcode::STR | Opcode::LAR
-            => process_op_ra_c1(inst),
-        
-        Opcode::NEG | Opcode::NOT
-            => process_op_ra_rc(inst),
+    if fits(signed) {
+        return vec![la(signed)];
+    }
+    for shift in (1..32).rev() {
+        let base = signed >> shift;
+        if fits(base) && (base << shift) as u32 == value {
+            return vec![la(base), inst_ra_rb_c3(isa::get("shl"), ra, shift)];
+        }
+    }
+    if fits(signed - 0xFFFF) || fits(signed + 0x10000) {
+        let base = match signed > 0 {
+            true => 0xFFFF,
+            false => -0x10000,
+        };
+        return vec![la(base), inst_ra_rb_c2(isa::get("addi"), Some(ra), Some(ra), Con::C((signed - base) as usize))];
+    }
+    vec![
+        la(signed >> 16),
+        inst_ra_rb_c3(isa::get("shl"), ra, 16),
+        inst_ra_rb_c2(isa::get("ori"), Some(ra), Some(ra), Con::C((value & 0xFFFF) as usize)),
+    ]
+}
 
-        Opcode::BR | Opcode::BRNV | Opcode::BRZR | Opcode::BRNZ | Opcode::BRPL | Opcode::BRMI
-            => process_branch(inst, &opcode),
+/// Loads a value only known once symbols are placed, so always uses the full sequence.
+fn load_expression(ra: usize, value: Expr) -> Vec<Inst> {
+    let high = Expr::Binary(">>", Box::new(value.clone()), Box::new(Expr::Num(16)));
+    let low = Expr::Binary("&", Box::new(value), Box::new(Expr::Num(0xFFFF)));
+    vec![
+        inst_ra_rb_c2(isa::get("la"), Some(ra), Some(0), Con::E(high)),
+        inst_ra_rb_c3(isa::get("shl"), ra, 16),
+        inst_ra_rb_c2(isa::get("ori"), Some(ra), Some(ra), Con::E(low)),
+    ]
+}
 
-        Opcode::SHR | Opcode::SHRA | Opcode::SHL | Opcode::SHC
-            => process_op_ra_rb_rc_c3(inst),
+fn inst_ra_c1(opcode: &'static isa::Def, ra: usize, c1: Con) -> Inst {
+    Inst {
+        opcode,
+        params: Params {
+            ra: Some(ra),
+            rb: None,
+            rc: None,
+            c1: Some(c1),
+            c2: None,
+            c3: None,
+        },
     }
 }
 
-fn process_branch<'a> (inst: &'a str, opcode: &Opcode) -> Result<Params<'a>, Box<dyn Error>> {
-    let (rb, rc) = match opcode {
-        Opcode::BR => (inst, "r0"),
-        Opcode::BRNV if inst.is_empty() => ("r0", "r0"),
-        Opcode::BRZR | Opcode::BRNZ | Opcode::BRPL | Opcode::BRMI
-            => match inst.find(',') {
-                Some(x) => (&inst[..x], &inst[(x+1)..]),
-                None => bail!("Could not parse temp"),
-            },
-        _ => bail!("Opcode could not be matched"),
-    };
+fn inst_branch(opcode: &'static isa::Def, rb: usize, rc: usize) -> Inst {
+    Inst {
+        opcode,
+        params: Params {
+            ra: None,
+            rb: Some(rb),
+            rc: Some(rc),
+            c1: None,
+            c2: None,
+            c3: opcode.cond.map(Con::C),
+        },
+    }
+}
 
-    let rb = match register_string_parse(rb) {
-        Ok(x) => Some(x),
-        Err(x) => return Err(x),
+fn inst_ra_rb_c2(opcode: &'static isa::Def, ra: Option<usize>, rb: Option<usize>, c2: Con) -> Inst {
+    Inst {
+        opcode,
+        params: Params {
+            ra,
+            rb,
+            rc: None,
+            c1: None,
+            c2: Some(c2),
+            c3: None,
+        },
+    }
+}
+
+fn inst_ra_rb_c3(opcode: &'static isa::Def, ra: usize, c3: usize) -> Inst {
+    Inst {
+        opcode,
+        params: Params {
+            ra: Some(ra),
+            rb: Some(ra),
+            rc: Some(0),
+            c1: None,
+            c2: None,
+            c3: Some(Con::C(c3)),
+        },
+    }
+}
+
+fn process_instruction(mnemonic: &str, ops: &[Vec<Token>]) -> Result<Inst, Box<dyn Error>> {
+    let opcode = match isa::find(mnemonic) {
+        Some(x) => x,
+        None => bail!(format!("Opcode \"{}\" not found", mnemonic)),
     };
-    let rc = match register_string_parse(rc) {
-        Ok(x) => Some(x),
-        Err(x) => return Err(x),
+    let params = process_params(ops, opcode)?;
+    Ok(Inst{
+        opcode,
+        params,
+    })
+}
+
+fn process_directive(directive: &str, ops: &[Vec<Token>]) -> Result<Offset, Box<dyn Error>> {
+    if ops.is_empty() {
+        bail!("Could not interpret directive (no parameter)");
+    }
+    expect_operands(ops, 1)?;
+    let value = match parse_constant(&ops[0])? {
+        Con::C(x) => x as i64,
+        _ => bail!(format!("Could not interpret directive ({} expects a number)", directive)),
     };
+    match directive {
+        ".org" => match value {
+            x if (0..(1 << 32)).contains(&x) => Ok(Offset::Absolute(x as usize)),
+            x => bail!(format!("Address {} is not in the 32-bit address space", x)),
+        },
+        ".dw" => match value {
+            x if (0..=(1 << 27)).contains(&x) => Ok(Offset::Relative(32 * x as usize)),
+            x => bail!(format!("Cannot reserve {} blocks of 32 bytes", x)),
+        },
+        _ => bail!("Could not interpret directive (invalid directive)"),
+    }
+}
+
+/// Parses the operands of `.word`, each a 32-bit value.
+fn process_word(ops: &[Vec<Token>]) -> Result<Vec<Con>, Box<dyn Error>> {
+    if ops.is_empty() {
+        bail!("Could not interpret directive (no parameter)");
+    }
+    ops.iter().map(|x| parse_constant(x)).collect()
+}
+
+/// Checks that an instruction was given exactly `count` operands.
+fn expect_operands(ops: &[Vec<Token>], count: usize) -> Result<(), Box<dyn Error>> {
+    match ops.len() {
+        x if x == count => Ok(()),
+        x => bail!(format!("Expected {} operand{}, found {}", count, if count == 1 { "" } else { "s" }, x)),
+    }
+}
+
+fn process_params (ops: &[Vec<Token>], opcode: &isa::Def) -> Result<Params, Box<dyn Error>> {
+    match opcode.format {
+        Format::Op => process_op(ops),
+        Format::RaRbRc => process_op_ra_rb_rc(ops),
+        Format::RaC2Rb => process_op_ra_c2_rb(ops),
+        Format::RaRbC2 => process_op_ra_rb_c2(ops),
+        Format::RaC1 => process_op_ra_c1(ops),
+        Format::RaRc => process_op_ra_rc(ops),
+        Format::RaRb => process_op_ra_rb(ops),
+        Format::Branch => process_branch(ops, opcode),
+        Format::BranchLink => process_branch_link(ops, opcode),
+        Format::Shift => process_op_ra_rb_rc_c3(ops),
+    }
+}
 
-    let c3 = match opcode {
-        Opcode::BRNV => Some(Con::C(0)),
-        Opcode::BR   => Some(Con::C(1)),
-        Opcode::BRZR => Some(Con::C(2)),
-        Opcode::BRNZ => Some(Con::C(3)),
-        Opcode::BRPL => Some(Con::C(4)),
-        Opcode::BRMI => Some(Con::C(5)),
-        _ => Some(Con::C(0)),
+/// Parses the target rb and condition register rc of a branch, as many as its condition uses.
+fn process_branch (ops: &[Vec<Token>], opcode: &isa::Def) -> Result<Params, Box<dyn Error>> {
+    let (rb, rc) = match opcode.cond {
+        Some(isa::NEVER) => {
+            expect_operands(ops, 0)?;
+            (0, 0)
+        },
+        Some(isa::ALWAYS) => {
+            expect_operands(ops, 1)?;
+            (register(&ops[0])?, 0)
+        },
+        Some(_) => {
+            expect_operands(ops, 2)?;
+            (register(&ops[0])?, register(&ops[1])?)
+        },
+        None => bail!("Opcode could not be matched"),
     };
-    
+
     Ok(Params {
         ra: None,
-        rb: rb,
-        rc: rc,
+        rb: Some(rb),
+        rc: Some(rc),
         c1: None,
         c2: None,
-        c3: c3,
+        c3: opcode.cond.map(Con::C),
     })
 }
 
-fn process_op (inst: &str) -> Result<Params, Box<dyn Error>> {
-    let inst = inst.trim();
-    match inst.is_empty() {
-        false => bail!("why are there arguments"),
-        true => {
-            Ok(Params {
-                ra: None,
-                rb: None,
-                rc: None,
-                c1: None,
-                c2: None,
-                c3: None,
-            })
-        },
+/// Parses `brl ra, rb`, `brlnv ra` or `brlzr ra, rb, rc`: the matching branch with the
+/// register receiving the return address in front.
+fn process_branch_link (ops: &[Vec<Token>], opcode: &isa::Def) -> Result<Params, Box<dyn Error>> {
+    if opcode.format != Format::BranchLink {
+        bail!("Opcode could not be matched");
     }
+    let (ra, ops) = match ops.split_first() {
+        Some(x) => x,
+        None => bail!("Expected the register receiving the return address"),
+    };
+
+    let ra = register(ra)?;
+    let params = process_branch(ops, opcode)?;
+    Ok(Params {
+        ra: Some(ra),
+        ..params
+    })
 }
 
-fn process_op_ra_rb_rc (inst: &str) -> Result<Params, Box<dyn Error>> {
-    let (ra, inst) = match inst.find(',') {
-        Some(x) => (&inst[..x], &inst[(x+1)..]),
-        None => bail!("no comma temp"),
-    };
-    let (rb, inst) = match inst.find(',') {
-        Some(x) => (&inst[..x], &inst[(x+1)..]),
-        None => bail!("no comma temp"),
-    };
-    let rc = inst;
+fn process_op (ops: &[Vec<Token>]) -> Result<Params, Box<dyn Error>> {
+    expect_operands(ops, 0)?;
+    Ok(Params {
+        ra: None,
+        rb: None,
+        rc: None,
+        c1: None,
+        c2: None,
+        c3: None,
+    })
+}
+
+fn process_op_ra_rb_rc (ops: &[Vec<Token>]) -> Result<Params, Box<dyn Error>> {
+    expect_operands(ops, 3)?;
 
-    let ra = match register_string_parse(ra) {
+    let ra = match register(&ops[0]) {
         Ok(x) => Some(x),
         Err(x) => return Err(x),
     };
-    let rb = match register_string_parse(rb) {
+    let rb = match register(&ops[1]) {
         Ok(x) => Some(x),
         Err(x) => return Err(x),
     };
-    let rc = match register_string_parse(rc) {
+    let rc = match register(&ops[2]) {
         Ok(x) => Some(x),
         Err(x) => return Err(x),
     };
 
     Ok(Params {
-        ra: ra,
-        rb: rb,
-        rc: rc,
+        ra,
+        rb,
+        rc,
         c1: None,
         c2: None,
         c3: None,
     })
 }
 
-fn process_op_ra_c2_rb (inst: &str) -> Result<Params, Box<dyn Error>> {
-    let (ra, inst) = match inst.find(',') {
-        Some(x) => (&inst[..x], &inst[(x+1)..]),
-        None => bail!("no comma temp"),
-    };
-    let (c2, rb) = match (inst.find('('), inst.find(')')) {
-        (Some(x), Some(y)) if (x < y) => (&inst[..x], &inst[(x+1)..y]),
-        _ => (inst, "r0"),
+fn process_op_ra_c2_rb (ops: &[Vec<Token>]) -> Result<Params, Box<dyn Error>> {
+    expect_operands(ops, 2)?;
+    let (c2, rb) = match ops[1].as_slice() {
+        [c2 @ .., Token { kind: Kind::Op("("), .. }, Token { kind: Kind::Ident(rb), .. }, Token { kind: Kind::Op(")"), .. }]
+            if register_string_parse(rb).is_ok() => (c2, register_string_parse(rb)?),
+        c2 => (c2, 0),
     };
 
-    let ra = match register_string_parse(ra) {
-        Ok(x) => Some(x),
-        Err(x) => return Err(x),
-    };
-    let rb = match register_string_parse(rb) {
+    let ra = match register(&ops[0]) {
         Ok(x) => Some(x),
         Err(x) => return Err(x),
     };
@@ -392,145 +956,161 @@ fn process_op_ra_c2_rb (inst: &str) -> Result<Params, Box<dyn Error>> {
     };
 
     Ok(Params {
-        ra: ra,
-        rb: rb,
+        ra,
+        rb: Some(rb),
         rc: None,
         c1: None,
-        c2: c2,
+        c2,
         c3: None,
     })
 }
 
-fn process_op_ra_rb_c2 (inst: &str) -> Result<Params, Box<dyn Error>> {
-    let (ra, inst) = match inst.find(',') {
-        Some(x) => (&inst[..x], &inst[(x+1)..]),
-        None => bail!("no comma temp"),
-    };
-    let (rb, inst) = match inst.find(',') {
-        Some(x) => (&inst[..x], &inst[(x+1)..]),
-        None => bail!("no comma temp"),
-    };
-    let c2 = inst;
+fn process_op_ra_rb_c2 (ops: &[Vec<Token>]) -> Result<Params, Box<dyn Error>> {
+    expect_operands(ops, 3)?;
 
-    let ra = match register_string_parse(ra) {
+    let ra = match register(&ops[0]) {
         Ok(x) => Some(x),
         Err(x) => return Err(x),
     };
-    let rb = match register_string_parse(rb) {
+    let rb = match register(&ops[1]) {
         Ok(x) => Some(x),
         Err(x) => return Err(x),
     };
-    let c2 = match parse_constant(c2) {
+    let c2 = match parse_constant(&ops[2]) {
         Ok(x) => Some(x),
         Err(x) => return Err(x),
     };
 
     Ok(Params {
-        ra: ra,
-        rb: rb,
+        ra,
+        rb,
         rc: None,
         c1: None,
-        c2: c2,
+        c2,
         c3: None,
     })
 }
 
-fn process_op_ra_c1 (inst: &str) -> Result<Params, Box<dyn Error>> {
-    let (ra, inst) = match inst.find(',') {
-        Some(x) => (&inst[..x], &inst[(x+1)..]),
-        None => bail!("no comma temp"),
-    };
-    let c1 = inst;
+fn process_op_ra_c1 (ops: &[Vec<Token>]) -> Result<Params, Box<dyn Error>> {
+    expect_operands(ops, 2)?;
 
-    let ra = match register_string_parse(ra) {
+    let ra = match register(&ops[0]) {
         Ok(x) => Some(x),
         Err(x) => return Err(x),
     };
-    let c1 = match parse_constant(c1) {
+    let c1 = match parse_constant(&ops[1]) {
         Ok(x) => Some(x),
         Err(x) => return Err(x),
     };
 
     Ok(Params {
-        ra: ra,
+        ra,
         rb: None,
         rc: None,
-        c1: c1,
+        c1,
         c2: None,
         c3: None,
     })
 }
 
-fn process_op_ra_rc (inst: &str) -> Result<Params, Box<dyn Error>> {
-    let (ra, inst) = match inst.find(',') {
-        Some(x) => (&inst[..x], &inst[(x+1)..]),
-        None => bail!("no comma temp"),
-    };
-    let rc = inst;
+fn process_op_ra_rc (ops: &[Vec<Token>]) -> Result<Params, Box<dyn Error>> {
+    expect_operands(ops, 2)?;
 
-    let ra = match register_string_parse(ra) {
+    let ra = match register(&ops[0]) {
         Ok(x) => Some(x),
         Err(x) => return Err(x),
     };
-    let rc = match register_string_parse(rc) {
+    let rc = match register(&ops[1]) {
         Ok(x) => Some(x),
         Err(x) => return Err(x),
     };
 
     Ok(Params {
-        ra: ra,
+        ra,
         rb: None,
-        rc: rc,
+        rc,
         c1: None,
         c2: None,
         c3: None,
     })
 }
 
-fn process_op_ra_rb_rc_c3 (inst: &str) -> Result<Params, Box<dyn Error>> {
-    let (ra, inst) = match inst.find(',') {
-        Some(x) => (&inst[..x], &inst[(x+1)..]),
-        None => bail!("no comma temp"),
+fn process_op_ra_rb (ops: &[Vec<Token>]) -> Result<Params, Box<dyn Error>> {
+    expect_operands(ops, 2)?;
+
+    let ra = match register(&ops[0]) {
+        Ok(x) => Some(x),
+        Err(x) => return Err(x),
     };
-    let (rb, inst) = match inst.find(',') {
-        Some(x) => (&inst[..x], &inst[(x+1)..]),
-        None => bail!("no comma temp"),
+    let rb = match register(&ops[1]) {
+        Ok(x) => Some(x),
+        Err(x) => return Err(x),
     };
 
-    let ra = match register_string_parse(ra) {
+    Ok(Params {
+        ra,
+        rb,
+        rc: None,
+        c1: None,
+        c2: None,
+        c3: None,
+    })
+}
+
+fn process_op_ra_rb_rc_c3 (ops: &[Vec<Token>]) -> Result<Params, Box<dyn Error>> {
+    expect_operands(ops, 3)?;
+
+    let ra = match register(&ops[0]) {
         Ok(x) => Some(x),
         Err(x) => return Err(x),
     };
-    let rb = match register_string_parse(rb) {
+    let rb = match register(&ops[1]) {
         Ok(x) => Some(x),
         Err(x) => return Err(x),
     };
-    let (rc, c3) = match (register_string_parse(inst), parse_constant(inst)) {
-        (Ok(x), Err(_)) => (Some(x), Some(Con::C(0))),
+    let (rc, c3) = match (register(&ops[2]), parse_constant(&ops[2])) {
+        (Ok(x), _) => (Some(x), Some(Con::C(0))),
+        (Err(_), Ok(Con::C(x))) => (Some(0), Some(Con::C(shift_count(x as i64)?))),
         (Err(_), Ok(x)) => (Some(0), Some(x)),
-        _ => bail!("Could not parse temp"),
+        _ => bail!("Expected a register or a constant shift count"),
     };
 
     Ok(Params {
-        ra: ra,
-        rb: rb,
-        rc: rc,
+        ra,
+        rb,
+        rc,
         c1: None,
         c2: None,
-        c3: c3,
+        c3,
     })
 }
 
-fn register_string_parse(reg: &str) -> Result<usize, Box<dyn Error>> {
+/// `value` as the count of a shift, which only has room for 0 to 31.
+fn shift_count(value: i64) -> Result<usize, Box<dyn Error>> {
+    match value {
+        x if (0..32).contains(&x) => Ok(x as usize),
+        x => bail!(format!("Shift count {} is not in 0..=31", x)),
+    }
+}
+
+/// The register named by an operand.
+fn register(operand: &[Token]) -> Result<usize, Box<dyn Error>> {
+    match operand {
+        [Token { kind: Kind::Ident(x), .. }] => register_string_parse(x),
+        _ => bail!("Expected a register"),
+    }
+}
+
+pub fn register_string_parse(reg: &str) -> Result<usize, Box<dyn Error>> {
     let reg = reg.trim();
     if reg.len() < 2 || reg.len() > 3  {
-        println!("e:[{}]", reg);
         bail!("Incorrect register formatting (too many/few characters)");
     }
 
-    let (a, b) = (reg.chars().next().unwrap(), &reg[1..]);
+    let a = reg.chars().next().unwrap();
+    let b = &reg[a.len_utf8()..];
 
-    if a != 'r' {
+    if a != 'r' && a != 'R' {
         bail!("Incorrect register formatting (does not start with 'r')");
     }
 
@@ -543,26 +1123,25 @@ fn register_string_parse(reg: &str) -> Result<usize, Box<dyn Error>> {
     }
 }
 
-fn parse_constant<'a>(con: &'a str) -> Result<Con, Box<dyn Error>> {
-    let con = con.trim();
-    let ch = con.chars().next().unwrap();
-    match ch {
-        '-' => match con[1..].parse::<usize>() {
-            Ok(x) => Ok(Con::C(!x + 1)),
-            Err(_) => match con.chars().all(char::is_alphanumeric) {
-                true => Ok(Con::S(con)),
-                false => bail!(format!("Could not parse constant \"{}\"", con)),
-            },
+fn parse_constant(operand: &[Token]) -> Result<Con, Box<dyn Error>> {
+    match operand {
+        [] => bail!("Could not parse constant (empty)"),
+        [Token { kind: Kind::Num(x), .. }] => Ok(Con::C(*x as usize)),
+        [Token { kind: Kind::Op("-"), .. }, Token { kind: Kind::Num(x), .. }] => Ok(Con::C((*x as usize).wrapping_neg())),
+        [Token { kind: Kind::Ident(x), .. }] if is_symbol(x) => Ok(Con::S(x.clone())),
+        _ => match Expr::parse_tokens(operand) {
+            Ok(x) => Ok(Con::E(x)),
+            Err(e) => bail!(format!("Could not parse constant ({})", e)),
         },
-        _ => match con.parse::<usize>() {
-            Ok(x) => Ok(Con::C(x)),
-            Err(_) => match con.chars().all(char::is_alphanumeric) {
-                true => Ok(Con::S(con)),
-                false => bail!(format!("Could not parse constant \"{}\"", con)),
-            },
-        }
     }
-    
+}
+
+pub fn is_symbol(sym: &str) -> bool {
+    match sym.chars().next() {
+        Some(ch) if ch.is_alphabetic() || ch == '_'
+            => sym.chars().all(|c| c.is_alphanumeric() || c == '_'),
+        _ => false,
+    }
 }
 
 #[cfg(test)]
@@ -570,21 +1149,206 @@ mod test {
 
     use super::*;
 
+    fn operands(text: &str) -> Result<Vec<Vec<Token>>, Box<dyn Error>> {
+        lexer::split_operands(&lexer::tokenize(text)?)
+    }
+
+    fn ops(text: &str) -> Vec<Vec<Token>> {
+        operands(text).unwrap()
+    }
+
+    fn instruction(text: &str) -> Result<Inst, Box<dyn Error>> {
+        let statement = Statement::parse(text)?;
+        process_instruction(&statement.mnemonic.unwrap_or_default().0, &statement.operands)
+    }
+
+    fn instructions(text: &str) -> Result<(Vec<Inst>, bool), Box<dyn Error>> {
+        let statement = Statement::parse(text)?;
+        process_instructions(&statement.mnemonic.unwrap_or_default().0, &statement.operands, &Context::default())
+    }
+
     #[test]
     fn inst_line_new_test() {
         let tests = [
-            ("add r1, r2, r3", None, Some(Inst{opcode: Opcode::ADD, params:Params{ra:Some(1), rb:Some(2), rc:Some(3), c1:None, c2:None, c3:None}}), None),
-            ("LABEL: stop ; comment", Some("LABEL"), Some(Inst{opcode: Opcode::STOP, params:Params{ra:None, rb:None, rc:None, c1:None, c2:None, c3:None}}), Some("comment")),
+            ("add r1, r2, r3", None, vec![Inst{opcode: isa::get("add"), params:Params{ra:Some(1), rb:Some(2), rc:Some(3), c1:None, c2:None, c3:None}}], None),
+            ("LABEL: stop ; comment", Some("LABEL"), vec![Inst{opcode: isa::get("stop"), params:Params{ra:None, rb:None, rc:None, c1:None, c2:None, c3:None}}], Some("comment")),
+        ];
+        for test in &tests {
+            let result = InstLine::new(test.0, &mut Context::default()).unwrap().unwrap();
+
+            assert_eq!(result.label.as_deref(), test.1);
+            assert_eq!(result.insts, test.2);
+            assert_eq!(result.comment.as_deref(), test.3);
+        }
+    }
+
+    /// Runs `la`, `addi`, `ori` and `shl` sequences to check the value they leave in `ra`.
+    #[test]
+    fn inst_line_whitespace_test() {
+        let tests = [
+            ("L1:\tld r1, 0xFFFFFFE8 ; Load RX_DATA_FLAG into r1", Some("L1"), vec![0x0841ffe8]),
+            ("\tbrzr r31, r1 ; Loop until RX_DATA_FLAG goes high", None, vec![0x403e1002]),
+            ("\taddi r3, r3, -63 ; Subtract ascii '?'", None, vec![0x68c7ffc1]),
+            ("\tandi r3, r3, 0   ; Clear r3", None, vec![0xa8c60000]),
+            ("\tadd\tr1,\tr2,\tr3", None, vec![0x60443000]),
+            ("  st  r1 , 4 ( r30 )  ", None, vec![0x187c0004]),
+            ("\tla r1, ';' ; semicolon", None, vec![0x2840003b]),
+            ("\tla r1, ':'", None, vec![0x2840003a]),
+            ("\tla r1, '.' ; 1.5: a comment with a colon", None, vec![0x2840002e]),
+            ("\tstop\t", None, vec![0xf8000000]),
+            ("END:", Some("END"), vec![]),
+        ];
+        for test in &tests {
+            let result = InstLine::new(test.0, &mut Context::default()).unwrap().unwrap();
+            assert_eq!(result.label.as_deref(), test.1, "failed with [{}]", test.0);
+            assert_eq!(result.encode_instructions(&HashMap::new(), 0).unwrap(), test.2, "failed with [{}]", test.0);
+        }
+
+        let invalid_tests = [
+            "add r1, r2, r3 r4",
+            "add r1, r2, r3, r4",
+            "add r1,, r2",
+            "la r1, 5 6",
+            "ld r1, 4(r2) r3",
+            "stop r1",
+        ];
+        for test in &invalid_tests {
+            let result = InstLine::new(test, &mut Context::default());
+            assert!(result.is_err(), "failed with [{}]", test);
+        }
+    }
+
+    fn simulate(insts: &[Inst]) -> u32 {
+        let sext = |x: usize| (((x as u32) << 15) as i32 >> 15) as u32;
+        let mut regs = [0u32; 32];
+        for inst in insts {
+            let word = inst.encode_instruction(&HashMap::new(), 0).unwrap();
+            let (ra, rb, c2) = ((word >> 22) & 31, (word >> 17) & 31, sext(word & 0x1FFFF));
+            let b = match rb {
+                0 => 0,
+                x => regs[x],
+            };
+            regs[ra] = match word >> 27 {
+                5 => b.wrapping_add(c2),
+                13 => regs[rb].wrapping_add(c2),
+                23 => regs[rb] | c2,
+                28 => regs[rb] << (word & 31),
+                x => panic!("unexpected opcode {}", x),
+            };
+        }
+        regs[1]
+    }
+
+    #[test]
+    fn load_immediate_test() {
+        let tests = [
+            (0, 1),
+            (65535, 1),
+            (0xFFFFFFE8, 1),
+            (0xFFFF0000, 1),
+            (0x80000000, 2),
+            (0x00100000, 2),
+            (100000, 2),
+            (0xFFFE0000 + 5, 2),
+            (0x12345678, 3),
+            (0xDEADBEEF, 3),
+            (0x7FFFFFFF, 3),
         ];
         for test in &tests {
-            let result = InstLine::new(test.0).unwrap().unwrap();
+            let result = load_immediate(1, test.0);
+            assert_eq!(result.len(), test.1, "failed with [{:#x}]", test.0);
+            assert_eq!(simulate(&result), test.0, "failed with [{:#x}]", test.0);
+        }
+    }
 
-            assert_eq!(result.label, test.1);
-            assert_eq!(result.inst, test.2);
-            assert_eq!(result.comment, test.3);
+    #[test]
+    fn process_instructions_test() {
+        let tests = [
+            ("mov r3, r2", vec![0x68c40000]),
+            ("clr r3", vec![0x28c00000]),
+            ("inc r5", vec![0x694a0001]),
+            ("dec r5", vec![0x694bffff]),
+            ("nop 3", vec![0, 0, 0]),
+            ("li r1, 0x12345678", vec![0x28401234, 0xe0420010, 0xb8425678]),
+            ("li r1, -1", vec![0x2841ffff]),
+            ("li r1, 0xFFFFFFFF", vec![0x2841ffff]),
+            ("li r1, -0x80000000", vec![0x2841ffff, 0xe042001f]),
+            ("li r1, TARGET + 4", vec![0x28400002, 0xe0420010, 0xb8420008]),
+        ];
+        let mut symbol_map = HashMap::new();
+        symbol_map.insert("TARGET".to_string(), 0x20004);
+        for test in &tests {
+            let (insts, pseudo) = instructions(test.0).unwrap();
+            assert!(pseudo);
+            let result: Vec<usize> = insts.iter().map(|x| x.encode_instruction(&symbol_map, 0).unwrap()).collect();
+            assert_eq!(result, test.1, "failed with [{}]", test.0);
+        }
+
+        let (insts, _) = instructions("li r1, 0x12345678").unwrap();
+        let text: Vec<String> = insts.iter().map(|x| x.to_string()).collect();
+        assert_eq!(text, vec!["la r1, 4660", "shl r1, r1, 16", "ori r1, r1, 22136"]);
+
+        let invalid_tests = [
+            "mov r1",
+            "clr 5",
+            "inc r1, r2",
+            "li r1",
+            "li 5, 5",
+            "li r1, 0x1FFFFFFFF",
+            "li r1, -0x80000001",
+            "nop 0",
+            "nop r1",
+        ];
+        for test in &invalid_tests {
+            let result = instructions(test);
+            assert!(result.is_err(), "failed with [{}]", test);
         }
     }
 
+    #[test]
+    fn branch_pseudo_test() {
+        let tests = [
+            ("jmp LOOP", 26, vec![0x368000bc, 0x40340001]),
+            ("jz r3, LOOP", 26, vec![0x368000bc, 0x40343002]),
+            ("jnz r3, LOOP", 26, vec![0x368000bc, 0x40343003]),
+            ("call LOOP", 26, vec![0x368000bc, 0x4e740001]),
+            ("ret", 26, vec![0x40320001]),
+            ("jmp LOOP", 20, vec![0x350000bc, 0x40280001]),
+            ("jmp .", 26, vec![0x36bffffc, 0x40340001]),
+        ];
+        let mut symbol_map = HashMap::new();
+        symbol_map.insert("LOOP".to_string(), 0x100);
+        for test in &tests {
+            let mut context = Context { scratch: test.1, ..Context::default() };
+            let result = InstLine::new(test.0, &mut context).unwrap().unwrap();
+            assert_eq!(result.encode_instructions(&symbol_map, 0x40).unwrap(), test.2, "failed with [{}]", test.0);
+        }
+
+        let result = InstLine::new("call LOOP", &mut Context::default()).unwrap().unwrap();
+        assert_eq!(result.expansion(), vec!["lar r26, LOOP", "brl r25, r26"]);
+        assert_eq!(result.clobbers(), Some(26));
+        assert!(!result.uses_register(26));
+        let result = InstLine::new("ret", &mut Context::default()).unwrap().unwrap();
+        assert_eq!(result.clobbers(), None);
+
+        let invalid_tests = [
+            "jmp",
+            "jz LOOP",
+            "jz r26, LOOP",
+            "jnz r3,",
+            "call",
+            "ret r1",
+        ];
+        for test in &invalid_tests {
+            let result = InstLine::new(test, &mut Context::default());
+            assert!(result.is_err(), "failed with [{}]", test);
+        }
+
+        assert_eq!(process_scratch("r20").unwrap(), 20);
+        assert!(process_scratch("r0").is_err());
+        assert!(process_scratch("r25").is_err());
+    }
+
     #[test]
     fn process_instruction_test() {
         let tests = [
@@ -594,12 +1358,99 @@ mod test {
             ("br r29", 0x403a0001),
             ("stop", 0xf8000000),
             ("st r1,0(r30)", 0x187c0000),
+            ("brl r25, r1", 0x4e420001),
+            ("brlnv r25", 0x4e400000),
+            ("brlmi r31, r1, r2", 0x4fc22005),
+            ("een", 0x50000000),
+            ("edi", 0x58000000),
+            ("svi r1, r2", 0x80440000),
+            ("ri r1, r2", 0x88440000),
+            ("rfi", 0xf0000000),
         ];
         for test in &tests {
-            let result = process_instruction(test.0).unwrap().unwrap();
+            let result = instruction(test.0).unwrap();
             let result = result.encode_instruction(&HashMap::new(), 0).unwrap();
             assert_eq!(result, test.1);
         }
+
+        let invalid_tests = [
+            "rfi r1",
+            "een 1",
+            "svi r1",
+            "svi r1, 4",
+            "ri r1, r2, r3",
+        ];
+        for test in &invalid_tests {
+            let result = instruction(test);
+            assert!(result.is_err(), "failed with [{}]", test);
+        }
+    }
+
+    #[test]
+    fn encode_range_test() {
+        let encode = |text: &str| instruction(text).and_then(|x| x.encode_instruction(&HashMap::new(), 0));
+        let tests = [
+            ("addi r1, r2, 0xFFFFFFE0", "addi r1, r2, -32"),
+            ("la r1, 65535", "la r1, 0xFFFF"),
+            ("la r1, 0xFFFF0000", "la r1, -65536"),
+            ("lar r1, 0x200003", "lar r1, 2097155"),
+            ("ldr r1, 0xFFE00004", "ldr r1, -0x1FFFFC"),
+        ];
+        for test in &tests {
+            assert_eq!(encode(test.0).unwrap(), encode(test.1).unwrap(), "failed with [{}]", test.0);
+        }
+
+        let invalid_tests = [
+            "la r1, 70000",
+            "la r1, 65536",
+            "la r1, -65537",
+            "addi r1, r2, 0xFFFFFFFFFFF",
+            "ld r1, 0x10000(r2)",
+            "lar r1, 0x200004",
+            "ldr r1, -0x200000",
+        ];
+        for test in &invalid_tests {
+            assert!(encode(test).is_err(), "failed with [{}]", test);
+        }
+    }
+
+    #[test]
+    fn disassemble_test() {
+        let tests = [
+            "add r1, r2, r3",
+            "neg r4, r5",
+            "ld r1, -8(r30)",
+            "la r1, 100",
+            "ori r1, r1, 65535",
+            "ldr r2, 64",
+            "lar r2, 4",
+            "brnv",
+            "br r29",
+            "brmi r31, r3",
+            "brlnv r25",
+            "brl r25, r26",
+            "brlnz r1, r2, r3",
+            "shc r1, r2, 31",
+            "shra r1, r2, r3",
+            "svi r30, r31",
+            "rfi",
+            "stop",
+        ];
+        for test in &tests {
+            let word = instruction(test).unwrap().encode_instruction(&HashMap::new(), 32).unwrap();
+            assert_eq!(disassemble(word, 32).unwrap(), *test);
+        }
+
+        let invalid_tests = [
+            0x38000000,
+            0x40000006,
+            0xffffffff,
+            0x60443001,
+        ];
+        for test in &invalid_tests {
+            let result = disassemble(*test, 0);
+            assert!(result.is_err(), "failed with [{:08x}]", test);
+        }
     }
 
     #[test]
@@ -609,43 +1460,87 @@ mod test {
             (".dw 10", Offset::Relative(320)),
         ];
         for test in &tests {
-            let result = process_directive(test.0).unwrap();
+            let statement = Statement::parse(test.0).unwrap();
+            let result = process_directive(&statement.mnemonic.unwrap().0, &statement.operands).unwrap();
             assert_eq!(result, test.1);
         }
+
+        let invalid_tests = [
+            ".org -4",
+            ".org 0x100000000",
+            ".dw -1",
+            ".dw 0x1000000000000000",
+        ];
+        for test in &invalid_tests {
+            let statement = Statement::parse(test).unwrap();
+            let result = process_directive(&statement.mnemonic.unwrap().0, &statement.operands);
+            assert!(result.is_err(), "failed with [{}]", test);
+        }
     }
 
     #[test]
     fn process_branch_test() {
         let tests = [
-            ("r1", &Opcode::BR, 
+            ("r1", isa::get("br"), 
             Params{ra: None, rb: Some(1), rc: Some(0), c1: None, c2: None, c3: Some(Con::C(1))}),
-            ("", &Opcode::BRNV, 
+            ("", isa::get("brnv"), 
             Params{ra: None, rb: Some(0), rc: Some(0), c1: None, c2: None, c3: Some(Con::C(0))}),
-            ("r1, r2", &Opcode::BRZR, 
+            ("r1, r2", isa::get("brzr"), 
             Params{ra: None, rb: Some(1), rc: Some(2), c1: None, c2: None, c3: Some(Con::C(2))}),
-            ("r1, r2", &Opcode::BRNZ, 
+            ("r1, r2", isa::get("brnz"), 
             Params{ra: None, rb: Some(1), rc: Some(2), c1: None, c2: None, c3: Some(Con::C(3))}),
-            ("r1, r2", &Opcode::BRPL, 
+            ("r1, r2", isa::get("brpl"), 
             Params{ra: None, rb: Some(1), rc: Some(2), c1: None, c2: None, c3: Some(Con::C(4))}),
-            ("r1, r2", &Opcode::BRMI,
+            ("r1, r2", isa::get("brmi"),
             Params{ra: None, rb: Some(1), rc: Some(2), c1: None, c2: None, c3: Some(Con::C(5))}),
         ];
         for test in &tests {
-            let result = process_branch(test.0, test.1).unwrap();
+            let result = process_branch(&ops(test.0), test.1).unwrap();
+            assert_eq!(result, test.2);
+        }
+
+        let invalid_tests = [
+            ("test", isa::get("nop")),
+            ("r1, r31", isa::get("br")),
+            ("r1", isa::get("brnv")),
+            ("a3,r5", isa::get("brnz")),
+            ("r3,, r5", isa::get("brnz")),
+            ("r3,r5,r6", isa::get("brnz")),
+        ];
+        for test in &invalid_tests {
+            let result = operands(test.0).and_then(|x| process_branch(&x, test.1));
+            assert!(result.is_err(), "failed with [{}]", test.0);
+        }
+    }
+
+    #[test]
+    fn process_branch_link_test() {
+        let tests = [
+            ("r25, r1", isa::get("brl"),
+            Params{ra: Some(25), rb: Some(1), rc: Some(0), c1: None, c2: None, c3: Some(Con::C(1))}),
+            ("r25", isa::get("brlnv"),
+            Params{ra: Some(25), rb: Some(0), rc: Some(0), c1: None, c2: None, c3: Some(Con::C(0))}),
+            ("r25, r1, r2", isa::get("brlzr"),
+            Params{ra: Some(25), rb: Some(1), rc: Some(2), c1: None, c2: None, c3: Some(Con::C(2))}),
+            ("r25, r1, r2", isa::get("brlpl"),
+            Params{ra: Some(25), rb: Some(1), rc: Some(2), c1: None, c2: None, c3: Some(Con::C(4))}),
+        ];
+        for test in &tests {
+            let result = process_branch_link(&ops(test.0), test.1).unwrap();
             assert_eq!(result, test.2);
         }
 
         let invalid_tests = [
-            ("test", &Opcode::NOP),
-            ("r1, r31", &Opcode::BR),
-            ("r1", &Opcode::BRNV),
-            ("a3,r5", &Opcode::BRNZ),
-            ("r3,, r5", &Opcode::BRNZ),
-            ("r3,r5,r6", &Opcode::BRNZ),
+            ("r1, r2", isa::get("br")),
+            ("", isa::get("brl")),
+            ("r25", isa::get("brl")),
+            ("r25, r1", isa::get("brlnv")),
+            ("r25, r1", isa::get("brlzr")),
+            ("r25, r1, r2, r3", isa::get("brlnz")),
         ];
         for test in &invalid_tests {
-            let result = process_branch(test.0, test.1);
-            assert!(result.is_err(), format!("failed with [{}]", test.0));
+            let result = operands(test.0).and_then(|x| process_branch_link(&x, test.1));
+            assert!(result.is_err(), "failed with [{}]", test.0);
         }
     }
 
@@ -658,7 +1553,7 @@ mod test {
             Params{ra: None, rb: None, rc: None, c1: None, c2: None, c3: None}),
         ];
         for test in &tests {
-            let result = process_op(test.0).unwrap();
+            let result = process_op(&ops(test.0)).unwrap();
             assert_eq!(result, test.1);
         }
 
@@ -666,7 +1561,7 @@ mod test {
             "test"
         ];
         for test in &invalid_tests {
-            let result = process_op(test);
+            let result = operands(test).and_then(|x| process_op(&x));
             assert!(result.is_err());
         }
     }
@@ -680,7 +1575,7 @@ mod test {
             Params{ra: Some(23), rb: Some(6), rc: Some(11), c1: None, c2: None, c3: None}),
         ];
         for test in &tests {
-            let result = process_op_ra_rb_rc(test.0).unwrap();
+            let result = process_op_ra_rb_rc(&ops(test.0)).unwrap();
             assert_eq!(result, test.1);
         }
 
@@ -691,8 +1586,8 @@ mod test {
             "a3,b3",
         ];
         for test in &invalid_tests {
-            let result = process_op_ra_rb_rc(test);
-            assert!(result.is_err(), format!("failed with [{}]", test));
+            let result = operands(test).and_then(|x| process_op_ra_rb_rc(&x));
+            assert!(result.is_err(), "failed with [{}]", test);
         }
     }
 
@@ -709,7 +1604,7 @@ mod test {
             Params{ra: Some(1), rb: Some(0), rc: None, c1: None, c2: Some(Con::C(5)), c3: None}),
         ];
         for test in &tests {
-            let result = process_op_ra_c2_rb(test.0).unwrap();
+            let result = process_op_ra_c2_rb(&ops(test.0)).unwrap();
             assert_eq!(result, test.1);
         }
 
@@ -720,8 +1615,8 @@ mod test {
             "a3,b3",
         ];
         for test in &invalid_tests {
-            let result = process_op_ra_c2_rb(test);
-            assert!(result.is_err(), format!("failed with [{}]", test));
+            let result = operands(test).and_then(|x| process_op_ra_c2_rb(&x));
+            assert!(result.is_err(), "failed with [{}]", test);
         }
     }
 
@@ -738,7 +1633,7 @@ mod test {
             Params{ra: Some(1), rb: Some(1), rc: None, c1: None, c2: Some(Con::C(16)), c3: None}),
         ];
         for test in &tests {
-            let result = process_op_ra_rb_c2(test.0).unwrap();
+            let result = process_op_ra_rb_c2(&ops(test.0)).unwrap();
             assert_eq!(result, test.1);
         }
 
@@ -749,8 +1644,8 @@ mod test {
             "a3,b3",
         ];
         for test in &invalid_tests {
-            let result = process_op_ra_rb_c2(test);
-            assert!(result.is_err(), format!("failed with [{}]", test));
+            let result = operands(test).and_then(|x| process_op_ra_rb_c2(&x));
+            assert!(result.is_err(), "failed with [{}]", test);
         }
     }
 
@@ -765,7 +1660,7 @@ mod test {
             Params{ra: Some(23), rb: None, rc: None, c1: Some(Con::C(16)), c2: None, c3: None}),
         ];
         for test in &tests {
-            let result = process_op_ra_c1(test.0).unwrap();
+            let result = process_op_ra_c1(&ops(test.0)).unwrap();
             assert_eq!(result, test.1);
         }
 
@@ -776,8 +1671,8 @@ mod test {
             "a3,5",
         ];
         for test in &invalid_tests {
-            let result = process_op_ra_c1(test);
-            assert!(result.is_err(), format!("failed with [{}]", test));
+            let result = operands(test).and_then(|x| process_op_ra_c1(&x));
+            assert!(result.is_err(), "failed with [{}]", test);
         }
     }
 
@@ -792,7 +1687,7 @@ mod test {
             Params{ra: Some(23), rb: None, rc: Some(31), c1: None, c2: None, c3: None}),
         ];
         for test in &tests {
-            let result = process_op_ra_rc(test.0).unwrap();
+            let result = process_op_ra_rc(&ops(test.0)).unwrap();
             assert_eq!(result, test.1);
         }
 
@@ -803,8 +1698,34 @@ mod test {
             "a3,r5",
         ];
         for test in &invalid_tests {
-            let result = process_op_ra_rc(test);
-            assert!(result.is_err(), format!("failed with [{}]", test));
+            let result = operands(test).and_then(|x| process_op_ra_rc(&x));
+            assert!(result.is_err(), "failed with [{}]", test);
+        }
+    }
+
+    #[test]
+    fn process_op_ra_rb_test() {
+        let tests = [
+            ("r1, r3",
+            Params{ra: Some(1), rb: Some(3), rc: None, c1: None, c2: None, c3: None}),
+            ("  r30,r31",
+            Params{ra: Some(30), rb: Some(31), rc: None, c1: None, c2: None, c3: None}),
+        ];
+        for test in &tests {
+            let result = process_op_ra_rb(&ops(test.0)).unwrap();
+            assert_eq!(result, test.1);
+        }
+
+        let invalid_tests = [
+            "",
+            "r1",
+            "r1, r2, r3",
+            "r1, 5",
+            "r32, r1",
+        ];
+        for test in &invalid_tests {
+            let result = operands(test).and_then(|x| process_op_ra_rb(&x));
+            assert!(result.is_err(), "failed with [{}]", test);
         }
     }
 
@@ -819,21 +1740,89 @@ mod test {
             Params{ra: Some(1), rb: Some(2), rc: Some(3), c1: None, c2: None, c3: Some(Con::C(0))}),
             ("r1,r2,3 ",
             Params{ra: Some(1), rb: Some(2), rc: Some(0), c1: None, c2: None, c3: Some(Con::C(3))}),
+            ("r1, r2, SHIFT",
+            Params{ra: Some(1), rb: Some(2), rc: Some(0), c1: None, c2: None, c3: Some(Con::S(String::from("SHIFT")))}),
         ];
         for test in &tests {
-            let result = process_op_ra_rb_rc_c3(test.0).unwrap();
+            let result = process_op_ra_rb_rc_c3(&ops(test.0)).unwrap();
             assert_eq!(result, test.1);
         }
 
         let invalid_tests = [
             "test",
-            "r1, r31,a23",
+            "r1, r31, 32",
+            "r1, r31, -1",
             "r2,r3,,,r3",
             "a3,r5,5",
         ];
         for test in &invalid_tests {
-            let result = process_op_ra_rb_rc_c3(test);
-            assert!(result.is_err(), format!("failed with [{}]", test));
+            let result = operands(test).and_then(|x| process_op_ra_rb_rc_c3(&x));
+            assert!(result.is_err(), "failed with [{}]", test);
+        }
+
+        let mut symbol_map = HashMap::new();
+        symbol_map.insert(String::from("SHIFT"), 4);
+        let (insts, _) = instructions("shl r1, r2, SHIFT").unwrap();
+        assert_eq!(insts[0].encode_instruction(&symbol_map, 0).unwrap(), 0xe0440004);
+        symbol_map.insert(String::from("SHIFT"), 32);
+        assert!(insts[0].encode_instruction(&symbol_map, 0).is_err());
+        assert!(instructions("shl r1, r2, a23").unwrap().0[0].encode_instruction(&symbol_map, 0).is_err());
+    }
+
+    #[test]
+    fn resolve_operands_test() {
+        let mut context = Context::default();
+        context.aliases.insert("RXFLAG".to_string(), 1);
+        context.define_label("MAIN").unwrap();
+        context.define_label("2").unwrap();
+        let tests = [
+            ("jz RXFLAG, .loop", "jz r1, MAIN.loop"),
+            ("la r1, 2b + 0x1f", "la r1, .2.1 + 0x1f"),
+            ("jmp 2f", "jmp .2.2"),
+            ("jmp . + 3f", "jmp . + .3.1"),
+            ("add RXFLAG, sp, zero", "add r1, r29, r0"),
+            ("st lr, 4(sp)", "st r25, 4(r29)"),
+            ("la r1, sp1 + 's' + 0x1f", "la r1, sp1 + 's' + 0x1f"),
+            ("li fp, ';'", "li r30, ';'"),
+            ("stop", "stop"),
+        ];
+        let kinds = |ops: &[Vec<Token>]| -> Vec<Vec<Kind>> {
+            ops.iter().map(|x| x.iter().map(|t| t.kind.clone()).collect()).collect()
+        };
+        for test in &tests {
+            let mut result = Statement::parse(test.0).unwrap();
+            resolve_operands(&mut result.operands, &context).unwrap();
+            let expected = Statement::parse(test.1).unwrap();
+            assert_eq!(result.mnemonic, expected.mnemonic, "failed with [{}]", test.0);
+            assert_eq!(kinds(&result.operands), kinds(&expected.operands), "failed with [{}]", test.0);
+        }
+
+        let result = InstLine::new("LOOP: brzr lr, RXFLAG", &mut context).unwrap().unwrap();
+        assert_eq!(result.encode_instructions(&HashMap::new(), 0).unwrap(), vec![0x40321002]);
+    }
+
+    #[test]
+    fn process_reg_test() {
+        let tests = [
+            ("RXFLAG .reg r1", Some(("RXFLAG".to_string(), 1))),
+            ("  count .reg R20 ; loop counter", Some(("count".to_string(), 20))),
+            ("add r1, r2, r3", None),
+            ("LOOP: nop", None),
+        ];
+        for test in &tests {
+            assert_eq!(process_reg(test.0).unwrap(), test.1);
+        }
+
+        let invalid_tests = [
+            "r2 .reg r1",
+            "1abc .reg r1",
+            "NAME .reg",
+            "NAME .reg 5",
+            "NAME .reg r1, r2",
+        ];
+        for test in &invalid_tests {
+            let result = process_reg(test);
+            assert!(result.is_err(), "failed with [{}]", test);
         }
     }
 
@@ -844,6 +1833,7 @@ mod test {
             let result = register_string_parse(&input).unwrap();
             assert_eq!(i, result);
         }
+        assert_eq!(register_string_parse("R17").unwrap(), 17);
 
         let invalid_tests = [
             "a23",
@@ -853,10 +1843,11 @@ mod test {
             "r 23",
             "r32",
             "r-2",
+            "é1",
         ];
         for test in &invalid_tests {
             let result = register_string_parse(test);
-            assert!(result.is_err(), format!("failed with [{}]", test));
+            assert!(result.is_err(), "failed with [{}]", test);
         }
     }
 }
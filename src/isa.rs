@@ -0,0 +1,134 @@
+/// How an instruction's operands are written, and so which fields of the word they fill.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// `stop`
+    Op,
+    /// `add ra, rb, rc`
+    RaRbRc,
+    /// `ld ra, c2(rb)`, or `ld ra, c2` with rb left as r0
+    RaC2Rb,
+    /// `addi ra, rb, c2`
+    RaRbC2,
+    /// `ldr ra, c1`, where c1 is stored relative to the next instruction
+    RaC1,
+    /// `neg ra, rc`
+    RaRc,
+    /// `svi ra, rb`
+    RaRb,
+    /// `brzr rb, rc`; the operands present depend on the condition, see `Def::cond`
+    Branch,
+    /// `brlzr ra, rb, rc`: a branch with ra receiving the return address first
+    BranchLink,
+    /// `shl ra, rb, rc` or `shl ra, rb, count` with the count in c3
+    Shift,
+}
+
+/// Branch conditions held in c3.
+pub const NEVER: usize = 0;
+pub const ALWAYS: usize = 1;
+
+/// One instruction of the instruction set.
+#[derive(Debug, PartialEq)]
+pub struct Def {
+    pub mnemonic: &'static str,
+    pub op: usize,
+    pub format: Format,
+    /// The condition of a branch, which also tells apart the mnemonics sharing its `op`.
+    /// `NEVER` takes no branch operands and `ALWAYS` only the target rb.
+    pub cond: Option<usize>,
+}
+
+const fn def(mnemonic: &'static str, op: usize, format: Format) -> Def {
+    Def { mnemonic, op, format, cond: None }
+}
+
+const fn branch(mnemonic: &'static str, op: usize, format: Format, cond: usize) -> Def {
+    Def { mnemonic, op, format, cond: Some(cond) }
+}
+
+/// The SRC instruction set, with the exception and interrupt instructions of the extended SRC.
+pub const INSTRUCTIONS: &[Def] = &[
+    def("nop", 0, Format::Op),
+    def("ld", 1, Format::RaC2Rb),
+    def("ldr", 2, Format::RaC1),
+    def("st", 3, Format::RaC2Rb),
+    def("str", 4, Format::RaC1),
+    def("la", 5, Format::RaC2Rb),
+    def("lar", 6, Format::RaC1),
+    branch("brnv", 8, Format::Branch, NEVER),
+    branch("br", 8, Format::Branch, ALWAYS),
+    branch("brzr", 8, Format::Branch, 2),
+    branch("brnz", 8, Format::Branch, 3),
+    branch("brpl", 8, Format::Branch, 4),
+    branch("brmi", 8, Format::Branch, 5),
+    branch("brlnv", 9, Format::BranchLink, NEVER),
+    branch("brl", 9, Format::BranchLink, ALWAYS),
+    branch("brlzr", 9, Format::BranchLink, 2),
+    branch("brlnz", 9, Format::BranchLink, 3),
+    branch("brlpl", 9, Format::BranchLink, 4),
+    branch("brlmi", 9, Format::BranchLink, 5),
+    def("een", 10, Format::Op),
+    def("edi", 11, Format::Op),
+    def("add", 12, Format::RaRbRc),
+    def("addi", 13, Format::RaRbC2),
+    def("sub", 14, Format::RaRbRc),
+    def("neg", 15, Format::RaRc),
+    def("svi", 16, Format::RaRb),
+    def("ri", 17, Format::RaRb),
+    def("and", 20, Format::RaRbRc),
+    def("andi", 21, Format::RaRbC2),
+    def("or", 22, Format::RaRbRc),
+    def("ori", 23, Format::RaRbC2),
+    def("not", 24, Format::RaRc),
+    def("shr", 26, Format::Shift),
+    def("shra", 27, Format::Shift),
+    def("shl", 28, Format::Shift),
+    def("shc", 29, Format::Shift),
+    def("rfi", 30, Format::Op),
+    def("stop", 31, Format::Op),
+];
+
+/// The instruction written as `mnemonic`, in any case.
+pub fn find(mnemonic: &str) -> Option<&'static Def> {
+    INSTRUCTIONS.iter().find(|x| x.mnemonic.eq_ignore_ascii_case(mnemonic))
+}
+
+/// The instruction encoded with `op`, with branches told apart by their condition `c3`.
+pub fn decode(op: usize, c3: usize) -> Option<&'static Def> {
+    INSTRUCTIONS.iter().find(|x| x.op == op && x.cond.is_none_or(|cond| cond == c3))
+}
+
+/// Like `find`, for the mnemonics the assembler itself emits.
+pub fn get(mnemonic: &str) -> &'static Def {
+    match find(mnemonic) {
+        Some(x) => x,
+        None => panic!("\"{}\" is missing from the instruction table", mnemonic),
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn instructions_test() {
+        for (i, a) in INSTRUCTIONS.iter().enumerate() {
+            assert!(a.op < 32, "failed with [{}]", a.mnemonic);
+            assert_eq!(a.cond.is_some(), a.format == Format::Branch || a.format == Format::BranchLink,
+                "failed with [{}]", a.mnemonic);
+            for b in &INSTRUCTIONS[(i+1)..] {
+                assert_ne!(a.mnemonic, b.mnemonic);
+                assert!(a.op != b.op || (a.cond.is_some() && a.cond != b.cond),
+                    "{} and {} encode the same", a.mnemonic, b.mnemonic);
+            }
+        }
+
+        assert_eq!(find("BRLZR"), Some(&INSTRUCTIONS[15]));
+        assert_eq!(find("mov"), None);
+        assert_eq!(decode(9, 2).unwrap().mnemonic, "brlzr");
+        assert_eq!(decode(12, 7).unwrap().mnemonic, "add");
+        assert_eq!(decode(8, 6), None);
+        assert_eq!(decode(7, 0), None);
+    }
+}
@@ -0,0 +1,239 @@
+//! The command line of `orange-ld`, which links objects written by `orange_assembler -c`.
+
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use crate::archive::{self, Archive};
+use crate::checksum::{Kind, Request};
+use crate::link::{Linker, Script};
+use crate::map::MapFormat;
+use crate::object::Object;
+use crate::output::OutputFormat;
+use crate::{is_stdio, parse_format, read_input, write_output, Args};
+
+pub const USAGE: &str = "\
+Usage: orange-ld [OPTIONS] INPUT...
+
+Links the objects written by orange_assembler -c into one program. An INPUT may also be an
+archive written by orange-ar, from which only the members defining symbols that are still
+undefined are linked.
+
+Options:
+  -o PATH              Write the output to PATH, or standard output when PATH is -
+                       (default: the first INPUT with the format's extension)
+  -T SCRIPT            Place sections as SCRIPT lists them, one NAME ADDRESS per line
+                       (default: each section after the last, starting at 0)
+  -f, --format FORMAT  Output format: text-compat (default), text, bin, ihex, srec or
+                       elf
+  --entry ADDRESS      Start address, as a number or global symbol, in place of the .entry
+                       of an object; it must be code in .text
+  --crc32 AT=START,END Store at AT the CRC-32 of the words from START up to END once every
+                       word is linked
+  --checksum AT=START,END
+                       Store at AT the sum of the words from START up to END instead
+  --map[=FORMAT]       Write the symbol map next to the output: text (.map, default),
+                       json (.json) or csv (.csv)
+  -h, --help           Print this help
+  -V, --version        Print the version
+";
+
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Link,
+    Help,
+    Version,
+}
+
+#[derive(Debug)]
+pub struct Config {
+    pub action: Action,
+    /// Objects and archives; `-` reads standard input.
+    pub object_paths: Vec<PathBuf>,
+    /// `-` writes standard output.
+    pub output_path: PathBuf,
+    pub script_path: Option<PathBuf>,
+    pub format: OutputFormat,
+    pub entry: Option<String>,
+    /// From `--crc32` and `--checksum`, in order.
+    pub checksums: Vec<Request>,
+    pub write_map: Option<MapFormat>,
+}
+
+impl Config {
+    pub fn new(args: &[String]) -> Result<Config, Box<dyn Error>> {
+        let mut object_paths = Vec::new();
+        let mut output_path = None;
+        let mut script_path = None;
+        let mut format = OutputFormat::default();
+        let mut entry = None;
+        let mut checksums = Vec::new();
+        let mut write_map = None;
+
+        let mut args = Args::new(args);
+        while let Some(option) = args.next_option() {
+            match option {
+                "-h" | "--help" => return Ok(Config::help(Action::Help)),
+                "-V" | "--version" => return Ok(Config::help(Action::Version)),
+                "-o" => output_path = Some(PathBuf::from(args.value(option)?)),
+                "-T" => script_path = Some(PathBuf::from(args.value(option)?)),
+                "-f" | "--format" => format = parse_format(&args.value(option)?)?,
+                "--entry" => entry = Some(args.value(option)?),
+                "--crc32" => checksums.push(Request::parse(Kind::Crc32, &args.value(option)?)?),
+                "--checksum" => checksums.push(Request::parse(Kind::Sum, &args.value(option)?)?),
+                "--map" => write_map = Some(args.map_format()?),
+                "-" => object_paths.push(PathBuf::from(option)),
+                x if x.starts_with("-o") => output_path = Some(PathBuf::from(&x[2..])),
+                x if x.starts_with("-T") => script_path = Some(PathBuf::from(&x[2..])),
+                x if x.starts_with("-f") && !x.starts_with("--") => format = parse_format(&x[2..])?,
+                x if x.starts_with('-') => bail!(format!("unknown option \"{}\"", args.arg())),
+                x => object_paths.push(PathBuf::from(x)),
+            }
+        }
+
+        if object_paths.is_empty() {
+            bail!("no objects given");
+        }
+        if object_paths.iter().filter(|x| is_stdio(x)).count() > 1 {
+            bail!("standard input can only be read once");
+        }
+        let output_path = match output_path {
+            Some(x) => x,
+            None if is_stdio(&object_paths[0]) => PathBuf::from("-"),
+            None => object_paths[0].with_extension(format.extension()),
+        };
+        if write_map.is_some() && is_stdio(&output_path) {
+            bail!("cannot name the map file when writing standard output; use -o");
+        }
+
+        Ok(Config {
+            action: Action::Link,
+            object_paths,
+            output_path,
+            script_path,
+            format,
+            entry,
+            checksums,
+            write_map,
+        })
+    }
+
+    fn help(action: Action) -> Config {
+        Config {
+            action,
+            object_paths: Vec::new(),
+            output_path: PathBuf::new(),
+            script_path: None,
+            format: OutputFormat::default(),
+            entry: None,
+            checksums: Vec::new(),
+            write_map: None,
+        }
+    }
+}
+
+pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
+    match config.action {
+        Action::Help => {
+            print!("{}", USAGE);
+            return Ok(());
+        },
+        Action::Version => {
+            println!("orange-ld {}", env!("CARGO_PKG_VERSION"));
+            return Ok(());
+        },
+        Action::Link => (),
+    }
+
+    let mut linker = Linker::new();
+    for path in &config.object_paths {
+        let text = read_input(path)?;
+        let name = match is_stdio(path) {
+            true => Path::new("<stdin>"),
+            false => path.as_path(),
+        };
+        if text.lines().next().map(str::trim) == Some(archive::MAGIC) {
+            match Archive::read(&text) {
+                Ok(x) => linker.archive(name, x),
+                Err(e) => bail!(format!("Could not read archive \"{}\": {}", path.display(), e)),
+            };
+            continue;
+        }
+        match Object::read(&text) {
+            Ok(x) => linker.object(name, x),
+            Err(e) => bail!(format!("Could not read object \"{}\": {}", path.display(), e)),
+        };
+    }
+    if let Some(path) = &config.script_path {
+        match Script::parse(&read_input(path)?) {
+            Ok(x) => linker.script(x),
+            Err(e) => bail!(format!("{}: {}", path.display(), e)),
+        };
+    }
+    if let Some(entry) = &config.entry {
+        linker.entry(entry);
+    }
+    for request in &config.checksums {
+        linker.checksum(request.clone());
+    }
+    let linked = linker.link()?;
+
+    write_output(&config.output_path, &linked.output(config.format))?;
+    if let Some(format) = config.write_map {
+        write_output(&config.output_path.with_extension(format.extension()), linked.map(format).as_bytes())?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn config(args: &str) -> Result<Config, Box<dyn Error>> {
+        let args: Vec<String> = args.split_whitespace().map(String::from).collect();
+        Config::new(&args)
+    }
+
+    #[test]
+    fn config_test() {
+        let c = config("orange-ld main.o uart.o").unwrap();
+        assert_eq!(c.action, Action::Link);
+        assert_eq!(c.object_paths, vec![PathBuf::from("main.o"), PathBuf::from("uart.o")]);
+        assert_eq!(c.output_path, PathBuf::from("main.bin"));
+        assert_eq!(c.script_path, None);
+        assert_eq!(c.write_map, None);
+
+        let c = config("orange-ld -T board.ld -f bin --entry START --map=csv -o out/prog.img main.o").unwrap();
+        assert_eq!(c.script_path, Some(PathBuf::from("board.ld")));
+        assert_eq!(c.format, OutputFormat::Binary);
+        assert_eq!(c.entry.as_deref(), Some("START"));
+        assert_eq!(c.write_map, Some(MapFormat::Csv));
+        assert_eq!(c.output_path, PathBuf::from("out/prog.img"));
+
+        let c = config("orange-ld --checksum SUM=0,END main.o").unwrap();
+        assert_eq!(c.checksums, vec![Request::parse(Kind::Sum, "SUM=0,END").unwrap()]);
+
+        let c = config("orange-ld -Tboard.ld -fihex main.o").unwrap();
+        assert_eq!(c.script_path, Some(PathBuf::from("board.ld")));
+        assert_eq!(c.output_path, PathBuf::from("main.hex"));
+
+        assert_eq!(config("orange-ld - uart.o").unwrap().output_path, PathBuf::from("-"));
+        assert_eq!(config("orange-ld --help main.o").unwrap().action, Action::Help);
+
+        let invalid_tests = [
+            "orange-ld",
+            "orange-ld -T",
+            "orange-ld main.o --entry",
+            "orange-ld --crc32 0,4 main.o",
+            "orange-ld -f coff main.o",
+            "orange-ld --map=xml main.o",
+            "orange-ld --map -o - main.o",
+            "orange-ld - -",
+            "orange-ld -x main.o",
+        ];
+        for test in &invalid_tests {
+            assert!(config(test).is_err(), "failed with [{}]", test);
+        }
+    }
+}
@@ -0,0 +1,340 @@
+use std::error::Error;
+use std::ops::Range;
+
+/// The kind and value of a token.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Kind {
+    /// A number or character literal.
+    Num(i64),
+    /// A mnemonic, directive, register or symbol; may contain dots, as in `.org` or `MAIN.loop`,
+    /// and includes the numeric label references `1b` and `1f`.
+    Ident(String),
+    /// A quoted string, without its quotes.
+    Str(String),
+    Op(&'static str),
+}
+
+/// A token and the byte range of the line it was read from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: Kind,
+    pub span: Range<usize>,
+}
+
+/// The operators of expressions, and the separators of statements.
+const OPERATORS: [&str; 24] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>",
+    "|", "^", "&", "<", ">", "+", "-", "*", "/", "%", "~", "!", "(", ")", ",", ":",
+];
+
+/// One line split into its parts: `label: mnemonic operand, operand ; comment`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Statement {
+    pub label: Option<(String, Range<usize>)>,
+    /// The mnemonic of an instruction, or a directive with its leading dot.
+    pub mnemonic: Option<(String, Range<usize>)>,
+    /// The tokens of each comma-separated operand.
+    pub operands: Vec<Vec<Token>>,
+    pub comment: Option<String>,
+}
+
+impl Statement {
+    pub fn parse(text: &str) -> Result<Statement, Box<dyn Error>> {
+        let code = strip_comment(text);
+        let comment = match text.get(code.len()..) {
+            Some(x) if !x.is_empty() => Some(x[1..].trim().to_string()),
+            _ => None,
+        };
+        let tokens = tokenize(code)?;
+        let mut tokens = tokens.as_slice();
+
+        let mut label = None;
+        if let [first, Token { kind: Kind::Op(":"), .. }, rest @ ..] = tokens {
+            let name = &code[first.span.clone()];
+            match first.kind {
+                Kind::Ident(_) => (),
+                Kind::Num(_) if name.chars().all(|c| c.is_ascii_digit()) => (),
+                _ => bail!(format!("Invalid label \"{}\"", name)),
+            }
+            label = Some((name.to_string(), first.span.clone()));
+            tokens = rest;
+        }
+
+        let (mnemonic, rest) = match tokens.split_first() {
+            Some((Token { kind: Kind::Ident(x), span }, rest)) => (Some((x.clone(), span.clone())), rest),
+            Some((x, _)) => bail!(format!("Expected an instruction, found \"{}\"", &code[x.span.clone()])),
+            None => (None, tokens),
+        };
+
+        Ok(Statement {
+            label,
+            mnemonic,
+            operands: split_operands(rest)?,
+            comment,
+        })
+    }
+}
+
+/// Splits `tokens` at each comma, rejecting empty operands and stray colons.
+pub fn split_operands(tokens: &[Token]) -> Result<Vec<Vec<Token>>, Box<dyn Error>> {
+    let mut operands = Vec::new();
+    if tokens.is_empty() {
+        return Ok(operands);
+    }
+    for operand in tokens.split(|t| t.kind == Kind::Op(",")) {
+        if operand.is_empty() {
+            bail!("Empty operand");
+        }
+        if operand.iter().any(|t| t.kind == Kind::Op(":")) {
+            bail!("Unexpected ':' in operand");
+        }
+        operands.push(operand.to_vec());
+    }
+    Ok(operands)
+}
+
+/// Tokenizes `text` up to any comment.
+pub fn tokenize(text: &str) -> Result<Vec<Token>, Box<dyn Error>> {
+    let text = strip_comment(text);
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while let Some(ch) = text[pos..].chars().next() {
+        let rest = &text[pos..];
+        if ch.is_whitespace() {
+            pos += ch.len_utf8();
+            continue;
+        }
+        let (kind, len) = if ch.is_ascii_digit() {
+            let len = rest.find(|c: char| !c.is_alphanumeric()).unwrap_or(rest.len());
+            match parse_number(&rest[..len]) {
+                Some(x) => (Kind::Num(x), len),
+                None if is_numeric_reference(&rest[..len]) => (Kind::Ident(rest[..len].to_string()), len),
+                None => bail!(format!("Could not parse number \"{}\"", &rest[..len])),
+            }
+        } else if ch == '\'' {
+            match parse_char(rest) {
+                Some((x, len)) => (Kind::Num(x), len),
+                None => bail!("Could not parse character literal"),
+            }
+        } else if ch == '"' {
+            match rest[1..].find('"') {
+                Some(x) => (Kind::Str(rest[1..(x+1)].to_string()), x + 2),
+                None => bail!("Unterminated string"),
+            }
+        } else if ch == '.' && !rest[1..].starts_with(|c: char| c.is_alphanumeric() || c == '_') {
+            (Kind::Ident(String::from(".")), 1)
+        } else if ch.is_alphabetic() || ch == '_' || ch == '.' {
+            let len = rest[ch.len_utf8()..].find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.'))
+                .map_or(rest.len(), |x| x + ch.len_utf8());
+            (Kind::Ident(rest[..len].to_string()), len)
+        } else {
+            match OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+                Some(op) => (Kind::Op(op), op.len()),
+                None => bail!(format!("Unexpected character '{}'", ch)),
+            }
+        };
+        tokens.push(Token { kind, span: pos..(pos + len) });
+        pos += len;
+    }
+    Ok(tokens)
+}
+
+/// `text` up to the `;` starting its comment, skipping over character and string literals.
+pub fn strip_comment(text: &str) -> &str {
+    match find_unquoted(text, ';') {
+        Some(x) => &text[..x],
+        None => text,
+    }
+}
+
+/// The first `target` in `text` outside a character or string literal.
+pub fn find_unquoted(text: &str, target: char) -> Option<usize> {
+    let mut pos = 0;
+    while let Some(ch) = text[pos..].chars().next() {
+        let len = match ch {
+            _ if ch == target => return Some(pos),
+            '\'' => parse_char(&text[pos..]).map_or(1, |x| x.1),
+            '"' => text[(pos+1)..].find('"').map_or(1, |x| x + 2),
+            _ => ch.len_utf8(),
+        };
+        pos += len;
+    }
+    None
+}
+
+/// Splits off up to `count` whitespace-separated fields from the start of `text`, returning
+/// them and the trimmed rest of the line, which may hold spaces of its own.
+pub fn split_fields(text: &str, count: usize) -> (Vec<&str>, &str) {
+    let mut fields = Vec::new();
+    let mut rest = text.trim();
+    while fields.len() < count && !rest.is_empty() {
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        fields.push(&rest[..end]);
+        rest = rest[end..].trim_start();
+    }
+    (fields, rest)
+}
+
+/// Parses a decimal, `0x` hexadecimal, `0b` binary or `'c'` character literal.
+pub fn parse_number(text: &str) -> Option<i64> {
+    let text = text.trim();
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = text.strip_prefix("0b").or_else(|| text.strip_prefix("0B")) {
+        i64::from_str_radix(bin, 2).ok()
+    } else if text.starts_with('\'') {
+        match parse_char(text) {
+            Some((x, len)) if len == text.len() => Some(x),
+            _ => None,
+        }
+    } else if text.starts_with(|c: char| c.is_ascii_digit()) {
+        text.parse::<i64>().ok()
+    } else {
+        None
+    }
+}
+
+/// Parses a character literal at the start of `text`, returning its value and length.
+fn parse_char(text: &str) -> Option<(i64, usize)> {
+    let mut chars = text.char_indices().skip(1);
+    let value = match chars.next()? {
+        (_, '\\') => match chars.next()?.1 {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            c => c,
+        },
+        (_, c) => c,
+    };
+    match chars.next()? {
+        (i, '\'') => Some((value as i64, i + 1)),
+        _ => None,
+    }
+}
+
+/// `1b` or `1f`, a reference to the nearest numeric label `1` before or after.
+fn is_numeric_reference(text: &str) -> bool {
+    match text.strip_suffix('b').or_else(|| text.strip_suffix('f')) {
+        Some(x) => !x.is_empty() && x.chars().all(|c| c.is_ascii_digit()),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn kinds(text: &str) -> Vec<Kind> {
+        tokenize(text).unwrap().into_iter().map(|t| t.kind).collect()
+    }
+
+    fn ident(s: &str) -> Kind {
+        Kind::Ident(s.to_string())
+    }
+
+    #[test]
+    fn parse_number_test() {
+        let tests = [
+            ("42", Some(42)),
+            ("0x1F", Some(31)),
+            ("0XffffFFE8", Some(0xFFFF_FFE8)),
+            ("0b101", Some(5)),
+            ("'A'", Some(65)),
+            ("'\\n'", Some(10)),
+            ("';'", Some(59)),
+            ("12a", None),
+            ("0x", None),
+            ("abc", None),
+            ("'ab'", None),
+        ];
+        for test in &tests {
+            assert_eq!(parse_number(test.0), test.1, "failed with [{}]", test.0);
+        }
+    }
+
+    #[test]
+    fn tokenize_test() {
+        assert_eq!(kinds("ld r1, -4(r2)"), vec![
+            ident("ld"), ident("r1"), Kind::Op(","), Kind::Op("-"), Kind::Num(4),
+            Kind::Op("("), ident("r2"), Kind::Op(")"),
+        ]);
+        assert_eq!(kinds("\tla\tr1,';'\t; ;comment"), vec![ident("la"), ident("r1"), Kind::Op(","), Kind::Num(59)]);
+        assert_eq!(kinds("MAIN.loop: brzr 1f, . + 8"), vec![
+            ident("MAIN.loop"), Kind::Op(":"), ident("brzr"), ident("1f"), Kind::Op(","),
+            ident("."), Kind::Op("+"), Kind::Num(8),
+        ]);
+        assert_eq!(kinds(".include \"a;b.asm\""), vec![ident(".include"), Kind::Str(String::from("a;b.asm"))]);
+        assert_eq!(tokenize("  add r1,r2").unwrap()[3].span, 9..11);
+        assert_eq!(kinds("éé: add é1"), vec![ident("éé"), Kind::Op(":"), ident("add"), ident("é1")]);
+
+        let invalid_tests = [
+            "la r1, 12a",
+            "la r1, 'ab'",
+            "la r1, $4",
+            ".include \"a.asm",
+        ];
+        for test in &invalid_tests {
+            assert!(tokenize(test).is_err(), "failed with [{}]", test);
+        }
+    }
+
+    #[test]
+    fn strip_comment_test() {
+        let tests = [
+            ("add r1, r2, r3 ; add", "add r1, r2, r3 "),
+            ("la r1, ';' ; semicolon", "la r1, ';' "),
+            ("la r1, '\\'' ; quote", "la r1, '\\'' "),
+            (".include \"a;b\"", ".include \"a;b\""),
+            ("; 1.5 volts", ""),
+        ];
+        for test in &tests {
+            assert_eq!(strip_comment(test.0), test.1, "failed with [{}]", test.0);
+        }
+    }
+
+    #[test]
+    fn split_fields_test() {
+        assert_eq!(split_fields(" a  b c d", 2), (vec!["a", "b"], "c d"));
+        assert_eq!(split_fields("a b", 3), (vec!["a", "b"], ""));
+        assert_eq!(split_fields("", 1), (vec![], ""));
+    }
+
+    #[test]
+    fn statement_parse_test() {
+        let tests = [
+            ("\tbrzr r31, r1 ; Loop", None, Some("brzr"), 2, Some("Loop")),
+            ("LOOP:\tld\tr1,\t4(r2)", Some("LOOP"), Some("ld"), 2, None),
+            ("  MAIN.x :  stop", Some("MAIN.x"), Some("stop"), 0, None),
+            ("1: la r1, ':' ; 1.5 volts: ok", Some("1"), Some("la"), 2, Some("1.5 volts: ok")),
+            ("END:", Some("END"), None, 0, None),
+            ("\t\t; only a comment", None, None, 0, Some("only a comment")),
+            ("", None, None, 0, None),
+            (".org 0x100", None, Some(".org"), 1, None),
+            ("shl r1,r2,r3", None, Some("shl"), 3, None),
+        ];
+        for test in &tests {
+            let result = Statement::parse(test.0).unwrap();
+            assert_eq!(result.label.as_ref().map(|x| x.0.as_str()), test.1, "failed with [{}]", test.0);
+            assert_eq!(result.mnemonic.as_ref().map(|x| x.0.as_str()), test.2, "failed with [{}]", test.0);
+            assert_eq!(result.operands.len(), test.3, "failed with [{}]", test.0);
+            assert_eq!(result.comment.as_deref(), test.4, "failed with [{}]", test.0);
+        }
+
+        let invalid_tests = [
+            "add r1,, r2",
+            "add r1, r2,",
+            ", add",
+            "A: B: stop",
+            "la r1, a:b",
+            "(x): stop",
+            "0x10: stop",
+        ];
+        assert_eq!(Statement::parse("  L: add r1").unwrap().mnemonic, Some((String::from("add"), 5..8)));
+
+        for test in &invalid_tests {
+            assert!(Statement::parse(test).is_err(), "failed with [{}]", test);
+        }
+    }
+}
@@ -1,42 +1,524 @@
 use std::error::Error;
 use std::fs;
-use std::path::PathBuf;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use checksum::{Kind, Request};
 extern crate strum;
 #[macro_use]
 extern crate strum_macros;
 #[macro_use]
 extern crate simple_error;
 
+pub mod ar;
+pub mod archive;
+mod assembler;
+pub mod ast;
+pub mod checksum;
+mod cond;
+pub mod disasm;
+pub mod elf;
+mod expr;
 mod inst;
+mod isa;
+pub mod ld;
+mod lexer;
+pub mod link;
+mod macros;
+pub mod map;
+pub mod object;
+pub mod output;
 pub mod prog;
+pub mod source;
+pub mod text;
 
+pub use assembler::{Assembler, Assembly, ObjectAssembly};
+pub use map::MapFormat;
+pub use output::OutputFormat;
+pub use source::SourceRef;
+
+pub const USAGE: &str = "\
+Usage: orange_assembler [OPTIONS] SOURCE
+
+Assembles SOURCE, or standard input when SOURCE is -.
+
+Options:
+  -o PATH              Write the output to PATH, or standard output when PATH is -
+                       (default: SOURCE with the format's extension)
+  -f, --format FORMAT  Output format: text-compat (default), text, bin, ihex, srec or
+                       elf; text adds a header with the entry, word count and checksum
+  -c                   Assemble to a relocatable object (.o) to link later; symbols
+                       declared .extern may be left undefined
+  -D NAME[=VALUE]      Define NAME before the first line (VALUE defaults to 1)
+  -I DIR               Search DIR for .include files
+  -T SCRIPT            Place sections as SCRIPT lists them, one NAME ADDRESS per line,
+                       as orange-ld does (default: each after the last, .text at 0)
+  -l                   Write a listing next to the output (.lst)
+  --map[=FORMAT]       Write the symbol map next to the output: text (.map, default),
+                       json (.json) or csv (.csv)
+  --symbols PATH       Import the symbols of a map file in any of these formats, such as
+                       the routines of a resident monitor
+  -MD                  Write a make dependency rule next to the output (.d)
+  --entry ADDRESS      Start address, as a number or symbol, in place of any .entry; it
+                       must be code in .text and is recorded by every format but bin
+  --crc32 AT=START,END Store at AT the CRC-32 of the words from START up to END once every
+                       word is known, as .crc32 does
+  --checksum AT=START,END
+                       Store at AT the sum of the words from START up to END instead
+  -g                   Add the source line of each word to ELF output (DWARF line table)
+  --Werror             Treat warnings as errors
+  -d                   Disassemble SOURCE, which holds text output, to standard output
+                       or the -o path instead
+  -h, --help           Print this help
+  -V, --version        Print the version
+";
+
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Assemble,
+    /// Assemble to a relocatable object.
+    Compile,
+    /// Read `source_path` as text output and write it as assembly instead.
+    Disassemble,
+    Help,
+    Version,
+}
+
+#[derive(Debug)]
 pub struct Config {
+    pub action: Action,
+    /// `-` reads standard input.
     pub source_path: PathBuf,
+    /// `-` writes standard output.
     pub output_path: PathBuf,
+    pub format: OutputFormat,
+    pub include_paths: Vec<PathBuf>,
+    pub defines: Vec<(String, i64)>,
+    pub write_dependencies: bool,
+    pub write_listing: bool,
+    pub write_map: Option<MapFormat>,
+    /// Map files to import symbols from.
+    pub symbol_paths: Vec<PathBuf>,
+    pub script_path: Option<PathBuf>,
+    pub warnings_as_errors: bool,
+    pub entry: Option<String>,
+    /// From `--crc32` and `--checksum`, in order.
+    pub checksums: Vec<Request>,
+    pub debug_lines: bool,
 }
 
 impl Config {
     pub fn new(args: &[String]) -> Result<Config, Box<dyn Error>> {
-        if args.len() < 2 {
-            bail!("not enough arguments");
+        let mut action = Action::Assemble;
+        let mut source_path = None;
+        let mut output_path = None;
+        let mut format = OutputFormat::default();
+        let mut include_paths = Vec::new();
+        let mut defines = Vec::new();
+        let mut write_dependencies = false;
+        let mut write_listing = false;
+        let mut write_map = None;
+        let mut symbol_paths = Vec::new();
+        let mut script_path = None;
+        let mut warnings_as_errors = false;
+        let mut entry = None;
+        let mut checksums = Vec::new();
+        let mut debug_lines = false;
+
+        let mut args = Args::new(args);
+        while let Some(option) = args.next_option() {
+            match option {
+                "-h" | "--help" => return Ok(Config::help(Action::Help)),
+                "-V" | "--version" => return Ok(Config::help(Action::Version)),
+                "-o" => output_path = Some(PathBuf::from(args.value(option)?)),
+                "-f" | "--format" => format = parse_format(&args.value(option)?)?,
+                "-I" => include_paths.push(PathBuf::from(args.value(option)?)),
+                "-D" => defines.push(parse_define(&args.value(option)?)?),
+                "-T" => script_path = Some(PathBuf::from(args.value(option)?)),
+                "--entry" => entry = Some(args.value(option)?),
+                "--crc32" => checksums.push(Request::parse(Kind::Crc32, &args.value(option)?)?),
+                "--checksum" => checksums.push(Request::parse(Kind::Sum, &args.value(option)?)?),
+                "-MD" => write_dependencies = true,
+                "-g" => debug_lines = true,
+                "-l" => write_listing = true,
+                "--map" => write_map = Some(args.map_format()?),
+                "--symbols" => symbol_paths.push(PathBuf::from(args.value(option)?)),
+                "--Werror" => warnings_as_errors = true,
+                "-d" => action = Action::Disassemble,
+                "-c" => action = Action::Compile,
+                "-" if source_path.is_none() => source_path = Some(PathBuf::from(option)),
+                x if x.starts_with("-o") => output_path = Some(PathBuf::from(&x[2..])),
+                x if x.starts_with("-f") && !x.starts_with("--") => format = parse_format(&x[2..])?,
+                x if x.starts_with("-I") => include_paths.push(PathBuf::from(&x[2..])),
+                x if x.starts_with("-D") => defines.push(parse_define(&x[2..])?),
+                x if x.starts_with("-T") => script_path = Some(PathBuf::from(&x[2..])),
+                x if x.starts_with('-') => bail!(format!("unknown option \"{}\"", args.arg())),
+                x if source_path.is_none() => source_path = Some(PathBuf::from(x)),
+                x => bail!(format!("unexpected argument \"{}\"", x)),
+            }
+        }
+
+        let source_path = match source_path {
+            Some(x) => x,
+            None => bail!("no source file given"),
+        };
+        if action == Action::Compile && (write_listing || write_map.is_some() || entry.is_some() || script_path.is_some() || !checksums.is_empty()) {
+            bail!("-l, --map, --entry, --crc32, --checksum and -T cannot be used with -c");
         }
+        if debug_lines && (format != OutputFormat::Elf || action != Action::Assemble) {
+            bail!("-g only applies to ELF output (-f elf)");
+        }
+        let output_path = match output_path {
+            Some(x) => x,
+            None if is_stdio(&source_path) || action == Action::Disassemble => PathBuf::from("-"),
+            None if action == Action::Compile => source_path.with_extension("o"),
+            None => source_path.with_extension(format.extension()),
+        };
+
+        Ok(Config {
+            action,
+            source_path,
+            output_path,
+            format,
+            include_paths,
+            defines,
+            write_dependencies,
+            write_listing,
+            write_map,
+            symbol_paths,
+            script_path,
+            warnings_as_errors,
+            entry,
+            checksums,
+            debug_lines,
+        })
+    }
+
+    fn help(action: Action) -> Config {
+        Config {
+            action,
+            source_path: PathBuf::new(),
+            output_path: PathBuf::new(),
+            format: OutputFormat::default(),
+            include_paths: Vec::new(),
+            defines: Vec::new(),
+            write_dependencies: false,
+            write_listing: false,
+            write_map: None,
+            symbol_paths: Vec::new(),
+            script_path: None,
+            warnings_as_errors: false,
+            entry: None,
+            checksums: Vec::new(),
+            debug_lines: false,
+        }
+    }
+
+    /// Where a file written beside the output, such as the listing, goes: next to the output,
+    /// or next to the source when writing standard output.
+    fn side_path(&self, extension: &str) -> Result<PathBuf, Box<dyn Error>> {
+        match (is_stdio(&self.output_path), is_stdio(&self.source_path)) {
+            (false, _) => Ok(self.output_path.with_extension(extension)),
+            (true, false) => Ok(self.source_path.with_extension(extension)),
+            (true, true) => bail!(format!("cannot name the .{} file when reading standard input and writing standard output; use -o", extension)),
+        }
+    }
+}
+
+pub(crate) fn parse_format(format: &str) -> Result<OutputFormat, Box<dyn Error>> {
+    match format.parse::<OutputFormat>() {
+        Ok(x) => Ok(x),
+        Err(_) => bail!(format!("unknown output format \"{}\" (expected text, text-compat, bin, ihex, srec or elf)", format)),
+    }
+}
 
-        let source_path = PathBuf::from(args[1].clone());
-        let output_path = source_path.with_extension("bin");
+pub(crate) fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
 
-        Ok(Config { source_path, output_path })
+pub(crate) fn read_input(path: &Path) -> Result<String, Box<dyn Error>> {
+    let mut text = String::new();
+    let result = match is_stdio(path) {
+        true => io::stdin().read_to_string(&mut text).map(|_| ()),
+        false => fs::read_to_string(path).map(|x| text = x),
+    };
+    match result {
+        Ok(()) => Ok(text),
+        Err(e) => bail!(format!("Could not read \"{}\": {}", path.display(), e)),
     }
 }
 
-pub fn run<'a> (config: Config) -> Result<(), Box<dyn Error>> {
-    let contents = fs::read_to_string(config.source_path)?;
+pub(crate) fn write_output(path: &Path, contents: &[u8]) -> Result<(), Box<dyn Error>> {
+    let result = match is_stdio(path) {
+        true => io::stdout().write_all(contents),
+        false => fs::write(path, contents),
+    };
+    match result {
+        Ok(()) => Ok(()),
+        Err(e) => bail!(format!("Could not write \"{}\": {}", path.display(), e)),
+    }
+}
 
-    let ilines = prog::Prog::new("file", &contents).unwrap(); // TEMP FIX
+/// Parses `NAME=value` or `NAME`, which defines `NAME` as 1.
+pub(crate) fn parse_define(define: &str) -> Result<(String, i64), Box<dyn Error>> {
+    let (name, value) = match define.find('=') {
+        Some(x) => (&define[..x], expr::Expr::parse(&define[(x+1)..])?.eval(&|_| None)?),
+        None => (define, 1),
+    };
+    if !inst::is_symbol(name) {
+        bail!(format!("invalid symbol name \"{}\" in -D", name));
+    }
+    Ok((name.to_string(), value))
+}
 
-    let encoded = ilines.encode().unwrap(); // TEMP FIX
+/// The arguments of a command line after the program name, read an option at a time.
+pub(crate) struct Args<'a> {
+    args: std::slice::Iter<'a, String>,
+    arg: &'a str,
+    /// The value given as `--option=value` with the last option.
+    inline: Option<&'a str>,
+}
 
-    fs::write(config.output_path, encoded)
-        .expect("Unable to write file");
+impl<'a> Args<'a> {
+    pub fn new(args: &'a [String]) -> Args<'a> {
+        Args {
+            args: args.get(1..).unwrap_or_default().iter(),
+            arg: "",
+            inline: None,
+        }
+    }
+
+    /// The next option, or argument, without any value given with it after `=`.
+    pub fn next_option(&mut self) -> Option<&'a str> {
+        let arg = self.args.next()?;
+        let (option, inline) = match arg.find('=') {
+            Some(x) if arg.starts_with("--") => (&arg[..x], Some(&arg[(x+1)..])),
+            _ => (arg.as_str(), None),
+        };
+        self.arg = arg;
+        self.inline = inline;
+        Some(option)
+    }
+
+    /// The whole of the last argument.
+    pub fn arg(&self) -> &'a str {
+        self.arg
+    }
+
+    /// The value of `option`: the one given after `=`, or else the next argument.
+    pub fn value(&mut self, option: &str) -> Result<String, String> {
+        match self.inline.take() {
+            Some(x) => Ok(x.to_string()),
+            None => match self.args.next() {
+                Some(x) => Ok(x.clone()),
+                None => Err(format!("missing value after {}", option)),
+            },
+        }
+    }
+
+    /// The format of `--map`, which is only given after `=` and defaults to text.
+    pub fn map_format(&mut self) -> Result<MapFormat, Box<dyn Error>> {
+        match self.inline.take() {
+            Some(x) => match x.parse::<MapFormat>() {
+                Ok(x) => Ok(x),
+                Err(_) => bail!(format!("unknown map format \"{}\" (expected text, json or csv)", x)),
+            },
+            None => Ok(MapFormat::Text),
+        }
+    }
+}
+
+pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
+    match config.action {
+        Action::Help => {
+            print!("{}", USAGE);
+            return Ok(());
+        },
+        Action::Version => {
+            println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+            return Ok(());
+        },
+        Action::Disassemble => {
+            let encoded = read_input(&config.source_path)?;
+            let text = disasm::disassemble(&encoded)?;
+            return write_output(&config.output_path, text.as_bytes());
+        },
+        Action::Assemble | Action::Compile => (),
+    }
+
+    // Name every file written beside the output before writing any, so that none is written
+    // when one cannot be named.
+    let assembling = config.action == Action::Assemble;
+    let dependency_path = match config.write_dependencies {
+        true => Some(config.side_path("d")?),
+        false => None,
+    };
+    let listing_path = match config.write_listing && assembling {
+        true => Some(config.side_path("lst")?),
+        false => None,
+    };
+    let map_path = match config.write_map.filter(|_| assembling) {
+        Some(format) => Some((format, config.side_path(format.extension())?)),
+        None => None,
+    };
+
+    let mut assembler = Assembler::new();
+    match is_stdio(&config.source_path) {
+        true => assembler.source("<stdin>", &read_input(&config.source_path)?),
+        false => assembler.file(&config.source_path),
+    };
+    for path in &config.include_paths {
+        assembler.include_path(path);
+    }
+    for (name, value) in &config.defines {
+        assembler.define(name, *value);
+    }
+    for path in &config.symbol_paths {
+        let symbols = match map::read(&read_input(path)?) {
+            Ok(x) => x,
+            Err(e) => bail!(format!("Could not read symbols from \"{}\": {}", path.display(), e)),
+        };
+        assembler.import(&symbols);
+    }
+    if let Some(path) = &config.script_path {
+        let script = match link::Script::parse(&read_input(path)?) {
+            Ok(x) => x,
+            Err(e) => bail!(format!("{}: {}", path.display(), e)),
+        };
+        for (name, address) in &script.sections {
+            assembler.section(name, *address);
+        }
+    }
+    if let Some(entry) = &config.entry {
+        assembler.entry(entry);
+    }
+    for request in &config.checksums {
+        assembler.checksum(request.clone());
+    }
+    assembler.format(config.format);
+    assembler.debug_lines(config.debug_lines);
+
+    if config.action == Action::Compile {
+        let assembly = assembler.assemble_object()?;
+        report(&config, &assembly.diagnostics)?;
+        write_output(&config.output_path, assembly.object.write().as_bytes())?;
+        if let Some(path) = &dependency_path {
+            write_output(path, assembly.make_rule(&config.output_path).as_bytes())?;
+        }
+        return Ok(());
+    }
+
+    let assembly = assembler.assemble()?;
+    report(&config, &assembly.diagnostics)?;
+
+    write_output(&config.output_path, &assembly.output())?;
+
+    if let Some(path) = &dependency_path {
+        write_output(path, assembly.make_rule(&config.output_path).as_bytes())?;
+    }
+    if let Some(path) = &listing_path {
+        write_output(path, assembly.listing()?.as_bytes())?;
+    }
+    if let Some((format, path)) = &map_path {
+        write_output(path, assembly.map(*format).as_bytes())?;
+    }
 
     Ok(())
 }
+
+/// Prints each warning, failing when they are to be treated as errors.
+fn report(config: &Config, diagnostics: &[String]) -> Result<(), Box<dyn Error>> {
+    for warning in diagnostics {
+        eprintln!("Warning: {}", warning);
+    }
+    if config.warnings_as_errors && !diagnostics.is_empty() {
+        bail!(format!("{} warning(s) treated as errors (--Werror)", diagnostics.len()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn config(args: &str) -> Result<Config, Box<dyn Error>> {
+        let args: Vec<String> = args.split_whitespace().map(String::from).collect();
+        Config::new(&args)
+    }
+
+    #[test]
+    fn config_test() {
+        let result = config("asm prog.asm").unwrap();
+        assert_eq!(result.action, Action::Assemble);
+        assert_eq!(result.output_path, PathBuf::from("prog.bin"));
+        assert_eq!(result.format, OutputFormat::TextCompat);
+
+        let result = config("asm -f ihex -DBOARD=2 -D DEBUG -I lib -Iinc --map --Werror --entry=START --symbols monitor.map prog.asm").unwrap();
+        assert_eq!(result.output_path, PathBuf::from("prog.hex"));
+        assert_eq!(result.format, OutputFormat::IntelHex);
+        assert_eq!(result.defines, vec![(String::from("BOARD"), 2), (String::from("DEBUG"), 1)]);
+        assert_eq!(result.include_paths, vec![PathBuf::from("lib"), PathBuf::from("inc")]);
+        assert!(result.warnings_as_errors);
+        assert_eq!(result.write_map, Some(MapFormat::Text));
+        assert_eq!(result.symbol_paths, vec![PathBuf::from("monitor.map")]);
+        assert_eq!(config("asm --map=csv prog.asm").unwrap().write_map, Some(MapFormat::Csv));
+        assert_eq!(result.entry.as_deref(), Some("START"));
+        assert_eq!(config("asm -T board.ld prog.asm").unwrap().script_path, Some(PathBuf::from("board.ld")));
+        assert_eq!(config("asm -Tboard.ld prog.asm").unwrap().script_path, Some(PathBuf::from("board.ld")));
+        assert!(config("asm -f elf -g prog.asm").unwrap().debug_lines);
+        let checksums = config("asm --crc32 CRC=0,END --checksum=SUM=0,END prog.asm").unwrap().checksums;
+        assert_eq!(checksums.iter().map(|x| (x.kind, x.address.as_str())).collect::<Vec<_>>(), vec![(Kind::Crc32, "CRC"), (Kind::Sum, "SUM")]);
+
+        let tests = [
+            ("asm --format=srec -o out.s19 prog.asm", "out.s19", OutputFormat::SRecord),
+            ("asm -fbin -oout.img prog.asm", "out.img", OutputFormat::Binary),
+            ("asm -f elf prog.asm", "prog.elf", OutputFormat::Elf),
+            ("asm -f text prog.asm", "prog.bin", OutputFormat::Text),
+            ("asm -", "-", OutputFormat::TextCompat),
+            ("asm -o - prog.asm", "-", OutputFormat::TextCompat),
+            ("asm -d prog.bin", "-", OutputFormat::TextCompat),
+            ("asm -c lib/uart.asm", "lib/uart.o", OutputFormat::TextCompat),
+        ];
+        for test in &tests {
+            let result = config(test.0).unwrap();
+            assert_eq!(result.output_path, PathBuf::from(test.1), "failed with [{}]", test.0);
+            assert_eq!(result.format, test.2, "failed with [{}]", test.0);
+        }
+
+        assert_eq!(config("asm --help prog.asm").unwrap().action, Action::Help);
+        assert_eq!(config("asm -V").unwrap().action, Action::Version);
+
+        let invalid_tests = [
+            "asm",
+            "asm -f coff prog.asm",
+            "asm --map=xml prog.asm",
+            "asm -c -l prog.asm",
+            "asm -c -T board.ld prog.asm",
+            "asm -g prog.asm",
+            "asm -c --crc32 CRC=0,END prog.asm",
+            "asm --crc32 CRC prog.asm",
+            "asm prog.asm --symbols",
+            "asm prog.asm -o",
+            "asm --entry",
+            "asm -x prog.asm",
+            "asm a.asm b.asm",
+            "asm -D 1X prog.asm",
+        ];
+        for test in &invalid_tests {
+            assert!(config(test).is_err(), "failed with [{}]", test);
+        }
+    }
+
+    #[test]
+    fn side_path_test() {
+        assert_eq!(config("asm -o out/prog.hex prog.asm").unwrap().side_path("lst").unwrap(), PathBuf::from("out/prog.lst"));
+        assert_eq!(config("asm -o - prog.asm").unwrap().side_path("map").unwrap(), PathBuf::from("prog.map"));
+        assert!(config("asm -").unwrap().side_path("map").is_err());
+
+        // Reading standard input and writing standard output leaves nothing to name them
+        // after, which fails before any input is read or output written.
+        for test in &["asm --map -", "asm -l -", "asm -MD -", "asm -c -MD -"] {
+            assert!(run(config(test).unwrap()).is_err(), "failed with [{}]", test);
+        }
+    }
+}
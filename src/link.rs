@@ -0,0 +1,532 @@
+//! Links relocatable objects into one program: places their sections by a linker script,
+//! resolves `.global` and `.extern` symbols across objects and fills in relocations.
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use crate::expr::Expr;
+use crate::inst;
+use crate::lexer;
+use crate::map::{self, MapFormat};
+use crate::archive::Archive;
+use crate::checksum::{self, Request};
+use crate::elf;
+use crate::object::{Binding, Object, RelocKind, Relocation};
+use crate::output::{self, OutputFormat};
+use crate::prog::{self, ProgSection, Symbol, SymbolKind, ABSOLUTE_SECTION};
+
+/// Where each section goes, one `NAME ADDRESS` line per section:
+///
+/// ```text
+/// ; Comments start with a semicolon.
+/// .text 4096
+/// .data 0x1000
+/// .vga  0x200000
+/// ```
+///
+/// Sections the script leaves out follow the one placed before them, and the first starts
+/// at 0.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Script {
+    pub sections: Vec<(String, usize)>,
+}
+
+impl Script {
+    pub fn parse(text: &str) -> Result<Script, Box<dyn Error>> {
+        let mut script = Script::default();
+        for (index, line) in text.lines().enumerate() {
+            let line = lexer::strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (fields, rest) = lexer::split_fields(line, 1);
+            let address = match Expr::parse(rest).and_then(|e| e.eval(&|_| None)) {
+                Ok(x) if (0..1 << 32).contains(&x) && x % 4 == 0 => x as usize,
+                Ok(x) => bail!(format!("line {}: Address {:#x} is not a word address", index + 1, x)),
+                Err(e) => bail!(format!("line {}: Expected NAME ADDRESS: {}", index + 1, e)),
+            };
+            if script.sections.iter().any(|x| x.0 == fields[0]) {
+                bail!(format!("line {}: Section {} placed more than once", index + 1, fields[0]));
+            }
+            script.sections.push((fields[0].to_string(), address));
+        }
+        Ok(script)
+    }
+}
+
+/// Links objects held in memory, named by the files they came from in messages and maps.
+#[derive(Default)]
+pub struct Linker {
+    objects: Vec<(PathBuf, Object)>,
+    archives: Vec<(PathBuf, Archive)>,
+    script: Script,
+    entry: Option<String>,
+    checksums: Vec<Request>,
+}
+
+/// A section of one object placed at `address`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Placement {
+    pub file: PathBuf,
+    pub section: String,
+    pub address: usize,
+    pub size: usize,
+}
+
+/// A linked program.
+pub struct Linked {
+    /// Every word, by address.
+    pub words: BTreeMap<usize, u32>,
+    /// Every symbol defined by the objects with its final value.
+    pub symbols: Vec<Symbol>,
+    /// The names of the symbols the objects declare global.
+    pub globals: Vec<String>,
+    /// Where each section of each object went, by address.
+    pub placements: Vec<Placement>,
+    /// The start address, from `Linker::entry` or else the object declaring one.
+    pub entry: Option<usize>,
+}
+
+impl Linker {
+    pub fn new() -> Linker {
+        Linker::default()
+    }
+
+    pub fn object<P: AsRef<Path>>(&mut self, name: P, object: Object) -> &mut Linker {
+        self.objects.push((name.as_ref().to_path_buf(), object));
+        self
+    }
+
+    /// Adds an archive to take the members of that define symbols otherwise undefined.
+    pub fn archive<P: AsRef<Path>>(&mut self, name: P, archive: Archive) -> &mut Linker {
+        self.archives.push((name.as_ref().to_path_buf(), archive));
+        self
+    }
+
+    pub fn script(&mut self, script: Script) -> &mut Linker {
+        self.script = script;
+        self
+    }
+
+    /// Sets the start address to an expression of numbers and global symbols, in place of
+    /// any an object declares with `.entry`.
+    pub fn entry(&mut self, entry: &str) -> &mut Linker {
+        self.entry = Some(entry.to_string());
+        self
+    }
+
+    /// Stores a checksum once every relocation is filled in.
+    pub fn checksum(&mut self, request: Request) -> &mut Linker {
+        self.checksums.push(request);
+        self
+    }
+
+    pub fn link(&self) -> Result<Linked, Box<dyn Error>> {
+        if self.objects.is_empty() {
+            bail!("No objects to link");
+        }
+        let objects = self.select();
+        let placements = self.place(&objects)?;
+        let base = |index: usize, section: &str| placements.iter()
+            .find(|x| x.0 == index && x.1.section == section)
+            .map(|x| x.1.address);
+
+        // Resolve every symbol to its address, collecting the globals by name.
+        let mut globals: HashMap<&str, (usize, &Path)> = HashMap::new();
+        let mut symbols = Vec::new();
+        let mut errors = Vec::new();
+        for (index, (file, object)) in objects.iter().enumerate() {
+            for symbol in object.symbols.iter().filter(|x| x.binding != Binding::Extern) {
+                let (value, kind) = match symbol.section.as_str() {
+                    ABSOLUTE_SECTION => (symbol.value, SymbolKind::Equ),
+                    section => match base(index, section) {
+                        Some(x) => ((x + symbol.value) % (1 << 32), SymbolKind::Label),
+                        None => bail!(format!("{}: Symbol \"{}\" is in unknown section {}", file.display(), symbol.name, section)),
+                    },
+                };
+                if symbol.binding == Binding::Global {
+                    if let Some((_, other)) = globals.insert(&symbol.name, (value, file)) {
+                        errors.push(format!("Symbol \"{}\" defined in both {} and {}", symbol.name, other.display(), file.display()));
+                    }
+                }
+                symbols.push(Symbol {
+                    name: symbol.name.clone(),
+                    value,
+                    kind,
+                    section: symbol.section.clone(),
+                    source: symbol.source.clone(),
+                });
+            }
+        }
+        for (file, object) in &objects {
+            for symbol in object.symbols.iter().filter(|x| x.binding == Binding::Extern) {
+                if !globals.contains_key(symbol.name.as_str()) {
+                    errors.push(format!("{}: Undefined symbol \"{}\"", file.display(), symbol.name));
+                }
+            }
+        }
+        if !errors.is_empty() {
+            bail!(errors.join("\n"));
+        }
+
+        // The address a relocation or entry point of object `index` is relative to.
+        let resolve = |index: usize, symbol: &str| match symbol {
+            ABSOLUTE_SECTION => Some(0),
+            x if x.starts_with('.') => base(index, x),
+            x => globals.get(x).map(|x| x.0),
+        };
+
+        let mut words = BTreeMap::new();
+        for (index, placement) in &placements {
+            let (file, object) = &objects[*index];
+            let section = match object.sections.iter().find(|x| x.name == placement.section) {
+                Some(x) => x,
+                None => continue,
+            };
+            for (offset, word) in &section.words {
+                words.insert(placement.address + offset, *word);
+            }
+            for reloc in &section.relocations {
+                let target = match resolve(*index, &reloc.symbol) {
+                    Some(x) => x as i64 + reloc.addend,
+                    None => bail!(format!("{}: Relocation against unknown symbol \"{}\"", file.display(), reloc.symbol)),
+                };
+                let address = placement.address + reloc.offset;
+                let word = words.entry(address).or_insert(0);
+                *word = match relocate(*word, reloc, target, address) {
+                    Ok(x) => x,
+                    Err(e) => bail!(format!("{}: {} at {:#010x}: {}", file.display(), reloc.kind, address, e)),
+                };
+            }
+        }
+
+        let lookup = |s: &str| globals.get(s).map(|x| x.0 as i64);
+        checksum::apply(&self.checksums, &mut words, &lookup)?;
+        // The object declaring the entry point, if it did not come from `Linker::entry`.
+        let mut declarer = None;
+        let entry = match &self.entry {
+            Some(x) => match Expr::parse(x).and_then(|e| e.eval(&lookup)).and_then(prog::entry_address) {
+                Ok(x) => Some(x),
+                Err(e) => bail!(format!("Invalid entry point \"{}\": {}", x, e)),
+            },
+            None => {
+                let mut declared = objects.iter().enumerate().filter_map(|(i, x)| x.1.entry.as_ref().map(|entry| (i, entry)));
+                match (declared.next(), declared.next()) {
+                    (Some((a, _)), Some((b, _))) => bail!(format!("Entry point declared in both {} and {}",
+                        objects[a].0.display(), objects[b].0.display())),
+                    (Some((index, (symbol, addend))), None) => match resolve(index, symbol) {
+                        Some(x) => match prog::entry_address(x as i64 + addend) {
+                            Ok(x) => {
+                                declarer = Some(objects[index].0.as_path());
+                                Some(x)
+                            },
+                            Err(e) => bail!(format!("{}: Invalid entry point: {}", objects[index].0.display(), e)),
+                        },
+                        None => bail!(format!("{}: Entry point relative to unknown symbol \"{}\"", objects[index].0.display(), symbol)),
+                    },
+                    _ => None,
+                }
+            },
+        };
+
+        let mut names: Vec<String> = globals.keys().map(|x| x.to_string()).collect();
+        names.sort();
+        let linked = Linked {
+            words,
+            symbols,
+            globals: names,
+            placements: placements.into_iter().map(|x| x.1).collect(),
+            entry,
+        };
+        if let Some(x) = entry {
+            if let Err(e) = prog::check_entry(x, &linked.words, &linked.sections()) {
+                match declarer {
+                    Some(file) => bail!(format!("{}: {}", file.display(), e)),
+                    None => return Err(e),
+                }
+            }
+        }
+        Ok(linked)
+    }
+
+    /// The objects to link: those given, then each archive member defining a symbol that is
+    /// still undefined, named `ARCHIVE(MEMBER)`, until no more are needed.
+    fn select(&self) -> Vec<(PathBuf, &Object)> {
+        let mut objects: Vec<(PathBuf, &Object)> = self.objects.iter().map(|x| (x.0.clone(), &x.1)).collect();
+        loop {
+            let defined = |name: &str| objects.iter()
+                .any(|x| x.1.symbols.iter().any(|x| x.name == name && x.binding == Binding::Global));
+            let needed = objects.iter()
+                .flat_map(|x| x.1.symbols.iter().filter(|x| x.binding == Binding::Extern))
+                .find_map(|symbol| match defined(&symbol.name) {
+                    true => None,
+                    false => self.archives.iter().find_map(|(file, archive)| archive.find(&symbol.name).map(|x| (file, x))),
+                });
+            match needed {
+                Some((file, member)) => objects.push((PathBuf::from(format!("{}({})", file.display(), member.name)), &member.object)),
+                None => return objects,
+            }
+        }
+    }
+
+    /// Places every section of every object: sections named in the script in its order, then
+    /// the rest in the order first seen. The pieces of one section follow each other in the
+    /// order the objects were given.
+    fn place(&self, objects: &[(PathBuf, &Object)]) -> Result<Vec<(usize, Placement)>, Box<dyn Error>> {
+        let mut names: Vec<&str> = self.script.sections.iter().map(|x| x.0.as_str()).collect();
+        for (_, object) in objects {
+            for section in &object.sections {
+                if !names.contains(&section.name.as_str()) {
+                    names.push(&section.name);
+                }
+            }
+        }
+
+        let mut placements = Vec::new();
+        let mut address = 0;
+        for name in names {
+            if let Some((_, x)) = self.script.sections.iter().find(|x| x.0 == name) {
+                address = *x;
+            }
+            for (index, (file, object)) in objects.iter().enumerate() {
+                for section in object.sections.iter().filter(|x| x.name == name) {
+                    placements.push((index, Placement {
+                        file: file.clone(),
+                        section: section.name.clone(),
+                        address,
+                        size: section.size,
+                    }));
+                    address += (section.size + 3) & !3;
+                }
+            }
+        }
+        if let Some(x) = placements.iter().find(|x| x.1.address + x.1.size > 1 << 32) {
+            bail!(format!("{} of {} ends beyond the 32-bit address space", x.1.section, x.1.file.display()));
+        }
+
+        let mut sorted: Vec<&Placement> = placements.iter().map(|x| &x.1).filter(|x| x.size > 0).collect();
+        sorted.sort_by_key(|x| x.address);
+        for pair in sorted.windows(2) {
+            if pair[0].address + pair[0].size > pair[1].address {
+                bail!(format!("{} overlaps {}", describe(pair[0]), describe(pair[1])));
+            }
+        }
+        Ok(placements)
+    }
+}
+
+fn describe(placement: &Placement) -> String {
+    format!("{} of {} ({:#010x}-{:#010x})", placement.section, placement.file.display(),
+        placement.address, placement.address + placement.size - 1)
+}
+
+/// Fills in the field of `word` that `reloc` names with `target`, its symbol plus addend,
+/// for the word at `address`.
+fn relocate(word: u32, reloc: &Relocation, target: i64, address: usize) -> Result<u32, Box<dyn Error>> {
+    match reloc.kind {
+        RelocKind::C1 => Ok(word | inst::signed_field(target - (address as i64 + 4), 22)?),
+        RelocKind::C2 => Ok(word | inst::signed_field(target, 17)?),
+        RelocKind::Word => Ok(word.wrapping_add(inst::word_value(target)?)),
+    }
+}
+
+impl Linked {
+    /// The words written in `format`, with the symbols in ELF output.
+    pub fn output(&self, format: OutputFormat) -> Vec<u8> {
+        match format {
+            OutputFormat::Elf => elf::write(&elf::Image {
+                words: &self.words,
+                entry: self.entry,
+                symbols: &self.symbols,
+                globals: &self.globals,
+                sections: &self.sections(),
+                lines: None,
+            }),
+            x => output::write(&self.words, self.entry, x),
+        }
+    }
+
+    /// Each placement as a section of the program.
+    fn sections(&self) -> Vec<ProgSection> {
+        self.placements.iter()
+            .map(|x| ProgSection { name: x.section.clone(), address: x.address, size: x.size })
+            .collect()
+    }
+
+    /// The symbols as a map file, followed in the text format by where each section went.
+    pub fn map(&self, format: MapFormat) -> String {
+        let mut s = map::write(&self.symbols, format);
+        if format == MapFormat::Text {
+            for placement in self.placements.iter().filter(|x| x.size > 0) {
+                s.push_str(&format!("; {}\n", describe(placement)));
+            }
+        }
+        s
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::prog::Prog;
+
+    fn object(source: &str) -> Object {
+        Prog::new("file", source).unwrap().object().unwrap()
+    }
+
+    #[test]
+    fn script_test() {
+        let script = Script::parse("; layout\n.text 4096\n\n.data 0x1000 ; after\n.vga 0x200000").unwrap();
+        assert_eq!(script.sections, vec![
+            (String::from(".text"), 4096),
+            (String::from(".data"), 0x1000),
+            (String::from(".vga"), 0x200000),
+        ]);
+
+        let invalid_tests = [
+            ".text",
+            ".text 4097",
+            ".text 0x100000000",
+            ".text START",
+            ".text 0\n.text 4",
+        ];
+        for test in &invalid_tests {
+            assert!(Script::parse(test).is_err(), "failed with [{}]", test);
+        }
+    }
+
+    #[test]
+    fn link_test() {
+        let main = object(".global START\n.extern TX, COUNT\nSTART: call TX\n la r1, COUNT\n .word TX + 4\n la r2, START");
+        let uart = object(".global TX, COUNT\n.equ COUNT, 5\nTX: lar r1, TX\n ret");
+        let linked = Linker::new()
+            .object("main.o", main)
+            .object("uart.o", uart)
+            .script(Script::parse(".text 4096").unwrap())
+            .link()
+            .unwrap();
+
+        let words: Vec<(usize, u32)> = linked.words.iter().map(|(a, w)| (*a, *w)).collect();
+        assert_eq!(words, vec![
+            (4096, 0x36800010),
+            (4100, 0x4e740001),
+            (4104, 0x28400005),
+            (4108, 0x00001018),
+            (4112, 0x28801000),
+            (4116, 0x307ffffc),
+            (4120, 0x40320001),
+        ]);
+        let symbols: Vec<(&str, usize)> = linked.symbols.iter().map(|x| (x.name.as_str(), x.value)).collect();
+        assert_eq!(symbols, vec![("START", 4096), ("COUNT", 5), ("TX", 4116)]);
+        assert_eq!(linked.placements[1], Placement { file: PathBuf::from("uart.o"), section: String::from(".text"), address: 4116, size: 8 });
+        assert!(linked.map(MapFormat::Text).ends_with("; .text of uart.o (0x00001014-0x0000101b)\n"));
+    }
+
+    #[test]
+    fn link_sections_test() {
+        let main = object(".global START\n.extern COUNT\nSTART: ld r1, COUNT\n.data\nLOCAL: .word 1\n.word LOCAL");
+        let uart = object(".global COUNT\n.data\nCOUNT: .word 2\n.bss\n.dw 1\n.text\n stop");
+        let linked = Linker::new()
+            .object("main.o", main)
+            .object("uart.o", uart)
+            .script(Script::parse(".text 4096\n.data 0x2000").unwrap())
+            .link()
+            .unwrap();
+
+        let words: Vec<(usize, u32)> = linked.words.iter().map(|(a, w)| (*a, *w)).collect();
+        assert_eq!(words, vec![(0x1000, 0x08402008), (0x1004, 0xf8000000), (0x2000, 1), (0x2004, 0x2000), (0x2008, 2)]);
+        let placements: Vec<(&str, usize)> = linked.placements.iter().map(|x| (x.section.as_str(), x.address)).collect();
+        assert_eq!(placements, vec![(".text", 0x1000), (".text", 0x1004), (".data", 0x2000), (".data", 0x2008), (".bss", 0x200c)]);
+    }
+
+    #[test]
+    fn link_archive_test() {
+        let mut library = Archive::new();
+        let prog = Prog::new("lib.asm", ".global RX, TX, PRINT\nRX: ret\nTX: ret\nPRINT: call TX\n ret").unwrap();
+        for member in prog.members("lib").unwrap() {
+            library.add(member).unwrap();
+        }
+        let main = object(".global START\n.extern PRINT\nSTART: call PRINT\n stop");
+        let linked = Linker::new()
+            .object("main.o", main)
+            .archive("lib.a", library)
+            .link()
+            .unwrap();
+
+        let placements: Vec<(String, usize)> = linked.placements.iter().map(|x| (x.file.display().to_string(), x.address)).collect();
+        assert_eq!(placements, vec![(String::from("main.o"), 0), (String::from("lib.a(PRINT)"), 12), (String::from("lib.a(TX)"), 24)]);
+        let symbols: Vec<(&str, usize)> = linked.symbols.iter().map(|x| (x.name.as_str(), x.value)).collect();
+        assert_eq!(symbols, vec![("START", 0), ("PRINT", 12), ("TX", 24)]);
+    }
+
+    #[test]
+    fn link_entry_test() {
+        let main = || object(".global START\n.extern INIT\n.entry START + 4\nSTART: call INIT\n stop");
+        let init = || object(".global INIT\n.data\n.word 7\n.text\nINIT: ret");
+        let link = |entry: Option<&str>| {
+            let mut linker = Linker::new();
+            linker.object("init.o", init()).object("main.o", main()).script(Script::parse(".text 4096").unwrap());
+            if let Some(x) = entry {
+                linker.entry(x);
+            }
+            linker.link()
+        };
+        let linked = link(None).unwrap();
+        assert_eq!(linked.entry, Some(4104));
+        assert!(linked.output(OutputFormat::SRecord).ends_with(b"S70500001008E2\n"));
+        assert!(String::from_utf8(linked.output(OutputFormat::Text)).unwrap().starts_with("orange-text 1\nentry 00001008\n"));
+        assert_eq!(link(Some("INIT")).unwrap().entry, Some(4096));
+        assert_eq!(Linker::new().object("init.o", init()).link().unwrap().entry, None);
+
+        let crc = Linker::new()
+            .object("init.o", init())
+            .object("end.o", object(".global END\nEND: .dw 1"))
+            .checksum(Request::parse(checksum::Kind::Crc32, "END=0,END").unwrap())
+            .link()
+            .unwrap();
+        assert_eq!(crc.words[&4], checksum::crc32(&[0x40, 0x32, 0x00, 0x01]));
+
+        let invalid_tests = ["MISSING", "INIT + 2", "START + 12", "0", "0x100000000", "-4"];
+        for test in &invalid_tests {
+            assert!(link(Some(test)).is_err(), "failed with [{}]", test);
+        }
+        let message = Linker::new().object("a.o", main()).object("b.o", object(".entry 0\nstop")).object("init.o", init())
+            .link().err().unwrap().to_string();
+        assert_eq!(message, "Entry point declared in both a.o and b.o");
+        let message = Linker::new().object("a.o", object(".entry END\nstop\nEND: .dw 1")).link().err().unwrap().to_string();
+        assert_eq!(message, "a.o: Entry point 0x00000004 is not the address of emitted code");
+        let message = Linker::new().object("x.o", object(".global X\nX: stop")).object("a.o", object(".extern X\n.entry X - 4\nstop"))
+            .link().err().unwrap().to_string();
+        assert_eq!(message, "a.o: Invalid entry point: -4 is not in the 32-bit address space");
+    }
+
+    #[test]
+    fn link_errors_test() {
+        let link = |sources: &[&str], script: &str| {
+            let mut linker = Linker::new();
+            for (i, source) in sources.iter().enumerate() {
+                linker.object(format!("{}.o", i), object(source));
+            }
+            linker.script(Script::parse(script).unwrap()).link()
+        };
+
+        let message = link(&[".extern A, B\nla r1, A\nla r2, B", ".global A\nA: nop"], "").err().unwrap().to_string();
+        assert_eq!(message, "0.o: Undefined symbol \"B\"");
+        let message = link(&[".global A\nA: nop", ".global A\nA: nop"], "").err().unwrap().to_string();
+        assert_eq!(message, "Symbol \"A\" defined in both 0.o and 1.o");
+
+        let invalid_tests = [
+            (vec![".extern A\nla r1, A", ".global A\n.org 0x10000\nA: nop"], ""),
+            (vec![".extern A\nlar r1, A", ".global A\n.org 0x200000\nA: nop"], ""),
+            (vec![".extern A\nlar r1, A"], ""),
+            (vec!["nop\nnop"], ".text 0xfffffffc"),
+            (vec![".extern A\n.word A + 0xffffffff", ".global A\nA: nop"], ".text 0x1000"),
+            (vec![], ""),
+        ];
+        for test in &invalid_tests {
+            assert!(link(&test.0, test.1).is_err(), "failed with [{:?}]", test.0);
+        }
+    }
+}
@@ -0,0 +1,546 @@
+use std::collections::HashMap;
+use std::error::Error;
+use crate::cond::Early;
+use crate::expr::Expr;
+use crate::lexer;
+use crate::source::{Loc, Source, SourceLine};
+
+/// Deepest chain of nested macro invocations expanded before giving up.
+pub const RECURSION_LIMIT: usize = 64;
+
+/// Largest `.rept` count expanded, and the most lines macro invocations and `.rept`/`.irp`
+/// blocks may expand to in all, so that nested blocks cannot multiply past it.
+pub const REPEAT_LIMIT: usize = 65536;
+
+#[derive(Debug, PartialEq)]
+struct Param {
+    name: String,
+    default: Option<String>,
+}
+
+struct Macro {
+    params: Vec<Param>,
+    body: Vec<SourceLine>,
+}
+
+struct Expander<'a> {
+    source: &'a Source,
+    early: Early,
+    macros: HashMap<String, Macro>,
+    count: usize,
+    /// Lines expanded so far; see `REPEAT_LIMIT`.
+    expanded: usize,
+    output: Vec<SourceLine>,
+}
+
+/// Replaces every `.macro` definition and invocation and every `.rept`/`.irp` block in
+/// `source` with the expanded lines. Repeat counts may only refer to `known` symbols and
+/// `.equ` symbols whose values are known before them. Lines in a block that conditions skip
+/// are kept as they are; see `cond::Early`.
+///
+/// Invocation lines are kept with only their label, so listings can show them above the
+/// expansion and labels still mark the first expanded line.
+pub fn expand(source: &Source, known: &[(String, i64)]) -> Result<Vec<SourceLine>, Box<dyn Error>> {
+    let mut expander = Expander {
+        source,
+        early: Early::new(known),
+        macros: HashMap::new(),
+        count: 0,
+        expanded: 0,
+        output: Vec::new(),
+    };
+    expander.process(&source.lines)?;
+    Ok(expander.output)
+}
+
+impl<'a> Expander<'a> {
+    fn process(&mut self, lines: &[SourceLine]) -> Result<(), Box<dyn Error>> {
+        let mut i = 0;
+        while i < lines.len() {
+            let line = &lines[i];
+            match self.early.process(&line.text, line.loc) {
+                Ok(true) => (),
+                Ok(false) if self.early.active() => (),
+                Ok(false) => {
+                    self.output.push(line.clone());
+                    i += 1;
+                    continue;
+                },
+                Err(e) => bail!(format!("{}: {}", self.source.describe_line(line), e)),
+            }
+            let (label, body) = split_label(&line.text);
+            let word = body.split_whitespace().next().unwrap_or("");
+            let rest = body[word.len()..].trim();
+
+            match word {
+                ".macro" => {
+                    if label.is_some() {
+                        bail!(format!("{}: Labels are not allowed on .macro", self.source.describe_line(line)));
+                    }
+                    let end = match find_end(lines, i, &[".macro"], ".endm") {
+                        Some(x) => x,
+                        None => bail!(format!("{}: Unterminated .macro (missing .endm)", self.source.describe_line(line))),
+                    };
+                    let (name, params) = match process_header(rest) {
+                        Ok(x) => x,
+                        Err(e) => bail!(format!("{}: {}", self.source.describe_line(line), e)),
+                    };
+                    if self.macros.contains_key(&name) {
+                        bail!(format!("{}: Macro \"{}\" is already defined", self.source.describe_line(line), name));
+                    }
+                    let body = lines[(i+1)..end].to_vec();
+                    self.macros.insert(name, Macro { params, body });
+                    i = end + 1;
+                    continue;
+                },
+                ".endm" => bail!(format!("{}: .endm without .macro", self.source.describe_line(line))),
+                ".rept" | ".irp" => {
+                    if line.expansion.len() >= RECURSION_LIMIT {
+                        bail!(format!("{}: Macro recursion limit ({}) exceeded expanding {}",
+                            self.source.describe_line(line), RECURSION_LIMIT, word));
+                    }
+                    let end = match find_end(lines, i, &[".rept", ".irp"], ".endr") {
+                        Some(x) => x,
+                        None => bail!(format!("{}: Unterminated {} (missing .endr)", self.source.describe_line(line), word)),
+                    };
+                    let expanded = match self.repeat(word, rest, &lines[(i+1)..end], line) {
+                        Ok(x) => x,
+                        Err(e) => bail!(format!("{}: {}", self.source.describe_line(line), e)),
+                    };
+                    self.output.push(SourceLine {
+                        text: label.map(|l| format!("{}:", l)).unwrap_or_default(),
+                        raw: line.raw.clone(),
+                        loc: line.loc,
+                        expansion: line.expansion.clone(),
+                    });
+                    self.process(&expanded)?;
+                    i = end + 1;
+                    continue;
+                },
+                ".endr" => bail!(format!("{}: .endr without .rept or .irp", self.source.describe_line(line))),
+                _ if self.macros.contains_key(word) => {
+                    if line.expansion.len() >= RECURSION_LIMIT {
+                        bail!(format!("{}: Macro recursion limit ({}) exceeded expanding \"{}\"",
+                            self.source.describe_line(line), RECURSION_LIMIT, word));
+                    }
+                    let expanded = match self.invoke(word, rest, line) {
+                        Ok(x) => x,
+                        Err(e) => bail!(format!("{}: {}", self.source.describe_line(line), e)),
+                    };
+                    self.output.push(SourceLine {
+                        text: label.map(|l| format!("{}:", l)).unwrap_or_default(),
+                        raw: line.raw.clone(),
+                        loc: line.loc,
+                        expansion: line.expansion.clone(),
+                    });
+                    self.process(&expanded)?;
+                },
+                _ => self.output.push(line.clone()),
+            }
+            i += 1;
+        }
+        Ok(())
+    }
+
+    /// Counts `copies` copies of `lines` lines towards `REPEAT_LIMIT`.
+    fn add_expanded(&mut self, lines: usize, copies: usize) -> Result<(), Box<dyn Error>> {
+        match lines.checked_mul(copies).and_then(|x| x.checked_add(self.expanded)) {
+            Some(x) if x <= REPEAT_LIMIT => {
+                self.expanded = x;
+                Ok(())
+            },
+            _ => bail!(format!("Expansion is over the limit of {} lines in all", REPEAT_LIMIT)),
+        }
+    }
+
+    fn invoke(&mut self, name: &str, args: &str, line: &SourceLine) -> Result<Vec<SourceLine>, Box<dyn Error>> {
+        let unique = self.count.to_string();
+        self.count += 1;
+        let lines = self.macros[name].body.len();
+        self.add_expanded(lines, 1)?;
+        let mac = &self.macros[name];
+        let values = bind_args(name, &mac.params, args)?;
+
+        let expansion = expansion_of(line);
+
+        Ok(mac.body.iter()
+            .map(|body_line| {
+                let text = substitute(&body_line.text, &mac.params, &values, &unique);
+                SourceLine {
+                    raw: text.clone(),
+                    text,
+                    loc: body_line.loc,
+                    expansion: expansion.clone(),
+                }
+            })
+            .collect())
+    }
+
+    /// Copies `body` once per `.rept` count or once per `.irp` value, substituting the
+    /// `.irp` symbol.
+    fn repeat(&mut self, directive: &str, args: &str, body: &[SourceLine], line: &SourceLine) -> Result<Vec<SourceLine>, Box<dyn Error>> {
+        let (params, values) = match directive {
+            ".rept" => {
+                let lookup = |s: &str| self.early.value(s);
+                let count = match Expr::parse(args)?.eval(&lookup) {
+                    Ok(x) => x,
+                    Err(e) => bail!(format!("{} (a .rept count may only use -D defines and .equ symbols defined before it)", e)),
+                };
+                match count {
+                    x if x < 0 => bail!(format!("Negative .rept count {}", x)),
+                    x if x as u64 > REPEAT_LIMIT as u64 => bail!(format!(".rept count {} is over the limit of {}", x, REPEAT_LIMIT)),
+                    _ => (),
+                }
+                (Vec::new(), vec![Vec::new(); count as usize])
+            },
+            _ => {
+                let (name, list) = match args.find(',') {
+                    Some(x) => (args[..x].trim(), &args[(x+1)..]),
+                    None => (args.trim(), ""),
+                };
+                if !is_name(name) {
+                    bail!(format!("Invalid .irp symbol \"{}\"", name));
+                }
+                let param = Param {
+                    name: name.to_string(),
+                    default: None,
+                };
+                let values = list.split(',').map(|v| vec![v.trim().to_string()]).collect();
+                (vec![param], values)
+            },
+        };
+
+        self.add_expanded(body.len(), values.len())?;
+        let expansion = expansion_of(line);
+        let mut expanded = Vec::new();
+        for value in values {
+            let unique = self.count.to_string();
+            self.count += 1;
+            for body_line in body {
+                let text = substitute(&body_line.text, &params, &value, &unique);
+                expanded.push(SourceLine {
+                    raw: text.clone(),
+                    text,
+                    loc: body_line.loc,
+                    expansion: expansion.clone(),
+                });
+            }
+        }
+        Ok(expanded)
+    }
+}
+
+/// The expansion chain for lines produced from the invocation at `line`.
+fn expansion_of(line: &SourceLine) -> Vec<Loc> {
+    let mut expansion: Vec<Loc> = vec![line.loc];
+    expansion.extend(line.expansion.iter().cloned());
+    expansion
+}
+
+/// Splits off the label of a line, dropping any comment.
+pub fn split_label(text: &str) -> (Option<&str>, &str) {
+    let text = lexer::strip_comment(text).trim();
+    match lexer::find_unquoted(text, ':') {
+        Some(x) => (Some(text[..x].trim()), text[(x+1)..].trim()),
+        None => (None, text),
+    }
+}
+
+/// Index of the `close` directive ending the block opened at `start`, skipping nested blocks.
+fn find_end(lines: &[SourceLine], start: usize, open: &[&str], close: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, line) in lines.iter().enumerate().skip(start) {
+        match split_label(&line.text).1.split_whitespace().next() {
+            Some(word) if open.contains(&word) => depth += 1,
+            Some(word) if word == close => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            },
+            _ => (),
+        }
+    }
+    None
+}
+
+fn process_header(header: &str) -> Result<(String, Vec<Param>), Box<dyn Error>> {
+    let name_end = header.find(|c: char| c.is_whitespace() || c == ',').unwrap_or(header.len());
+    let name = &header[..name_end];
+    if !is_name(name) {
+        bail!(format!("Invalid macro name \"{}\"", name));
+    }
+
+    let mut params: Vec<Param> = Vec::new();
+    for param in header[name_end..].split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (param_name, default) = match param.find('=') {
+            Some(x) => (param[..x].trim(), Some(param[(x+1)..].trim().to_string())),
+            None => (param, None),
+        };
+        if !is_name(param_name) {
+            bail!(format!("Invalid macro parameter \"{}\"", param_name));
+        }
+        if params.iter().any(|p| p.name == param_name) {
+            bail!(format!("Duplicate macro parameter \"{}\"", param_name));
+        }
+        params.push(Param {
+            name: param_name.to_string(),
+            default,
+        });
+    }
+
+    Ok((name.to_string(), params))
+}
+
+fn bind_args(name: &str, params: &[Param], args: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut values: Vec<Option<String>> = params.iter().map(|_| None).collect();
+    let mut position = 0;
+
+    if !args.is_empty() {
+        for arg in args.split(',').map(str::trim) {
+            let named = match arg.find('=') {
+                Some(x) if is_name(arg[..x].trim()) => Some((arg[..x].trim(), arg[(x+1)..].trim())),
+                _ => None,
+            };
+            let (index, value) = match named {
+                Some((param, value)) => match params.iter().position(|p| p.name == param) {
+                    Some(x) => (x, value),
+                    None => bail!(format!("Macro \"{}\" has no parameter \"{}\"", name, param)),
+                },
+                None => {
+                    position += 1;
+                    if position > params.len() {
+                        bail!(format!("Too many arguments for macro \"{}\"", name));
+                    }
+                    (position - 1, arg)
+                },
+            };
+            if values[index].is_some() {
+                bail!(format!("Parameter \"{}\" of macro \"{}\" given more than once", params[index].name, name));
+            }
+            if !value.is_empty() {
+                values[index] = Some(value.to_string());
+            }
+        }
+    }
+
+    params.iter()
+        .zip(values)
+        .map(|(param, value)| match value.or_else(|| param.default.clone()) {
+            Some(x) => Ok(x),
+            None => bail!(format!("Missing value for parameter \"{}\" of macro \"{}\"", param.name, name)),
+        })
+        .collect()
+}
+
+/// Replaces `\param` with its value, `\@` with the expansion number and drops `\()` separators.
+fn substitute(text: &str, params: &[Param], values: &[String], unique: &str) -> String {
+    let mut s = String::new();
+    let mut rest = text;
+    while let Some(x) = rest.find('\\') {
+        s.push_str(&rest[..x]);
+        rest = &rest[(x+1)..];
+        if let Some(tail) = rest.strip_prefix('@') {
+            s.push_str(unique);
+            rest = tail;
+        } else if let Some(tail) = rest.strip_prefix("()") {
+            rest = tail;
+        } else {
+            let end = rest.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(rest.len());
+            match params.iter().position(|p| p.name == rest[..end]) {
+                Some(index) => s.push_str(&values[index]),
+                None => {
+                    s.push('\\');
+                    s.push_str(&rest[..end]);
+                },
+            }
+            rest = &rest[end..];
+        }
+    }
+    s.push_str(rest);
+    s
+}
+
+fn is_name(name: &str) -> bool {
+    match name.chars().next() {
+        Some(ch) if ch.is_alphabetic() || ch == '_' => name.chars().all(|c| c.is_alphanumeric() || c == '_'),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::source::EXPANSION_FRAMES;
+    use std::path::Path;
+
+    fn expand_str(contents: &str) -> Result<Vec<(String, usize)>, Box<dyn Error>> {
+        let source = Source::from_str(Path::new("file"), contents, &[])?;
+        let known = vec![("COUNT".to_string(), 2)];
+        Ok(expand(&source, &known)?.into_iter().map(|l| (l.text, l.expansion.len())).collect())
+    }
+
+    #[test]
+    fn process_header_test() {
+        let tests = [
+            ("SEND", ("SEND", vec![])),
+            ("SEND ch", ("SEND", vec![Param{name: "ch".to_string(), default: None}])),
+            ("SEND ch, reg = r5", ("SEND", vec![
+                Param{name: "ch".to_string(), default: None},
+                Param{name: "reg".to_string(), default: Some("r5".to_string())},
+            ])),
+        ];
+        for test in &tests {
+            let (name, params) = process_header(test.0).unwrap();
+            assert_eq!(name, (test.1).0);
+            assert_eq!(params, (test.1).1);
+        }
+
+        let invalid_tests = [
+            "",
+            "1SEND",
+            "SEND a, a",
+            "SEND a-b",
+        ];
+        for test in &invalid_tests {
+            let result = process_header(test);
+            assert!(result.is_err(), "failed with [{}]", test);
+        }
+    }
+
+    #[test]
+    fn bind_args_test() {
+        let (_, params) = process_header("SEND ch, reg=r5").unwrap();
+        let tests = [
+            ("82", vec!["82", "r5"]),
+            ("82, r6", vec!["82", "r6"]),
+            ("reg=r7, ch=1", vec!["1", "r7"]),
+            ("65, reg = r8", vec!["65", "r8"]),
+        ];
+        for test in &tests {
+            let result = bind_args("SEND", &params, test.0).unwrap();
+            assert_eq!(result, test.1);
+        }
+
+        let invalid_tests = [
+            "",
+            "1, r2, r3",
+            "port=5",
+            "1, ch=2",
+        ];
+        for test in &invalid_tests {
+            let result = bind_args("SEND", &params, test);
+            assert!(result.is_err(), "failed with [{}]", test);
+        }
+    }
+
+    #[test]
+    fn expand_test() {
+        let source = "\
+.macro SEND ch, reg=r5
+L\\@: ld r4, 4
+ addi \\reg, \\reg, \\ch
+.endm
+START: SEND 82 ; R
+ SEND 73, r6";
+        let result = expand_str(source).unwrap();
+        assert_eq!(result, vec![
+            ("START:".to_string(), 0),
+            ("L0: ld r4, 4".to_string(), 1),
+            (" addi r5, r5, 82".to_string(), 1),
+            ("".to_string(), 0),
+            ("L1: ld r4, 4".to_string(), 1),
+            (" addi r6, r6, 73".to_string(), 1),
+        ]);
+    }
+
+    #[test]
+    fn expand_nested_test() {
+        let source = "\
+.macro INNER x
+ nop\\()\\x
+.endm
+.macro OUTER
+ INNER 1
+ .macro LATE
+  stop
+ .endm
+.endm
+ OUTER
+ LATE";
+        let result = expand_str(source).unwrap();
+        assert_eq!(result, vec![
+            ("".to_string(), 0),
+            ("".to_string(), 1),
+            (" nop1".to_string(), 2),
+            ("".to_string(), 0),
+            ("  stop".to_string(), 1),
+        ]);
+
+        let invalid_tests = [
+            ".macro LOOP\n LOOP\n.endm\n LOOP",
+            ".macro OPEN\n nop",
+            " nop\n.endm",
+            ".macro TWICE\n.endm\n.macro TWICE\n.endm",
+            "L: .macro M\n.endm",
+        ];
+        for test in &invalid_tests {
+            let result = expand_str(test);
+            assert!(result.is_err(), "failed with [{}]", test);
+        }
+
+        let message = expand_str(".macro LOOP\n LOOP\n.endm\n LOOP").err().unwrap().to_string();
+        assert!(message.contains("recursion limit"), "{}", message);
+        assert_eq!(message.matches("expanded from").count(), 2 * EXPANSION_FRAMES, "{}", message);
+        assert!(message.contains(&format!("(… {} more)", RECURSION_LIMIT - 2 * EXPANSION_FRAMES)), "{}", message);
+    }
+
+    #[test]
+    fn expand_repeat_test() {
+        let source = "\
+.rept COUNT + 1
+ nop
+.endr
+T: .irp reg, r1, r2
+ .rept 2
+ addi \\reg, \\reg, 1
+ .endr
+.endr";
+        let result = expand_str(source).unwrap();
+        assert_eq!(result, vec![
+            ("".to_string(), 0),
+            (" nop".to_string(), 1),
+            (" nop".to_string(), 1),
+            (" nop".to_string(), 1),
+            ("T:".to_string(), 0),
+            ("".to_string(), 1),
+            (" addi r1, r1, 1".to_string(), 2),
+            (" addi r1, r1, 1".to_string(), 2),
+            ("".to_string(), 1),
+            (" addi r2, r2, 1".to_string(), 2),
+            (" addi r2, r2, 1".to_string(), 2),
+        ]);
+
+        let invalid_tests = [
+            ".rept 2\n nop",
+            ".endr",
+            ".rept MISSING\n.endr",
+            ".rept -1\n.endr",
+            ".irp 1x, a\n.endr",
+            ".rept 1\n.endm\n.endr",
+            ".rept 100000000\n nop\n.endr",
+            ".rept LABEL\n.endr\nLABEL: nop",
+            ".rept 65536\n.rept 65536\n.rept 65536\n nop\n.endr\n.endr\n.endr",
+            ".rept 256\n.rept 512\n nop\n.endr\n.endr",
+            ".macro twice\n nop\n nop\n.endm\n.rept 65536\n twice\n.endr",
+        ];
+        for test in &invalid_tests {
+            let result = expand_str(test);
+            assert!(result.is_err(), "failed with [{}]", test);
+        }
+
+        let result = expand_str(".equ N, COUNT * 2\n.rept N\n nop\n.endr").unwrap();
+        assert_eq!(result.iter().filter(|x| x.0 == " nop").count(), 4);
+    }
+}
@@ -8,6 +8,7 @@ fn main() {
 
     let config = Config::new(&args).unwrap_or_else(|err| {
         eprintln!("Problem parsing arguments: {}", err);
+        eprintln!("Try --help for usage.");
         process::exit(1);
     });
     
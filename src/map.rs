@@ -0,0 +1,350 @@
+//! Map files: every symbol of a program with its value, kind, section and defining line, for
+//! people to read and for other programs, such as one loaded beside a resident monitor, to
+//! import.
+
+use std::error::Error;
+use std::path::PathBuf;
+
+use crate::lexer;
+use crate::prog::{Symbol, SymbolKind};
+use crate::source::SourceRef;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, EnumString)]
+pub enum MapFormat {
+    /// Aligned `value kind section name file:line` columns after a `;` heading.
+    #[default]
+    #[strum(serialize = "text")]
+    Text,
+    /// An array of objects with `name`, `value`, `kind`, `section`, `file` and `line` members.
+    #[cfg(feature = "serde")]
+    #[strum(serialize = "json")]
+    Json,
+    /// A `name,value,kind,section,file,line` heading, then one row per symbol.
+    #[strum(serialize = "csv")]
+    Csv,
+}
+
+impl MapFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            MapFormat::Text => "map",
+            #[cfg(feature = "serde")]
+            MapFormat::Json => "json",
+            MapFormat::Csv => "csv",
+        }
+    }
+}
+
+const CSV_HEADING: &str = "name,value,kind,section,file,line";
+
+/// Writes `symbols` in `format`, ordered by value then name.
+pub fn write(symbols: &[Symbol], format: MapFormat) -> String {
+    let mut symbols: Vec<&Symbol> = symbols.iter().collect();
+    symbols.sort_by(|a, b| (a.value, &a.name).cmp(&(b.value, &b.name)));
+    match format {
+        MapFormat::Text => write_text(&symbols),
+        #[cfg(feature = "serde")]
+        MapFormat::Json => write_json(&symbols),
+        MapFormat::Csv => write_csv(&symbols),
+    }
+}
+
+fn describe(source: &Option<SourceRef>) -> String {
+    match source {
+        Some(x) => x.to_string(),
+        None => String::from("-"),
+    }
+}
+
+/// The value as the 32-bit word it stands for, so negative `.equ`s and defines read back.
+fn word(symbol: &Symbol) -> u32 {
+    symbol.value as u32
+}
+
+fn write_text(symbols: &[&Symbol]) -> String {
+    let width = symbols.iter().map(|x| x.name.len()).max().unwrap_or(0).max(4);
+    let mut s = format!("; {:<6} {:<6} {:<7} {:<w$} source\n", "value", "kind", "section", "name", w = width);
+    for symbol in symbols {
+        s.push_str(&format!("{:08x} {:<6} {:<7} {:<w$} {}\n", word(symbol), symbol.kind.to_string(),
+            symbol.section, symbol.name, describe(&symbol.source), w = width));
+    }
+    s
+}
+
+/// A row of a JSON map file.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonSymbol {
+    name: String,
+    value: u32,
+    kind: String,
+    section: String,
+    file: Option<String>,
+    line: Option<usize>,
+}
+
+#[cfg(feature = "serde")]
+fn write_json(symbols: &[&Symbol]) -> String {
+    let rows: Vec<String> = symbols.iter().map(|symbol| {
+        let row = JsonSymbol {
+            name: symbol.name.clone(),
+            value: word(symbol),
+            kind: symbol.kind.to_string(),
+            section: symbol.section.clone(),
+            file: symbol.source.as_ref().map(|x| x.file.to_string_lossy().into_owned()),
+            line: symbol.source.as_ref().map(|x| x.line),
+        };
+        format!("  {}", serde_json::to_string(&row).unwrap())
+    }).collect();
+    match rows.is_empty() {
+        true => String::from("[]\n"),
+        false => format!("[\n{}\n]\n", rows.join(",\n")),
+    }
+}
+
+fn csv_field(text: &str) -> String {
+    match text.contains([',', '"', '\n']) {
+        true => format!("\"{}\"", text.replace('"', "\"\"")),
+        false => text.to_string(),
+    }
+}
+
+fn write_csv(symbols: &[&Symbol]) -> String {
+    let mut s = format!("{}\n", CSV_HEADING);
+    for symbol in symbols {
+        let (file, line) = match &symbol.source {
+            Some(x) => (csv_field(&x.file.to_string_lossy()), x.line.to_string()),
+            None => (String::new(), String::new()),
+        };
+        s.push_str(&format!("{},{},{},{},{},{}\n", csv_field(&symbol.name), word(symbol),
+            symbol.kind, csv_field(&symbol.section), file, line));
+    }
+    s
+}
+
+/// Reads a map file written in any of the formats, telling them apart by their first line.
+pub fn read(text: &str) -> Result<Vec<Symbol>, Box<dyn Error>> {
+    let first = text.lines().map(str::trim).find(|x| !x.is_empty()).unwrap_or("");
+    if first.starts_with('[') {
+        read_json(text)
+    } else if first == CSV_HEADING {
+        read_csv(text)
+    } else {
+        read_text(text)
+    }
+}
+
+/// Builds a symbol from the text of its fields, as read from any format.
+fn symbol(name: &str, value: &str, kind: &str, section: &str, file: &str, line: &str) -> Result<Symbol, Box<dyn Error>> {
+    let value = match lexer::parse_number(value) {
+        Some(x) if (0..1 << 32).contains(&x) => x as usize,
+        _ => bail!(format!("Invalid value \"{}\" for \"{}\"", value, name)),
+    };
+    let kind = match kind.parse::<SymbolKind>() {
+        Ok(x) => x,
+        Err(_) => bail!(format!("Invalid kind \"{}\" for \"{}\"", kind, name)),
+    };
+    let source = match (file, line) {
+        ("", "") => None,
+        ("", _) | (_, "") => bail!(format!("Expected both a file and a line for \"{}\"", name)),
+        (file, line) => match line.parse::<usize>() {
+            Ok(line) => Some(SourceRef { file: PathBuf::from(file), line }),
+            Err(_) => bail!(format!("Invalid line \"{}\" for \"{}\"", line, name)),
+        },
+    };
+    Ok(Symbol { name: name.to_string(), value, kind, section: section.to_string(), source })
+}
+
+fn read_text(text: &str) -> Result<Vec<Symbol>, Box<dyn Error>> {
+    let mut symbols = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = lexer::strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        // The source is the rest of the line, as file names may hold spaces.
+        let (fields, rest) = lexer::split_fields(line, 4);
+        let (value, kind, section, name) = match fields.as_slice() {
+            [value, kind, section, name] => (value, kind, section, name),
+            _ => bail!(format!("line {}: Expected value, kind, section and name", index + 1)),
+        };
+        let result = match rest {
+            "" | "-" => symbol(name, &format!("0x{}", value), kind, section, "", ""),
+            x => SourceRef::parse(x).and_then(|source| {
+                symbol(name, &format!("0x{}", value), kind, section, &source.file.to_string_lossy(), &source.line.to_string())
+            }),
+        };
+        match result {
+            Ok(x) => symbols.push(x),
+            Err(e) => bail!(format!("line {}: {}", index + 1, e)),
+        }
+    }
+    Ok(symbols)
+}
+
+/// Splits a CSV row into fields, undoing the quoting `csv_field` adds.
+fn csv_row(line: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut fields = vec![String::new()];
+    let mut chars = line.chars().peekable();
+    let mut quoted = false;
+    while let Some(ch) = chars.next() {
+        let field = fields.last_mut().unwrap();
+        match ch {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            },
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        bail!("Unterminated quoted field");
+    }
+    Ok(fields)
+}
+
+fn read_csv(text: &str) -> Result<Vec<Symbol>, Box<dyn Error>> {
+    let mut symbols = Vec::new();
+    for (index, line) in text.lines().enumerate().skip_while(|(_, x)| x.trim() != CSV_HEADING).skip(1) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let result = csv_row(line).and_then(|fields| match fields.as_slice() {
+            [name, value, kind, section, file, line] => symbol(name, value, kind, section, file, line),
+            _ => bail!(format!("Expected {} fields", CSV_HEADING.split(',').count())),
+        });
+        match result {
+            Ok(x) => symbols.push(x),
+            Err(e) => bail!(format!("line {}: {}", index + 1, e)),
+        }
+    }
+    Ok(symbols)
+}
+
+#[cfg(feature = "serde")]
+fn read_json(text: &str) -> Result<Vec<Symbol>, Box<dyn Error>> {
+    let rows: Vec<JsonSymbol> = match serde_json::from_str(text) {
+        Ok(x) => x,
+        Err(e) => bail!(format!("Invalid JSON map: {}", e)),
+    };
+    let mut symbols = Vec::new();
+    for (index, row) in rows.iter().enumerate() {
+        let line = row.line.map(|x| x.to_string()).unwrap_or_default();
+        let result = symbol(&row.name, &row.value.to_string(), &row.kind, &row.section,
+            row.file.as_deref().unwrap_or(""), &line);
+        match result {
+            Ok(x) => symbols.push(x),
+            Err(e) => bail!(format!("symbol {}: {}", index + 1, e)),
+        }
+    }
+    Ok(symbols)
+}
+
+#[cfg(not(feature = "serde"))]
+fn read_json(_: &str) -> Result<Vec<Symbol>, Box<dyn Error>> {
+    bail!("Reading JSON maps needs the serde feature")
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn symbols() -> Vec<Symbol> {
+        let source = |file: &str, line| Some(SourceRef { file: PathBuf::from(file), line });
+        vec![
+            Symbol { name: String::from("TX"), value: 0x40, kind: SymbolKind::Label, section: String::from(".text"), source: source("monitor.asm", 12) },
+            Symbol { name: String::from("RX"), value: 0x20, kind: SymbolKind::Label, section: String::from(".text"), source: source("lib, \"io\".asm", 3) },
+            Symbol { name: String::from("BOARD"), value: 2, kind: SymbolKind::Define, section: String::from("*ABS*"), source: None },
+            Symbol { name: String::from("acc"), value: 5, kind: SymbolKind::Reg, section: String::from("*ABS*"), source: source("my dir/monitor.asm", 1) },
+        ]
+    }
+
+    #[test]
+    fn write_test() {
+        let symbols = symbols();
+        assert_eq!(write(&symbols, MapFormat::Text), "\
+; value  kind   section name  source
+00000002 define *ABS*   BOARD -
+00000005 reg    *ABS*   acc   my dir/monitor.asm:1
+00000020 label  .text   RX    lib, \"io\".asm:3
+00000040 label  .text   TX    monitor.asm:12
+");
+        assert_eq!(write(&symbols[..2], MapFormat::Csv), "\
+name,value,kind,section,file,line
+RX,32,label,.text,\"lib, \"\"io\"\".asm\",3
+TX,64,label,.text,monitor.asm,12
+");
+        #[cfg(feature = "serde")]
+        {
+            assert_eq!(write(&symbols[1..3], MapFormat::Json), "[
+  {\"name\":\"BOARD\",\"value\":2,\"kind\":\"define\",\"section\":\"*ABS*\",\"file\":null,\"line\":null},
+  {\"name\":\"RX\",\"value\":32,\"kind\":\"label\",\"section\":\".text\",\"file\":\"lib, \\\"io\\\".asm\",\"line\":3}
+]
+");
+            assert_eq!(write(&[], MapFormat::Json), "[]\n");
+        }
+    }
+
+    fn formats() -> Vec<MapFormat> {
+        let mut formats = vec![MapFormat::Text, MapFormat::Csv];
+        if cfg!(feature = "serde") {
+            formats.push("json".parse().unwrap());
+        }
+        formats
+    }
+
+    #[test]
+    fn read_test() {
+        let mut expected = symbols();
+        expected.sort_by_key(|x| x.value);
+        for format in &formats() {
+            let result = read(&write(&expected, *format)).unwrap();
+            assert_eq!(result, expected, "failed with [{:?}]", format);
+        }
+
+        let result = read("00001000 label .text START\n\n0000100c equ *ABS* SIZE main.asm:4 ; size\n").unwrap();
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].source, None);
+        assert_eq!(result[1].value, 0x100c);
+
+        let invalid_tests = [
+            "00001000 label .text",
+            "zz label .text START",
+            "00001000 proc .text START",
+            "00001000 label .text START main.asm",
+            "name,value,kind,section,file,line\nSTART,1,label,.text,main.asm",
+            "name,value,kind,section,file,line\nSTART,-1,label,.text,,",
+            "name,value,kind,section,file,line\n\"START,1,label,.text,,",
+            "[{\"name\": \"START\", \"value\": 1, \"kind\": \"label\"",
+            "[{\"name\": \"START\", \"value\": 1, \"kind\": \"label\", \"section\": \".text\", \"line\": 3}]",
+            "[1]",
+            "[] []",
+        ];
+        for test in &invalid_tests {
+            assert!(read(test).is_err(), "failed with [{}]", test);
+        }
+    }
+
+    #[test]
+    fn negative_value_test() {
+        // A negative `.equ` is written as the word it encodes to, which reads back.
+        let symbols = vec![Symbol { name: String::from("DOWN"), value: -32i64 as usize, kind: SymbolKind::Equ, section: String::from("*ABS*"), source: None }];
+        for format in &formats() {
+            let result = read(&write(&symbols, *format)).unwrap();
+            assert_eq!(result[0].value, 0xffff_ffe0, "failed with [{:?}]", format);
+        }
+        assert!(write(&symbols, MapFormat::Text).contains("ffffffe0 equ"));
+    }
+
+    #[test]
+    fn map_format_test() {
+        assert_eq!("csv".parse::<MapFormat>().unwrap(), MapFormat::Csv);
+        #[cfg(feature = "serde")]
+        assert_eq!(MapFormat::Json.extension(), "json");
+        assert!("xml".parse::<MapFormat>().is_err());
+    }
+}
@@ -0,0 +1,354 @@
+//! Relocatable objects: the sections of one assembled file with the addresses in them left for
+//! a linker to fill in, so a large program can be assembled a file at a time.
+//!
+//! Objects are written as text, one item per line after an `orange-object 1` heading:
+//!
+//! ```text
+//! section .text 0000000c
+//! word 00000000 28400008
+//! reloc 00000004 c1 TX 0
+//! symbol global .text 00000000 START main.asm:4
+//! symbol extern *UND* 00000000 TX
+//! entry .text 0
+//! ```
+//!
+//! `word` and `reloc` lines belong to the `section` above them. Offsets and values are
+//! hexadecimal, addends decimal. Symbols end with the line defining them, when known. The
+//! `entry` line, from `.entry`, gives the start address as a relocation would.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use crate::expr::Expr;
+use crate::lexer;
+use crate::source::SourceRef;
+
+/// The first line of every object.
+pub const MAGIC: &str = "orange-object 1";
+
+/// Section of undefined `.extern` symbols.
+pub const UNDEFINED_SECTION: &str = "*UND*";
+
+/// The field of a word a relocation fills in with the address of its symbol plus its addend.
+#[derive(Debug, Clone, Copy, PartialEq, EnumString, Display)]
+pub enum RelocKind {
+    /// The 22-bit c1 field, relative to the address after the instruction.
+    #[strum(serialize = "c1")]
+    C1,
+    /// The 17-bit signed c2 field.
+    #[strum(serialize = "c2")]
+    C2,
+    /// The whole word, as written by `.word`.
+    #[strum(serialize = "word")]
+    Word,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relocation {
+    pub offset: usize,
+    pub kind: RelocKind,
+    /// A section of the object, `*ABS*` for an absolute address, or an external symbol.
+    pub symbol: String,
+    pub addend: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, EnumString, Display)]
+pub enum Binding {
+    /// Only known inside the object; kept for maps and listings.
+    #[strum(serialize = "local")]
+    Local,
+    /// Declared with `.global`, so other objects may refer to it.
+    #[strum(serialize = "global")]
+    Global,
+    /// Declared with `.extern` and defined by another object.
+    #[strum(serialize = "extern")]
+    Extern,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectSymbol {
+    pub name: String,
+    pub binding: Binding,
+    /// The section the value is an offset into, `*ABS*` or `*UND*`.
+    pub section: String,
+    pub value: usize,
+    pub source: Option<SourceRef>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Section {
+    pub name: String,
+    pub size: usize,
+    /// Every emitted word, by offset from the start of the section.
+    pub words: BTreeMap<usize, u32>,
+    pub relocations: Vec<Relocation>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Object {
+    pub sections: Vec<Section>,
+    pub symbols: Vec<ObjectSymbol>,
+    /// The start address, as a section, `*ABS*` or external symbol and an addend.
+    pub entry: Option<(String, i64)>,
+}
+
+impl Object {
+    pub fn write(&self) -> String {
+        let mut s = format!("{}\n", MAGIC);
+        for section in &self.sections {
+            s.push_str(&format!("section {} {:08x}\n", section.name, section.size));
+            for (offset, word) in &section.words {
+                s.push_str(&format!("word {:08x} {:08x}\n", offset, word));
+            }
+            for reloc in &section.relocations {
+                s.push_str(&format!("reloc {:08x} {} {} {}\n", reloc.offset, reloc.kind, reloc.symbol, reloc.addend));
+            }
+        }
+        for symbol in &self.symbols {
+            s.push_str(&format!("symbol {} {} {:08x} {}", symbol.binding, symbol.section, symbol.value, symbol.name));
+            if let Some(x) = &symbol.source {
+                s.push_str(&format!(" {}", x));
+            }
+            s.push('\n');
+        }
+        if let Some((symbol, addend)) = &self.entry {
+            s.push_str(&format!("entry {} {}\n", symbol, addend));
+        }
+        s
+    }
+
+    pub fn read(text: &str) -> Result<Object, Box<dyn Error>> {
+        let mut lines = text.lines().enumerate();
+        match lines.next() {
+            Some((_, x)) if x.trim() == MAGIC => (),
+            _ => bail!(format!("Not an object (expected \"{}\" first)", MAGIC)),
+        }
+        let mut object = Object::default();
+        for (index, line) in lines {
+            if let Err(e) = object.read_line(line) {
+                bail!(format!("line {}: {}", index + 1, e));
+            }
+        }
+        Ok(object)
+    }
+
+    fn read_line(&mut self, line: &str) -> Result<(), Box<dyn Error>> {
+        let (fields, rest) = lexer::split_fields(line, 5);
+        match fields.as_slice() {
+            [] => (),
+            _ if !rest.is_empty() && fields[0] != "symbol" => bail!(format!("Could not interpret \"{}\"", line.trim())),
+            ["section", name, size] => self.sections.push(Section {
+                name: name.to_string(),
+                size: parse_hex(size)?,
+                ..Section::default()
+            }),
+            ["word", offset, word] => {
+                let offset = parse_hex(offset)?;
+                let word = parse_hex(word)? as u32;
+                self.last_section(offset)?.words.insert(offset, word);
+            },
+            ["reloc", offset, kind, symbol, addend] => {
+                let offset = parse_hex(offset)?;
+                let kind = match kind.parse::<RelocKind>() {
+                    Ok(x) => x,
+                    Err(_) => bail!(format!("Unknown relocation \"{}\"", kind)),
+                };
+                let addend = match addend.parse::<i64>() {
+                    Ok(x) => x,
+                    Err(_) => bail!(format!("Invalid addend \"{}\"", addend)),
+                };
+                self.last_section(offset)?.relocations.push(Relocation { offset, kind, symbol: symbol.to_string(), addend });
+            },
+            ["entry", symbol, addend] => {
+                if self.entry.is_some() {
+                    bail!("Entry point given more than once");
+                }
+                let addend = match addend.parse::<i64>() {
+                    Ok(x) => x,
+                    Err(_) => bail!(format!("Invalid addend \"{}\"", addend)),
+                };
+                self.entry = Some((symbol.to_string(), addend));
+            },
+            ["symbol", binding, section, value, name] => {
+                let binding = match binding.parse::<Binding>() {
+                    Ok(x) => x,
+                    Err(_) => bail!(format!("Unknown binding \"{}\"", binding)),
+                };
+                self.symbols.push(ObjectSymbol {
+                    name: name.to_string(),
+                    binding,
+                    section: section.to_string(),
+                    value: parse_hex(value)?,
+                    source: match rest {
+                        "" => None,
+                        x => Some(SourceRef::parse(x)?),
+                    },
+                });
+            },
+            _ => bail!(format!("Could not interpret \"{}\"", line.trim())),
+        }
+        Ok(())
+    }
+
+    /// The section being read, checking that `offset` lies within it.
+    fn last_section(&mut self, offset: usize) -> Result<&mut Section, Box<dyn Error>> {
+        match self.sections.last_mut() {
+            Some(x) if offset.is_multiple_of(4) && offset + 4 <= x.size => Ok(x),
+            Some(x) => bail!(format!("Offset {:08x} is outside section {}", offset, x.name)),
+            None => bail!("Expected a section first"),
+        }
+    }
+}
+
+fn parse_hex(text: &str) -> Result<usize, Box<dyn Error>> {
+    match usize::from_str_radix(text, 16) {
+        Ok(x) if x < 1 << 32 => Ok(x),
+        _ => bail!(format!("Could not parse \"{}\" as a hexadecimal word", text)),
+    }
+}
+
+/// Gives the base and offset of a symbol when assembling an object: a section and the offset
+/// of a label in it, an external symbol with offset 0, or no base for absolute values.
+pub type Lookup<'a> = dyn Fn(&str) -> Option<(Option<String>, i64)> + 'a;
+
+/// Splits the value of `expr` into an addend and the base it is relative to, if any: a section
+/// for expressions holding labels, or an external symbol. `lookup` gives the base and offset
+/// of each symbol. Differences of labels in one section are absolute; anything else that is
+/// not a base plus a constant, such as `LABEL >> 16`, cannot be relocated.
+pub fn split(expr: &Expr, lookup: &Lookup) -> Result<(i64, Option<String>), Box<dyn Error>> {
+    let mut bases: Vec<String> = Vec::new();
+    for symbol in expr.symbols() {
+        match lookup(symbol) {
+            Some((Some(base), _)) if !bases.contains(&base) => bases.push(base),
+            Some(_) => (),
+            None => bail!(format!("Undefined symbol \"{}\"", symbol)),
+        }
+    }
+
+    // Evaluate with each base placed at 0, then moved twice in turn: the value must move with
+    // the base by exactly the same distance for a relocation to describe it. The distance has
+    // bits set throughout so that masks and shifts change it.
+    const DISTANCE: i64 = 0x1234_5678_9abc;
+    let eval = |moved: Option<&str>, distance: i64| expr.eval(&|s| lookup(s).map(|(base, x)| match base {
+        Some(base) if Some(base.as_str()) == moved => x + distance,
+        _ => x,
+    }));
+    let addend = eval(None, 0)?;
+    let mut relative = None;
+    for base in bases {
+        let once = eval(Some(&base), DISTANCE)? - addend;
+        let twice = eval(Some(&base), 2 * DISTANCE)? - addend;
+        match (once, twice) {
+            (0, 0) => (),
+            (DISTANCE, x) if x == 2 * DISTANCE && relative.is_none() => relative = Some(base),
+            _ => bail!(format!("Expression \"{}\" cannot be relocated", expr)),
+        }
+    }
+    Ok((addend, relative))
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn split_test() {
+        let lookup = |s: &str| match s {
+            "START" => Some((Some(String::from(".text")), 0)),
+            "END" => Some((Some(String::from(".text")), 0x20)),
+            "TX" => Some((Some(String::from("TX")), 0)),
+            "SIZE" => Some((None, 8)),
+            _ => None,
+        };
+        let tests = [
+            ("SIZE * 2", 16, None),
+            ("END + 4", 0x24, Some(".text")),
+            ("SIZE + END - 4", 0x24, Some(".text")),
+            ("END - START", 0x20, None),
+            ("TX", 0, Some("TX")),
+            ("TX - 8", -8, Some("TX")),
+            ("(END - START) / 4 + TX", 8, Some("TX")),
+        ];
+        for test in &tests {
+            let result = split(&Expr::parse(test.0).unwrap(), &lookup).unwrap();
+            assert_eq!(result, (test.1, test.2.map(String::from)), "failed with [{}]", test.0);
+        }
+
+        let invalid_tests = [
+            "END >> 16",
+            "END & 0xFFFF",
+            "END + TX",
+            "START + END",
+            "2 * TX",
+            "MISSING",
+        ];
+        for test in &invalid_tests {
+            let result = split(&Expr::parse(test).unwrap(), &lookup);
+            assert!(result.is_err(), "failed with [{}]", test);
+        }
+    }
+
+    #[test]
+    fn write_read_test() {
+        let object = Object {
+            sections: vec![Section {
+                name: String::from(".text"),
+                size: 12,
+                words: vec![(0, 0x28400008), (8, 0)].into_iter().collect(),
+                relocations: vec![
+                    Relocation { offset: 4, kind: RelocKind::C1, symbol: String::from("TX"), addend: 0 },
+                    Relocation { offset: 8, kind: RelocKind::Word, symbol: String::from(".text"), addend: -4 },
+                ],
+            }],
+            symbols: vec![
+                ObjectSymbol {
+                    name: String::from("START"),
+                    binding: Binding::Global,
+                    section: String::from(".text"),
+                    value: 0,
+                    source: Some(SourceRef { file: PathBuf::from("my dir/main.asm"), line: 4 }),
+                },
+                ObjectSymbol {
+                    name: String::from("TX"),
+                    binding: Binding::Extern,
+                    section: String::from(UNDEFINED_SECTION),
+                    value: 0,
+                    source: None,
+                },
+            ],
+            entry: Some((String::from(".text"), 4)),
+        };
+        let text = object.write();
+        assert_eq!(text, "\
+orange-object 1
+section .text 0000000c
+word 00000000 28400008
+word 00000008 00000000
+reloc 00000004 c1 TX 0
+reloc 00000008 word .text -4
+symbol global .text 00000000 START my dir/main.asm:4
+symbol extern *UND* 00000000 TX
+entry .text 4
+");
+        assert_eq!(Object::read(&text).unwrap(), object);
+
+        let invalid_tests = [
+            "section .text 0000000c",
+            "orange-object 1\nword 00000000 00000000",
+            "orange-object 1\nsection .text 00000004\nword 00000004 00000000",
+            "orange-object 1\nsection .text 00000008\nreloc 00000000 c3 TX 0",
+            "orange-object 1\nsection .text 00000008\nreloc 00000000 c1 TX 0x4",
+            "orange-object 1\nsymbol weak .text 00000000 START",
+            "orange-object 1\nsymbol global .text 00000000",
+            "orange-object 1\nsymbol global .text 00000000 START main.asm",
+            "orange-object 1\nsection .text 00000004 extra",
+            "orange-object 1\nentry .text",
+            "orange-object 1\nentry .text 0\nentry .text 4",
+        ];
+        for test in &invalid_tests {
+            assert!(Object::read(test).is_err(), "failed with [{}]", test);
+        }
+    }
+}
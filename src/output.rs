@@ -0,0 +1,190 @@
+use std::collections::BTreeMap;
+
+use crate::elf;
+use crate::text;
+
+/// How assembled words are written out. Words are stored big-endian in the byte formats.
+#[derive(Debug, Clone, Copy, PartialEq, Default, EnumString)]
+pub enum OutputFormat {
+    /// A header giving the start address, size and checksum, then one `address<TAB>word` line
+    /// per word in hexadecimal; see `text`.
+    #[strum(serialize = "text")]
+    Text,
+    /// The text layout existing loaders read, and the default: a `00000000` line in place of
+    /// the header, with assembled words in source order.
+    #[default]
+    #[strum(serialize = "text-compat")]
+    TextCompat,
+    /// The bytes from the lowest to the highest address written, with gaps filled with zeros.
+    #[strum(serialize = "bin")]
+    Binary,
+    /// Intel HEX, using extended linear address records for addresses above 64K.
+    #[strum(serialize = "ihex")]
+    IntelHex,
+    /// Motorola S-record with 32-bit addresses (S3 and S7 records).
+    #[strum(serialize = "srec")]
+    SRecord,
+    /// An ELF32 executable; see `elf`.
+    #[strum(serialize = "elf")]
+    Elf,
+}
+
+impl OutputFormat {
+    /// The extension of output files written without `-o`. Text output keeps the `.bin`
+    /// extension it has always had.
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Text | OutputFormat::TextCompat => "bin",
+            OutputFormat::Binary => "img",
+            OutputFormat::IntelHex => "hex",
+            OutputFormat::SRecord => "srec",
+            OutputFormat::Elf => "elf",
+        }
+    }
+}
+
+/// Bytes per Intel HEX or S-record data record.
+const RECORD_SIZE: usize = 16;
+
+/// Writes `words` in `format`. The formats with a start address record hold `entry`. Text
+/// files are written without sources and ELF files without symbols; see `text::write` and
+/// `elf::write` for those.
+pub fn write(words: &BTreeMap<usize, u32>, entry: Option<usize>, format: OutputFormat) -> Vec<u8> {
+    match format {
+        OutputFormat::Text => text::write(words, entry, &[]).into_bytes(),
+        OutputFormat::TextCompat => {
+            let words: Vec<(usize, u32)> = words.iter().map(|(a, w)| (*a, *w)).collect();
+            text::write_compat(&words).into_bytes()
+        },
+        OutputFormat::Binary => write_binary(words),
+        OutputFormat::IntelHex => write_intel_hex(words, entry).into_bytes(),
+        OutputFormat::SRecord => write_srecord(words, entry).into_bytes(),
+        OutputFormat::Elf => elf::write(&elf::Image { words, entry, symbols: &[], globals: &[], sections: &[], lines: None }),
+    }
+}
+
+fn write_binary(words: &BTreeMap<usize, u32>) -> Vec<u8> {
+    let start = match words.keys().next() {
+        Some(x) => *x,
+        None => return Vec::new(),
+    };
+    let mut bytes = Vec::new();
+    for (address, word) in words {
+        bytes.resize(address - start, 0);
+        bytes.extend_from_slice(&word.to_be_bytes());
+    }
+    bytes
+}
+
+/// Splits the words into runs of at most `RECORD_SIZE` consecutive bytes that do not cross
+/// a 64K boundary, as `(address, bytes)`.
+fn records(words: &BTreeMap<usize, u32>) -> Vec<(usize, Vec<u8>)> {
+    let mut records: Vec<(usize, Vec<u8>)> = Vec::new();
+    for (address, word) in words {
+        match records.last_mut() {
+            Some((start, bytes)) if *start + bytes.len() == *address
+                && bytes.len() < RECORD_SIZE && *start >> 16 == address >> 16
+                => bytes.extend_from_slice(&word.to_be_bytes()),
+            _ => records.push((*address, word.to_be_bytes().to_vec())),
+        }
+    }
+    records
+}
+
+fn sum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, x| sum.wrapping_add(*x))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02X}", x)).collect()
+}
+
+fn intel_hex_record(kind: u8, address: u16, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend_from_slice(&address.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend_from_slice(data);
+    format!(":{}{:02X}\n", hex(&bytes), sum(&bytes).wrapping_neg())
+}
+
+fn write_intel_hex(words: &BTreeMap<usize, u32>, entry: Option<usize>) -> String {
+    let mut s = String::new();
+    let mut segment = 0;
+    for (address, data) in records(words) {
+        if address >> 16 != segment {
+            segment = address >> 16;
+            s.push_str(&intel_hex_record(4, 0, &(segment as u16).to_be_bytes()));
+        }
+        s.push_str(&intel_hex_record(0, address as u16, &data));
+    }
+    if let Some(x) = entry {
+        s.push_str(&intel_hex_record(5, 0, &(x as u32).to_be_bytes()));
+    }
+    s.push_str(&intel_hex_record(1, 0, &[]));
+    s
+}
+
+fn srecord(kind: char, address: &[u8], data: &[u8]) -> String {
+    let mut bytes = vec![(address.len() + data.len() + 1) as u8];
+    bytes.extend_from_slice(address);
+    bytes.extend_from_slice(data);
+    format!("S{}{}{:02X}\n", kind, hex(&bytes), !sum(&bytes))
+}
+
+fn write_srecord(words: &BTreeMap<usize, u32>, entry: Option<usize>) -> String {
+    let mut s = srecord('0', &[0, 0], &[]);
+    for (address, data) in records(words) {
+        s.push_str(&srecord('3', &(address as u32).to_be_bytes(), &data));
+    }
+    s.push_str(&srecord('7', &(entry.unwrap_or(0) as u32).to_be_bytes(), &[]));
+    s
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn words(pairs: &[(usize, u32)]) -> BTreeMap<usize, u32> {
+        pairs.iter().cloned().collect()
+    }
+
+    #[test]
+    fn write_test() {
+        let program = words(&[(0, 0x60443000), (4, 0xf8000000), (12, 0x12345678)]);
+        assert_eq!(write(&program, None, OutputFormat::TextCompat),
+            b"00000000\n00000000\t60443000\n00000004\tf8000000\n0000000c\t12345678\n".to_vec());
+        let result = String::from_utf8(write(&program, Some(4), OutputFormat::Text)).unwrap();
+        assert_eq!(text::read(&result).unwrap().words, program);
+        assert!(result.starts_with("orange-text 1\nentry 00000004\nwords 3\n"));
+        assert_eq!(write(&program, None, OutputFormat::Binary), vec![
+            0x60, 0x44, 0x30, 0x00, 0xf8, 0, 0, 0, 0, 0, 0, 0, 0x12, 0x34, 0x56, 0x78,
+        ]);
+        assert_eq!(write(&words(&[(8, 1)]), None, OutputFormat::Binary), vec![0, 0, 0, 1]);
+        assert_eq!(write(&BTreeMap::new(), None, OutputFormat::Binary), Vec::<u8>::new());
+
+        assert_eq!(String::from_utf8(write(&program, None, OutputFormat::IntelHex)).unwrap(),
+            ":0800000060443000F80000002C\n:04000C0012345678DC\n:00000001FF\n");
+        assert_eq!(String::from_utf8(write(&words(&[(0x1_0000, 0xf8000000)]), Some(0x1_0000), OutputFormat::IntelHex)).unwrap(),
+            ":020000040001F9\n:04000000F800000004\n:0400000500010000F6\n:00000001FF\n");
+
+        assert_eq!(String::from_utf8(write(&words(&[(0, 0xf8000000), (4, 0)]), Some(4), OutputFormat::SRecord)).unwrap(),
+            "S0030000FC\nS30D00000000F800000000000000FA\nS70500000004F6\n");
+    }
+
+    #[test]
+    fn records_test() {
+        let program: BTreeMap<usize, u32> = (0..6).map(|i| (0xfff8 + 4 * i, i as u32)).collect();
+        let result: Vec<(usize, usize)> = records(&program).iter().map(|(a, b)| (*a, b.len())).collect();
+        assert_eq!(result, vec![(0xfff8, 8), (0x10000, 16)]);
+    }
+
+    #[test]
+    fn output_format_test() {
+        assert_eq!("ihex".parse::<OutputFormat>().unwrap(), OutputFormat::IntelHex);
+        assert_eq!("srec".parse::<OutputFormat>().unwrap(), OutputFormat::SRecord);
+        assert_eq!("elf".parse::<OutputFormat>().unwrap(), OutputFormat::Elf);
+        assert_eq!("text-compat".parse::<OutputFormat>().unwrap(), OutputFormat::TextCompat);
+        assert!("coff".parse::<OutputFormat>().is_err());
+    }
+}
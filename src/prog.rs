@@ -1,69 +1,888 @@
 use std::error::Error;
+use crate::checksum;
+use crate::cond;
+use crate::expr::Expr;
 use crate::inst;
-use std::collections::HashMap;
+use crate::macros;
+use crate::archive::Member;
+use crate::object::{self, Binding, Object, ObjectSymbol, Section, UNDEFINED_SECTION};
+use crate::source::{self, Loc, Source, SourceRef};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::PathBuf;
 
-pub struct Prog<'a> {
-    pub name: &'a str,
-    lines: Vec<(usize, inst::InstLine<'a>)>,
-    symbol_map: HashMap<&'a str, usize>,
+/// First address of the memory-mapped I/O registers (TX_BUSY, TX_DATA, ...).
+pub const IO_WINDOW_START: usize = 0xFFFF_FFE0;
+
+/// The end of the 32-bit address space, which no word may reach past.
+pub const ADDRESS_SPACE_END: usize = 1 << 32;
+
+/// How many lines either side of a `jmp`, `jz`, `jnz` or `call` are checked for explicit
+/// use of the scratch register it overwrites.
+pub const SCRATCH_WINDOW: usize = 8;
+
+/// The section code goes in until another is chosen.
+pub const TEXT_SECTION: &str = ".text";
+
+pub const DATA_SECTION: &str = ".data";
+
+/// The section of zeroed data, which may reserve space but not hold words.
+pub const BSS_SECTION: &str = ".bss";
+
+/// How many times the program is laid out before giving up on the addresses of sections
+/// that follow others settling.
+pub const MAX_PASSES: usize = 4;
+
+/// Section of symbols that are not addresses: `.equ`, `.reg` and defines.
+pub const ABSOLUTE_SECTION: &str = "*ABS*";
+
+pub struct Options {
+    pub warn_io_window: bool,
+    /// Symbols defined before the first line, as with `-D NAME=value`.
+    pub defines: Vec<(String, i64)>,
+    /// Register branch pseudo-instructions load their target into, until changed by `.scratch`.
+    pub scratch_register: usize,
+    /// Symbols and register aliases defined elsewhere, such as the routines of a resident
+    /// monitor. A label, `.equ` or `.reg` of the program replaces an import of the same name.
+    pub imports: Vec<Symbol>,
+    /// Addresses of sections, as a linker script gives them. Other sections follow these in
+    /// the order first used, as `orange-ld` places them, and `.text` starts at 0.
+    pub sections: Vec<(String, usize)>,
+}
+
+impl Options {
+    /// The defines and imported symbols, which conditions guarding `.include` and `.macro`
+    /// can test before the program is laid out; see `cond::Early`.
+    pub fn known_symbols(&self) -> Vec<(String, i64)> {
+        let imports = self.imports.iter()
+            .filter(|x| x.kind != SymbolKind::Reg)
+            .map(|x| (x.name.clone(), x.value as i64));
+        self.defines.iter().cloned().chain(imports).collect()
+    }
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            warn_io_window: true,
+            defines: Vec::new(),
+            scratch_register: inst::SCRATCH_REGISTER,
+            imports: Vec::new(),
+            sections: Vec::new(),
+        }
+    }
+}
+
+/// A contiguous range of emitted words, `start..end`, first produced by the line at `loc`.
+#[derive(Debug, PartialEq)]
+pub struct Region {
+    pub start: usize,
+    pub end: usize,
+    pub loc: Loc,
+}
+
+/// A section of the program: where it was placed and how many bytes it spans.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProgSection {
+    pub name: String,
+    pub address: usize,
+    pub size: usize,
 }
 
-impl<'a> Prog<'a> {
-    pub fn new (name: &'a str, contents: &'a str) -> Result<Prog<'a>, Box<dyn Error>> {
-        let source_lines = contents.lines();
+/// An encoded word, with the line it was assembled from.
+#[derive(Debug, PartialEq)]
+pub struct Word {
+    pub address: usize,
+    pub value: u32,
+    pub loc: Loc,
+}
+
+/// What defined a symbol.
+#[derive(Debug, Clone, Copy, PartialEq, EnumString, Display)]
+pub enum SymbolKind {
+    #[strum(serialize = "label")]
+    Label,
+    #[strum(serialize = "equ")]
+    Equ,
+    /// A `NAME .reg rN` register alias, whose value is the register number.
+    #[strum(serialize = "reg")]
+    Reg,
+    /// A `-D` define.
+    #[strum(serialize = "define")]
+    Define,
+}
+
+/// A named value and where it came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub value: usize,
+    pub kind: SymbolKind,
+    pub section: String,
+    /// The line defining the symbol; `None` for defines.
+    pub source: Option<SourceRef>,
+}
+
+impl Symbol {
+    /// A symbol of any kind but a label, which is not an address.
+    fn new(name: &str, value: usize, kind: SymbolKind, source: Option<SourceRef>) -> Symbol {
+        Symbol { name: name.to_string(), value, kind, section: ABSOLUTE_SECTION.to_string(), source }
+    }
+
+    fn label(name: &str, value: usize, section: &str, source: SourceRef) -> Symbol {
+        Symbol { section: section.to_string(), ..Symbol::new(name, value, SymbolKind::Label, Some(source)) }
+    }
+}
+
+pub struct Prog {
+    pub source: Source,
+    lines: Vec<Line>,
+    symbol_map: HashMap<String, usize>,
+    /// Every symbol and register alias in the order defined, imports first.
+    definitions: Vec<Symbol>,
+    /// The section of each label, by stored name.
+    labels: HashMap<String, String>,
+    imported: HashSet<String>,
+    /// Names declared with `.global` and `.extern`, in order.
+    globals: Vec<String>,
+    externs: Vec<String>,
+    /// The start address given by `.entry`, and where.
+    entry: Option<(String, Loc)>,
+    pub regions: Vec<Region>,
+    /// Every section used, `.text` first and then in the order first used.
+    pub sections: Vec<ProgSection>,
+    pub warnings: Vec<String>,
+}
+
+/// A parsed line placed at `pc`; `index` refers to `Prog::source.lines` and `section` to
+/// `Prog::sections`.
+struct Line {
+    pc: usize,
+    index: usize,
+    section: usize,
+    inst_line: inst::InstLine,
+}
+
+impl Prog {
+    pub fn new (name: &str, contents: &str) -> Result<Prog, Box<dyn Error>> {
+        Prog::with_options(name, contents, &Options::default())
+    }
+
+    pub fn with_options (name: &str, contents: &str, options: &Options) -> Result<Prog, Box<dyn Error>> {
+        let sources = [(PathBuf::from(name), contents.to_string())];
+        let source = Source::from_sources(&sources, &[], &HashMap::new(), &options.known_symbols())?;
+        Prog::from_source(source, options)
+    }
+
+    pub fn from_source (mut source: Source, options: &Options) -> Result<Prog, Box<dyn Error>> {
+        source.lines = macros::expand(&source, &options.known_symbols())?;
+
+        // A section that follows another can only be placed once the size of that one is
+        // known, so lay the program out again until every section stays where it is.
+        let addresses = options.sections.iter().cloned().collect();
+        let mut prog = Prog::lay_out(source, options, &addresses)?;
+        for _ in 0..MAX_PASSES {
+            let addresses = place_sections(&options.sections, &prog.sections);
+            if prog.sections.iter().all(|x| addresses[&x.name] == x.address) {
+                return prog.check(options);
+            }
+            prog = Prog::lay_out(prog.source, options, &addresses)?;
+        }
+        bail!(format!("Section addresses did not settle after {} passes; place them with a linker script", MAX_PASSES + 1))
+    }
+
+    /// Assembles every line, placing each section at its address in `addresses` or else 0.
+    fn lay_out (source: Source, options: &Options, addresses: &HashMap<String, usize>) -> Result<Prog, Box<dyn Error>> {
         let mut lines = Vec::new();
 
         let mut symbol_map = HashMap::new();
-        let mut loc_counter = 0;
-
-        for line in source_lines {
-            match inst::InstLine::new(&line)? {
-                Some(inst_line) => {
-                    let loc_counter_temp = match inst_line.offset {
-                        inst::Offset::Relative(x) => loc_counter + x,
-                        inst::Offset::Absolute(x) => x,
-                    };
-                    match inst_line.label {
-                        Some(label) => match symbol_map.insert(label, loc_counter) {
-                            Some(_) => bail!("Encountered duplicate symbol"),
-                            None => (),
-                        },
-                        None => (),
+        let address = |name: &str| addresses.get(name).copied().unwrap_or(0);
+        let mut sections = vec![ProgSection { name: TEXT_SECTION.to_string(), address: address(TEXT_SECTION), size: 0 }];
+        let mut counters = vec![sections[0].address];
+        let mut current = 0;
+        let mut loc_counter = counters[0];
+        let mut regions: Vec<Region> = Vec::new();
+        let mut conditions = cond::Conditions::default();
+        let mut context = inst::Context::default();
+        context.scratch = inst::process_scratch(&format!("r{}", options.scratch_register))?;
+
+        let mut definitions = Vec::new();
+        let mut labels = HashMap::new();
+        let mut imported = HashSet::new();
+        let mut globals: Vec<(String, Loc)> = Vec::new();
+        let mut externs = Vec::new();
+        let mut entry: Option<(String, Loc)> = None;
+        for symbol in &options.imports {
+            let defined = match symbol.kind {
+                SymbolKind::Reg => context.aliases.insert(symbol.name.clone(), symbol.value).is_some()
+                    && !inst::BUILTIN_ALIASES.iter().any(|x| x.0 == symbol.name),
+                _ => symbol_map.insert(symbol.name.clone(), symbol.value).is_some(),
+            };
+            if defined || !imported.insert(symbol.name.clone()) {
+                bail!(format!("Imported symbol \"{}\" defined more than once", symbol.name));
+            }
+            definitions.push(symbol.clone());
+        }
+
+        for (name, value) in &options.defines {
+            if symbol_map.insert(name.clone(), *value as usize).is_some() {
+                bail!(format!("Symbol \"{}\" defined more than once", name));
+            }
+            if context.aliases.contains_key(name) {
+                bail!(format!("Symbol \"{}\" is already a register alias", name));
+            }
+            definitions.push(Symbol::new(name, *value as usize, SymbolKind::Define, None));
+        }
+
+        for (index, source_line) in source.lines.iter().enumerate() {
+            let directive = source::split_directive(&source_line.text);
+            if let Some((directive, arg)) = directive {
+                let lookup = |s: &str| symbol_map.get(s).map(|x| *x as i64);
+                match conditions.process(directive, arg, source_line.loc, &lookup) {
+                    Ok(true) => continue,
+                    Ok(false) => (),
+                    Err(e) => bail!(format!("{}: {}", source.describe_line(source_line), e)),
+                }
+            }
+            if !conditions.active() {
+                continue;
+            }
+            if let Some((".equ", arg)) = directive {
+                let lookup = |s: &str| symbol_map.get(s).map(|x| *x as i64);
+                let (name, value) = match process_equ(arg, &lookup) {
+                    Ok(x) => x,
+                    Err(e) => bail!(format!("{}: {}", source.describe_line(source_line), e)),
+                };
+                if symbol_map.insert(name.to_string(), value as usize).is_some() && !replace_import(&mut definitions, &mut imported, name) {
+                    bail!(format!("{}: Encountered duplicate symbol \"{}\"", source.describe_line(source_line), name));
+                }
+                if context.aliases.contains_key(name) {
+                    bail!(format!("{}: Symbol \"{}\" is already a register alias", source.describe_line(source_line), name));
+                }
+                definitions.push(Symbol::new(name, value as usize, SymbolKind::Equ, Some(source.source_ref(source_line.loc))));
+                continue;
+            }
+            if let Some((directive @ (".global" | ".extern"), arg)) = directive {
+                let names = match process_names(arg) {
+                    Ok(x) => x,
+                    Err(e) => bail!(format!("{}: {}", source.describe_line(source_line), e)),
+                };
+                for name in names {
+                    match directive {
+                        ".global" => globals.push((name, source_line.loc)),
+                        _ => externs.push(name),
+                    }
+                }
+                continue;
+            }
+            if let Some((".entry", arg)) = directive {
+                match entry {
+                    _ if arg.is_empty() => bail!(format!("{}: Expected an address after .entry", source.describe_line(source_line))),
+                    Some((_, loc)) => bail!(format!("{}: Entry point already declared at {}",
+                        source.describe_line(source_line), source.describe(loc))),
+                    None => entry = Some((arg.to_string(), source_line.loc)),
+                }
+                continue;
+            }
+            if let Some((directive @ (".text" | ".data" | ".bss" | ".section"), arg)) = directive {
+                let name = match process_section(directive, arg) {
+                    Ok(x) => x,
+                    Err(e) => bail!(format!("{}: {}", source.describe_line(source_line), e)),
+                };
+                counters[current] = loc_counter;
+                current = match sections.iter().position(|x| x.name == name) {
+                    Some(x) => x,
+                    None => {
+                        sections.push(ProgSection { name: name.to_string(), address: address(name), size: 0 });
+                        counters.push(address(name));
+                        sections.len() - 1
+                    },
+                };
+                loc_counter = counters[current];
+                continue;
+            }
+            if let Some((".scratch", arg)) = directive {
+                let arg = match context.aliases.get(arg) {
+                    Some(r) => format!("r{}", r),
+                    None => arg.to_string(),
+                };
+                context.scratch = match inst::process_scratch(&arg) {
+                    Ok(x) => x,
+                    Err(e) => bail!(format!("{}: {}", source.describe_line(source_line), e)),
+                };
+                continue;
+            }
+            match inst::process_reg(&source_line.text) {
+                Ok(Some((name, r))) => {
+                    if symbol_map.contains_key(&name) {
+                        bail!(format!("{}: Register alias \"{}\" shadows a label", source.describe_line(source_line), name));
                     }
-                    lines.push((loc_counter, inst_line));
-                    loc_counter = loc_counter_temp;
+                    replace_import(&mut definitions, &mut imported, &name);
+                    definitions.push(Symbol::new(&name, r, SymbolKind::Reg, Some(source.source_ref(source_line.loc))));
+                    context.aliases.insert(name, r);
+                    continue;
                 },
-                None => (),
+                Ok(None) => (),
+                Err(e) => bail!(format!("{}: {}", source.describe_line(source_line), e)),
+            }
+
+            let inst_line = match inst::InstLine::new(&source_line.text, &mut context) {
+                Ok(Some(x)) => x,
+                Ok(None) => continue,
+                Err(e) => bail!(format!("{}: {}", source.describe_line(source_line), e)),
+            };
+            let section = &mut sections[current];
+            let loc_counter_temp = match inst_line.offset {
+                inst::Offset::Relative(x) => match loc_counter.checked_add(x) {
+                    Some(end) if end <= ADDRESS_SPACE_END => {
+                        section.size = section.size.max(end - section.address);
+                        end
+                    },
+                    _ => bail!(format!("{}: Address {:#x} runs past the end of the 32-bit address space",
+                        source.describe_line(source_line), loc_counter)),
+                },
+                inst::Offset::Absolute(x) if x >= ADDRESS_SPACE_END => bail!(format!("{}: Address {:#x} is past the end of the 32-bit address space",
+                    source.describe_line(source_line), x)),
+                inst::Offset::Absolute(x) if x < section.address => bail!(format!("{}: Address {:#x} is before the start of section {} at {:#x}",
+                    source.describe_line(source_line), x, section.name, section.address)),
+                inst::Offset::Absolute(x) => x,
+            };
+            if inst_line.emits_words() && section.name == BSS_SECTION {
+                bail!(format!("{}: Section {} can only reserve space, as with .dw", source.describe_line(source_line), BSS_SECTION));
+            }
+            if let Some(label) = &inst_line.label {
+                if symbol_map.insert(label.clone(), loc_counter).is_some() && !replace_import(&mut definitions, &mut imported, label) {
+                    bail!(format!("{}: Encountered duplicate symbol \"{}\"", source.describe_line(source_line), label));
+                }
+                if context.aliases.contains_key(label) {
+                    bail!(format!("{}: Label \"{}\" is shadowed by a register alias", source.describe_line(source_line), label));
+                }
+                definitions.push(Symbol::label(label, loc_counter, &section.name, source.source_ref(source_line.loc)));
+                labels.insert(label.clone(), section.name.clone());
             }
+            if inst_line.emits_words() {
+                match regions.last_mut() {
+                    Some(region) if region.end == loc_counter => region.end = loc_counter_temp,
+                    _ => regions.push(Region {
+                        start: loc_counter,
+                        end: loc_counter_temp,
+                        loc: source_line.loc,
+                    }),
+                }
+            }
+            lines.push(Line {
+                pc: loc_counter,
+                index,
+                section: current,
+                inst_line,
+            });
+            loc_counter = loc_counter_temp;
+        }
+
+        if let Some(loc) = conditions.unterminated() {
+            bail!(format!("{}: Unterminated .if (missing .endif)", source.describe(loc)));
+        }
+
+        if let Some((name, loc)) = globals.iter().find(|x| !symbol_map.contains_key(&x.0)) {
+            bail!(format!("{}: Global symbol \"{}\" is not defined", source.describe(*loc), name));
         }
-        println!("\n{:?}", symbol_map);
 
         Ok(Prog {
-            name,
+            source,
             lines,
             symbol_map,
+            definitions,
+            labels,
+            imported,
+            globals: globals.into_iter().map(|x| x.0).collect(),
+            externs,
+            entry,
+            regions,
+            sections,
+            warnings: Vec::new(),
         })
     }
 
-    
-    pub fn encode (self: &Self) -> Result<String, Box<dyn Error>> {
+    /// Checks the final layout for overlaps and collects warnings.
+    fn check (mut self, options: &Options) -> Result<Prog, Box<dyn Error>> {
+        check_overlaps(&self.source, &self.regions)?;
+
+        self.warnings = check_scratch(&self.source, &self.lines);
+        if options.warn_io_window {
+            for region in self.regions.iter().filter(|r| r.end > IO_WINDOW_START) {
+                self.warnings.push(format!("{} overlaps the memory-mapped I/O window at {:#010x}",
+                    describe_region(&self.source, region), IO_WINDOW_START));
+            }
+        }
+        Ok(self)
+    }
+
+    pub fn encode (&self) -> Result<String, Box<dyn Error>> {
         let mut s: String = String::from("00000000\n");
+        for word in self.words()? {
+            s.push_str(&format!("{:08x}\t{:08x}\n", word.address, word.value));
+        }
+
+        Ok(s)
+    }
+
+    /// Every word in source order, with the values of `.crc32` and `.checksum` filled in.
+    pub fn words (&self) -> Result<Vec<Word>, Box<dyn Error>> {
+        let mut words = self.encoded_words()?;
+        let checksums = self.checksums(&words)?;
+        for word in &mut words {
+            if let Some((value, _)) = checksums.get(&word.address) {
+                word.value = *value;
+            }
+        }
+
+        Ok(words)
+    }
+
+    /// Every word in source order, as the lines encode them.
+    fn encoded_words (&self) -> Result<Vec<Word>, Box<dyn Error>> {
+        let mut words = Vec::new();
         for line in &self.lines {
-            match line.1.encode_instruction(&self.symbol_map, line.0)? {
-                Some(x) => s.push_str(&format!("{:08x}\t{:08x}\n", line.0, x)),
+            let loc = self.source.lines[line.index].loc;
+            for (i, x) in self.encode_line(line)?.iter().enumerate() {
+                words.push(Word { address: line.pc + 4 * i, value: *x as u32, loc });
+            }
+        }
+
+        Ok(words)
+    }
+
+    /// The value of each `.crc32` and `.checksum` by address, with its listing line. They are
+    /// computed in source order over `words`, so each may cover those before it but not after.
+    fn checksums (&self, words: &[Word]) -> Result<BTreeMap<usize, (u32, String)>, Box<dyn Error>> {
+        let describe = |line: &Line| self.source.describe_line(&self.source.lines[line.index]);
+        let mut ranges = Vec::new();
+        for line in &self.lines {
+            match line.inst_line.checksum_range(&self.symbol_map, line.pc) {
+                Ok(Some(x)) => ranges.push((line, x)),
+                Ok(None) => (),
+                Err(e) => bail!(format!("{}: {}", describe(line), e)),
+            }
+        }
+
+        let mut image: BTreeMap<usize, u32> = words.iter().map(|x| (x.address, x.value)).collect();
+        let mut checksums = BTreeMap::new();
+        for (index, (line, (kind, start, end))) in ranges.iter().enumerate() {
+            match ranges[index..].iter().find(|x| (*start..*end).contains(&x.0.pc)) {
+                Some((x, _)) if x.pc == line.pc => bail!(format!("{}: The range holds the checksum itself", describe(line))),
+                Some((x, _)) => bail!(format!("{}: The range holds the checksum at {:#010x}, which is not known yet", describe(line), x.pc)),
                 None => (),
             }
-            println!("");
+            let value = match checksum::compute(*kind, &image, *start, *end) {
+                Ok(x) => x,
+                Err(e) => bail!(format!("{}: {}", describe(line), e)),
+            };
+            image.insert(line.pc, value);
+            checksums.insert(line.pc, (value, checksum::describe(*kind, *start, *end, line.pc, value)));
+        }
+        Ok(checksums)
+    }
+
+    /// Every label, `.equ` and define, including the names local and numeric labels are
+    /// stored under.
+    pub fn symbols (&self) -> &HashMap<String, usize> {
+        &self.symbol_map
+    }
+
+    /// Every label, `.equ`, `.reg`, define and import in the order defined, including the
+    /// names local and numeric labels are stored under.
+    pub fn definitions (&self) -> &[Symbol] {
+        &self.definitions
+    }
+
+    /// The names declared with `.global`.
+    pub fn globals (&self) -> &[String] {
+        &self.globals
+    }
+
+    /// The start address given by `.entry`, if any.
+    pub fn entry (&self) -> Result<Option<usize>, Box<dyn Error>> {
+        let (text, loc) = match &self.entry {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let lookup = |s: &str| self.symbol_map.get(s).map(|x| *x as i64);
+        match Expr::parse(text).and_then(|e| e.eval(&lookup)).and_then(entry_address) {
+            Ok(x) => Ok(Some(x)),
+            Err(e) => bail!(format!("{}: Invalid entry point \"{}\": {}", self.source.describe(*loc), text, e)),
+        }
+    }
+
+    /// The file and line of `.entry`, if any.
+    pub fn entry_line (&self) -> Option<String> {
+        self.entry.as_ref().map(|(_, loc)| self.source.describe(*loc))
+    }
+
+    /// The program as a relocatable object with a section for each section of the program,
+    /// addressed from its start. Only symbols declared `.extern` may be left undefined, and
+    /// `.org` is only allowed in `.text`, where it gives the offset from the start.
+    pub fn object (&self) -> Result<Object, Box<dyn Error>> {
+        let lines: Vec<&Line> = self.lines.iter().collect();
+        self.object_from(&lines, None)
+    }
+
+    /// The program split into a member per routine, for an archive to hold so that a link
+    /// only takes the routines it uses. Each label in `.text` declared `.global` starts a
+    /// routine, which takes every line up to the next one in any section. Lines placing
+    /// anything before the first routine form a member called `name`, and `.equ` symbols go
+    /// with the first member. Routines may only refer to the global labels of others.
+    pub fn members (&self, name: &str) -> Result<Vec<Member>, Box<dyn Error>> {
+        let mut members: Vec<(String, Vec<&Line>)> = Vec::new();
+        for line in &self.lines {
+            let inst_line = &line.inst_line;
+            match &inst_line.label {
+                Some(label) if line.section == 0 && self.globals.contains(label) => members.push((label.clone(), Vec::new())),
+                None if members.is_empty() && !inst_line.emits_words() && inst_line.offset == inst::Offset::Relative(0) => continue,
+                _ if members.is_empty() => members.push((name.to_string(), Vec::new())),
+                _ => (),
+            }
+            if let Some(x) = members.last_mut() {
+                x.1.push(line);
+            }
+        }
+
+        let mut result = Vec::new();
+        for (index, (name, lines)) in members.into_iter().enumerate() {
+            result.push(Member { name, object: self.object_from(&lines, Some(index))? });
+        }
+        Ok(result)
+    }
+
+    /// Builds an object from `lines`: the whole program, or the member numbered `member`.
+    /// A member's part of each section starts at its first line there, and it refers to
+    /// labels of other members by name.
+    fn object_from (&self, lines: &[&Line], member: Option<usize>) -> Result<Object, Box<dyn Error>> {
+        let describe = |line: &Line| self.source.describe_line(&self.source.lines[line.index]);
+
+        // The start and size of the part of each section the lines cover, and their labels.
+        let mut parts: Vec<Option<(usize, usize)>> = match member {
+            Some(_) => vec![None; self.sections.len()],
+            None => self.sections.iter().map(|x| Some((x.address, x.size))).collect(),
+        };
+        let mut owned = HashMap::new();
+        for line in lines {
+            if line.section > 0 && matches!(line.inst_line.offset, inst::Offset::Absolute(_)) {
+                bail!(format!("{}: .org cannot place data in section {} before linking",
+                    describe(line), self.sections[line.section].name));
+            }
+            let part = parts[line.section].get_or_insert((line.pc, 0));
+            if let (Some(_), inst::Offset::Relative(x)) = (member, &line.inst_line.offset) {
+                part.1 = part.1.max(line.pc + x - part.0);
+            }
+            if let Some(label) = &line.inst_line.label {
+                owned.insert(label.as_str(), line.section);
+            }
+        }
+        let start = |section: usize| parts[section].map_or(0, |x| x.0);
+
+        let lookup = |s: &str| match self.symbol_map.get(s) {
+            Some(x) => match (self.labels.contains_key(s), owned.get(s)) {
+                (true, Some(i)) => Some((Some(self.sections[*i].name.clone()), (*x - start(*i)) as i64)),
+                (true, None) => Some((Some(s.to_string()), 0)),
+                (false, _) => Some((None, *x as i64)),
+            },
+            None if self.externs.iter().any(|x| x == s) => Some((Some(s.to_string()), 0)),
+            None => None,
+        };
+
+        let mut sections: Vec<Option<Section>> = self.sections.iter().zip(&parts)
+            .map(|(x, part)| part.map(|(_, size)| Section { name: x.name.clone(), size, ..Section::default() }))
+            .collect();
+        let mut referenced: Vec<String> = Vec::new();
+        for line in lines {
+            let offset = match line.pc.checked_sub(start(line.section)) {
+                Some(x) => x,
+                None => bail!(format!("{}: .org moves before the start of the routine", describe(line))),
+            };
+            let section = match &mut sections[line.section] {
+                Some(x) => x,
+                None => continue,
+            };
+            let words = match line.inst_line.encode_relocatable(&lookup, &section.name, offset) {
+                Ok(x) => x,
+                Err(e) => bail!(format!("{}: {}", describe(line), e)),
+            };
+            for (i, (word, relocation)) in words.into_iter().enumerate() {
+                section.words.insert(offset + 4 * i, word as u32);
+                if let Some(x) = relocation {
+                    if self.labels.contains_key(&x.symbol) && !self.globals.contains(&x.symbol) {
+                        bail!(format!("{}: Label \"{}\" belongs to another routine; declare it .global to use it here",
+                            describe(line), x.symbol));
+                    }
+                    if lookup(&x.symbol).is_some() && !referenced.contains(&x.symbol) {
+                        referenced.push(x.symbol.clone());
+                    }
+                    section.relocations.push(x);
+                }
+            }
+        }
+
+        let mut symbols = Vec::new();
+        for symbol in &self.definitions {
+            if symbol.kind == SymbolKind::Reg || symbol.name.starts_with('.') || self.imported.contains(&symbol.name) {
+                continue;
+            }
+            let value = match (symbol.kind, owned.get(symbol.name.as_str())) {
+                (SymbolKind::Label, Some(i)) => symbol.value - start(*i),
+                (SymbolKind::Label, None) => continue,
+                _ if member.unwrap_or(0) == 0 => symbol.value % (1 << 32),
+                _ => continue,
+            };
+            symbols.push(ObjectSymbol {
+                name: symbol.name.clone(),
+                binding: match self.globals.contains(&symbol.name) {
+                    true => Binding::Global,
+                    false => Binding::Local,
+                },
+                section: symbol.section.clone(),
+                value,
+                source: symbol.source.clone(),
+            });
+        }
+        // The entry point goes with the member holding it, or the first when it is absolute or
+        // external.
+        let entry = match &self.entry {
+            Some((text, loc)) => match Expr::parse(text).and_then(|e| object::split(&e, &lookup)).and_then(|x| match x {
+                (addend, None) => entry_address(addend).map(|_| (addend, None)),
+                x => Ok(x),
+            }) {
+                Ok((addend, base)) => {
+                    let include = match (&base, member) {
+                        (_, None) => true,
+                        (Some(x), Some(_)) if self.sections.iter().any(|s| &s.name == x) => true,
+                        (Some(x), Some(_)) if self.labels.contains_key(x) => false,
+                        (_, Some(x)) => x == 0,
+                    };
+                    match include {
+                        true => Some((base.unwrap_or_else(|| ABSOLUTE_SECTION.to_string()), addend)),
+                        false => None,
+                    }
+                },
+                Err(e) => bail!(format!("{}: Invalid entry point \"{}\": {}", self.source.describe(*loc), text, e)),
+            },
+            None => None,
+        };
+
+        // A member needs whatever it refers to by name, while the whole program keeps every
+        // `.extern` it declares.
+        let externs = match member {
+            Some(_) => referenced,
+            None => self.externs.iter().filter(|x| !self.symbol_map.contains_key(*x)).cloned().collect(),
+        };
+        for name in externs {
+            if !symbols.iter().any(|x| x.name == name) {
+                symbols.push(ObjectSymbol {
+                    name,
+                    binding: Binding::Extern,
+                    section: UNDEFINED_SECTION.to_string(),
+                    value: 0,
+                    source: None,
+                });
+            }
         }
 
+        Ok(Object { sections: sections.into_iter().flatten().collect(), symbols, entry })
+    }
+
+    /// One row per source line: location, address, encoded word and source text.
+    /// Lines produced by macro expansion are marked with one `+` per level of nesting,
+    /// and pseudo-instructions are followed by one `=` row per instruction they expand to.
+    /// `.crc32` and `.checksum` are followed by a comment giving the range they cover. The
+    /// addresses of the sections follow, as `placements` gives them.
+    pub fn listing (&self) -> Result<String, Box<dyn Error>> {
+        let checksums = self.checksums(&self.encoded_words()?)?;
+        let mut s = String::new();
+        for line in &self.lines {
+            let source_line = &self.source.lines[line.index];
+            let location = self.source.describe(source_line.loc);
+            let nesting = "+".repeat(source_line.expansion.len());
+            let mut words = self.encode_line(line)?;
+            let checksum = match (words.first_mut(), checksums.get(&line.pc)) {
+                (Some(x), Some((value, report))) => {
+                    *x = *value as usize;
+                    Some(report)
+                },
+                _ => None,
+            };
+            let word = match words.first() {
+                Some(x) if !line.inst_line.is_pseudo() => format!("{:08x}", x),
+                _ => String::from("        "),
+            };
+            s.push_str(&format!("{:<24} {:08x} {} {}{}\n",
+                location, line.pc, word, nesting, source_line.raw.trim_end()));
+            if let Some(x) = checksum {
+                s.push_str(x);
+            }
+
+            if line.inst_line.is_pseudo() {
+                for (i, (x, text)) in words.iter().zip(line.inst_line.expansion()).enumerate() {
+                    s.push_str(&format!("{:<24} {:08x} {:08x} {}= {}\n",
+                        location, line.pc + 4 * i, x, nesting, text));
+                }
+            }
+        }
+        s.push_str(&self.placements());
+
         Ok(s)
     }
+
+    /// A comment line for each section that is not empty, giving the addresses it spans.
+    pub fn placements (&self) -> String {
+        let mut s = String::new();
+        for section in self.sections.iter().filter(|x| x.size > 0) {
+            s.push_str(&format!("; {} ({:#010x}-{:#010x})\n",
+                section.name, section.address, section.address + section.size - 1));
+        }
+        s
+    }
+
+    fn encode_line (&self, line: &Line) -> Result<Vec<usize>, Box<dyn Error>> {
+        match line.inst_line.encode_instructions(&self.symbol_map, line.pc) {
+            Ok(x) => Ok(x),
+            Err(e) => bail!(format!("{}: {}", self.source.describe_line(&self.source.lines[line.index]), e)),
+        }
+    }
+}
+
+/// Forgets the import named `name`, which the program defines itself. Returns whether
+/// there was one.
+fn replace_import(definitions: &mut Vec<Symbol>, imported: &mut HashSet<String>, name: &str) -> bool {
+    if !imported.remove(name) {
+        return false;
+    }
+    definitions.retain(|x| x.name != name);
+    true
+}
+
+/// Parses the comma-separated symbol names of `.global` and `.extern`.
+fn process_names(arg: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut names = Vec::new();
+    for name in arg.split(',').map(str::trim) {
+        if !inst::is_symbol(name) {
+            bail!(format!("Invalid symbol name \"{}\"", name));
+        }
+        names.push(name.to_string());
+    }
+    Ok(names)
+}
+
+/// The section `.text`, `.data`, `.bss` or `.section NAME` switches to. Section names start
+/// with a dot so that they cannot be confused with symbols.
+fn process_section<'a>(directive: &'a str, arg: &'a str) -> Result<&'a str, Box<dyn Error>> {
+    match (directive, arg) {
+        (".section", x) if x.starts_with('.') && inst::is_symbol(&x[1..]) => Ok(x),
+        (".section", "") => bail!("Expected .section NAME"),
+        (".section", x) => bail!(format!("Invalid section name \"{}\" (expected a dot and a name, such as .vga)", x)),
+        (x, "") => Ok(x),
+        (x, _) => bail!(format!("Unexpected argument after {}", x)),
+    }
+}
+
+/// The address of each section: those in `configured` where given, in that order, and then
+/// the others in the order first used, each after the one before on a word boundary.
+fn place_sections(configured: &[(String, usize)], sections: &[ProgSection]) -> HashMap<String, usize> {
+    let mut names: Vec<&str> = configured.iter().map(|x| x.0.as_str()).collect();
+    for section in sections {
+        if !names.contains(&section.name.as_str()) {
+            names.push(&section.name);
+        }
+    }
+
+    let mut addresses = HashMap::new();
+    let mut address = 0;
+    for name in names {
+        if let Some((_, x)) = configured.iter().find(|x| x.0 == name) {
+            address = *x;
+        }
+        addresses.insert(name.to_string(), address);
+        if let Some(x) = sections.iter().find(|x| x.name == name) {
+            address += (x.size + 3) & !3;
+        }
+    }
+    addresses
+}
+
+/// Parses `.equ NAME, expr`, evaluating `expr` with the symbols defined so far.
+fn process_equ<'a>(arg: &'a str, lookup: &dyn Fn(&str) -> Option<i64>) -> Result<(&'a str, i64), Box<dyn Error>> {
+    let (name, value) = match arg.find(',') {
+        Some(x) => (arg[..x].trim(), &arg[(x+1)..]),
+        None => bail!("Expected .equ NAME, value"),
+    };
+    if !inst::is_symbol(name) {
+        bail!(format!("Invalid symbol name \"{}\"", name));
+    }
+    Ok((name, Expr::parse(value)?.eval(lookup)?))
+}
+
+/// `value` as a start address, when it is in the 32-bit address space.
+pub(crate) fn entry_address(value: i64) -> Result<usize, Box<dyn Error>> {
+    match value {
+        x if (0..ADDRESS_SPACE_END as i64).contains(&x) => Ok(x as usize),
+        x => bail!(format!("{} is not in the 32-bit address space", x)),
+    }
+}
+
+/// Checks that `entry` is the address of a word emitted in `.text`, as a start address must be.
+pub(crate) fn check_entry(entry: usize, words: &BTreeMap<usize, u32>, sections: &[ProgSection]) -> Result<(), Box<dyn Error>> {
+    let section = sections.iter().find(|x| (x.address..(x.address + x.size)).contains(&entry));
+    match section {
+        Some(x) if x.name == TEXT_SECTION && words.contains_key(&entry) => Ok(()),
+        Some(x) if x.name != TEXT_SECTION => bail!(format!("Entry point {:#010x} is in section {}, not {}", entry, x.name, TEXT_SECTION)),
+        _ => bail!(format!("Entry point {:#010x} is not the address of emitted code", entry)),
+    }
+}
+
+/// Warns about each branch pseudo-instruction whose scratch register is also named
+/// explicitly within `SCRATCH_WINDOW` lines, as the value held there would be lost.
+fn check_scratch(source: &Source, lines: &[Line]) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        let scratch = match line.inst_line.clobbers() {
+            Some(x) => x,
+            None => continue,
+        };
+        let start = i.saturating_sub(SCRATCH_WINDOW);
+        let end = (i + SCRATCH_WINDOW + 1).min(lines.len());
+        if let Some(other) = lines[start..end].iter().find(|x| x.inst_line.uses_register(scratch)) {
+            warnings.push(format!("{}: Branch overwrites scratch register r{}, which is also used at {}",
+                source.describe_line(&source.lines[line.index]), scratch,
+                source.describe_line(&source.lines[other.index])));
+        }
+    }
+    warnings
+}
+
+fn describe_region(source: &Source, region: &Region) -> String {
+    format!("region {:#010x}-{:#010x} ({})", region.start, region.end - 1, source.describe(region.loc))
+}
+
+fn check_overlaps(source: &Source, regions: &[Region]) -> Result<(), Box<dyn Error>> {
+    let mut sorted: Vec<&Region> = regions.iter().collect();
+    sorted.sort_by_key(|r| r.start);
+
+    let mut overlaps = Vec::new();
+    for (i, a) in sorted.iter().enumerate() {
+        for b in sorted[(i+1)..].iter().take_while(|b| b.start < a.end) {
+            overlaps.push(format!("Overlapping regions: {} and {}",
+                describe_region(source, a), describe_region(source, b)));
+        }
+    }
+
+    match overlaps.is_empty() {
+        true => Ok(()),
+        false => bail!(overlaps.join("\n")),
+    }
 }
 
 #[cfg(test)]
 mod test {
 
     use super::*;
+    use std::path::PathBuf;
 
     #[test]
     fn prog_new_test() {
@@ -71,12 +890,517 @@ mod test {
             ("add r1,r2,r3", "00000000\n00000000\t60443000\n"),
             ("add r1,r2,r3\n add r1,r2,r3", "00000000\n00000000\t60443000\n00000004\t60443000\n"),
             ("LABEL: addi r1,r2,LABEL", "00000000\n00000000\t68440000\n"),
+            ("éé: addi r1,r2,éé", "00000000\n00000000\t68440000\n"),
         ];
         for test in &tests {
             let result = Prog::new("file", test.0).unwrap().encode().unwrap();
 
             assert_eq!(result, test.1);
         }
+
+        let invalid_tests = [
+            "nop\n.org -4\nnop",
+            "nop\n.dw -1",
+            "nop\n.dw 0x1000000000000000",
+            "nop\nadd é1, r2, r3",
+            "nop\nld r1, 4(é1)",
+            "nop\n.scratch é1",
+        ];
+        for test in &invalid_tests {
+            let message = Prog::new("file", test).err().unwrap().to_string();
+            assert!(message.starts_with("file:2: "), "failed with [{}]: {}", test, message);
+        }
+    }
+
+    #[test]
+    fn prog_regions_test() {
+        let source = "nop\nnop\n.org 16\nnop\n.org 8\nstop";
+        let result = Prog::new("file", source).unwrap();
+        assert_eq!(result.regions, vec![
+            Region{start: 0, end: 8, loc: Loc{file: 0, line: 1}},
+            Region{start: 16, end: 20, loc: Loc{file: 0, line: 4}},
+            Region{start: 8, end: 12, loc: Loc{file: 0, line: 6}},
+        ]);
+
+        let invalid_tests = [
+            "nop\nnop\n.org 4\nstop",
+            ".org 8\nnop\n.org 0\nnop\nnop\nnop",
+            ".org 0xFFFFFFFC\nnop\nnop",
+            ".org 0x100000000\nnop",
+        ];
+        for test in &invalid_tests {
+            let result = Prog::new("file", test);
+            assert!(result.is_err(), "failed with [{}]", test);
+        }
+
+        let message = Prog::new("file", "nop\nnop\n.org 4\nstop").err().unwrap().to_string();
+        assert!(message.contains("file:1"));
+        assert!(message.contains("file:4"));
+    }
+
+    #[test]
+    fn prog_from_source_test() {
+        let temp = source::TempDir::new("prog");
+        let dir = &temp.0;
+        std::fs::write(dir.join("uart.inc"), "nop\nbogus r1").unwrap();
+
+        let source = Source::from_str(&dir.join("main.asm"), "stop\n.include \"uart.inc\"", &[]).unwrap();
+        let message = Prog::from_source(source, &Options::default()).err().unwrap().to_string();
+        assert!(message.starts_with(&format!("{}:2:", dir.join("uart.inc").display())), "{}", message);
+    }
+
+    #[test]
+    fn prog_listing_test() {
+        let source = ".macro TWICE\n nop\n nop\n.endm\nSTART: TWICE\n stop";
+        let result = Prog::new("file", source).unwrap();
+        assert_eq!(result.encode().unwrap(), "00000000\n00000000\t00000000\n00000004\t00000000\n00000008\tf8000000\n");
+
+        let listing = result.listing().unwrap();
+        let listing: Vec<&str> = listing.lines().collect();
+        assert_eq!(listing, vec![
+            "file:5                   00000000          START: TWICE",
+            "file:2                   00000000 00000000 + nop",
+            "file:3                   00000004 00000000 + nop",
+            "file:6                   00000008 f8000000  stop",
+            "; .text (0x00000000-0x0000000b)",
+        ]);
+    }
+
+    #[test]
+    fn prog_pseudo_test() {
+        let result = Prog::new("file", "li r1, 0x80000000\nEND: stop").unwrap();
+        assert_eq!(result.encode().unwrap(), "00000000\n00000000\t2841ffff\n00000004\te042001f\n00000008\tf8000000\n");
+
+        let listing = result.listing().unwrap();
+        let listing: Vec<&str> = listing.lines().collect();
+        assert_eq!(listing, vec![
+            "file:1                   00000000          li r1, 0x80000000",
+            "file:1                   00000000 2841ffff = la r1, -1",
+            "file:1                   00000004 e042001f = shl r1, r1, 31",
+            "file:2                   00000008 f8000000 END: stop",
+            "; .text (0x00000000-0x0000000b)",
+        ]);
+    }
+
+    #[test]
+    fn prog_section_test() {
+        let source = "\
+.data
+COUNT: .word 3
+.text
+START: ld r1, COUNT
+ jmp START
+.bss
+BUFFER: .dw 1
+.section .vga
+SCREEN: .word BUFFER
+.data
+TABLE: .word START, SCREEN";
+        let result = Prog::new("file", source).unwrap();
+        assert_eq!(result.encode().unwrap(), "00000000\n\
+            0000000c\t00000003\n00000000\t0840000c\n\
+            00000004\t36bffff8\n00000008\t40340001\n\
+            00000038\t00000018\n00000010\t00000000\n00000014\t00000038\n");
+        let sections: Vec<(&str, usize, usize)> = result.sections.iter().map(|x| (x.name.as_str(), x.address, x.size)).collect();
+        assert_eq!(sections, vec![(".text", 0, 12), (".data", 12, 12), (".bss", 24, 32), (".vga", 56, 4)]);
+        assert!(result.listing().unwrap().ends_with("; .bss (0x00000018-0x00000037)\n; .vga (0x00000038-0x0000003b)\n"));
+
+        let options = Options {
+            sections: vec![(String::from(".text"), 4096), (String::from(".vga"), 0x200000)],
+            ..Options::default()
+        };
+        let result = Prog::with_options("file", source, &options).unwrap();
+        let sections: Vec<(&str, usize)> = result.sections.iter().map(|x| (x.name.as_str(), x.address)).collect();
+        assert_eq!(sections, vec![(".text", 4096), (".data", 0x200004), (".bss", 0x200010), (".vga", 0x200000)]);
+        assert_eq!(result.symbols()["SCREEN"], 0x200000);
+        assert_eq!(result.symbols()["BUFFER"], 0x200010);
+        // COUNT, in .data after .vga, is out of reach of ld's 17-bit constant.
+        let message = result.encode().err().unwrap().to_string();
+        assert_eq!(message, "file:4: Constant 0x200004 (2097156) does not fit in the 17-bit signed constant of ld");
+        let screen = Prog::with_options("file", ".section .vga\nSCREEN: .dw 1\n.text\nla r1, SCREEN", &options).unwrap();
+        assert!(screen.encode().is_err());
+
+        let object = result.object().unwrap();
+        let sections: Vec<(&str, usize, usize)> = object.sections.iter().map(|x| (x.name.as_str(), x.size, x.words.len())).collect();
+        assert_eq!(sections, vec![(".text", 12, 3), (".data", 12, 3), (".bss", 32, 0), (".vga", 4, 1)]);
+        let symbols: Vec<(&str, &str, usize)> = object.symbols.iter().map(|x| (x.name.as_str(), x.section.as_str(), x.value)).collect();
+        assert_eq!(symbols, vec![("COUNT", ".data", 0), ("START", ".text", 0), ("BUFFER", ".bss", 0), ("SCREEN", ".vga", 0), ("TABLE", ".data", 4)]);
+        let relocations: Vec<(usize, &str)> = object.sections[1].relocations.iter().map(|x| (x.offset, x.symbol.as_str())).collect();
+        assert_eq!(relocations, vec![(4, ".text"), (8, ".vga")]);
+
+        let invalid_tests = [
+            ".bss\nnop",
+            ".bss\n.word 1",
+            ".section vga",
+            ".section",
+            ".data 4",
+        ];
+        for test in &invalid_tests {
+            let result = Prog::new("file", test);
+            assert!(result.is_err(), "failed with [{}]", test);
+        }
+
+        assert!(Prog::new("file", ".data\n.org 16\nnop").unwrap().object().is_err());
+
+        let options = Options { sections: vec![(String::from(".text"), 16), (String::from(".data"), 20)], ..Options::default() };
+        assert!(Prog::with_options("file", ".org 0\nnop", &options).is_err());
+        assert!(Prog::with_options("file", "nop\nnop\n.data\nnop", &options).is_err());
+    }
+
+    #[test]
+    fn prog_constant_range_test() {
+        // The monitor masks with constants too wide for andi, which are reported rather than
+        // truncated to zero.
+        let message = Prog::new("monitor.asm", include_str!("../monitor.asm")).and_then(|x| x.encode()).err().unwrap().to_string();
+        assert_eq!(message, "monitor.asm:273: Constant 0xff000000 (-16777216) does not fit in the 17-bit signed constant of andi");
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn prog_members_test() {
+        let source = "\
+.global RX, TX, PRINT
+.equ UART, -32
+; shared state
+RX: ld r1, UART
+ ret
+TX: st r1, UART + 4
+ ret
+.data
+LAST: .word 0
+.text
+PRINT: call TX
+.next: jmp .next";
+        let members = Prog::new("file", source).unwrap().members("uart").unwrap();
+        let names: Vec<&str> = members.iter().map(|x| x.name.as_str()).collect();
+        assert_eq!(names, vec!["RX", "TX", "PRINT"]);
+
+        let symbols = |i: usize| -> Vec<(String, Binding, usize)> {
+            members[i].object.symbols.iter().map(|x| (x.name.clone(), x.binding, x.value)).collect()
+        };
+        assert_eq!(symbols(0), vec![(String::from("UART"), Binding::Local, 0xffffffe0), (String::from("RX"), Binding::Global, 0)]);
+        assert_eq!(symbols(1), vec![(String::from("TX"), Binding::Global, 0), (String::from("LAST"), Binding::Local, 0)]);
+        assert_eq!(symbols(2), vec![(String::from("PRINT"), Binding::Global, 0), (String::from("PRINT.next"), Binding::Local, 8), (String::from("TX"), Binding::Extern, 0)]);
+        let sections: Vec<(&str, usize)> = members[1].object.sections.iter().map(|x| (x.name.as_str(), x.size)).collect();
+        assert_eq!(sections, vec![(".text", 8), (".data", 4)]);
+        assert_eq!(members[2].object.sections[0].relocations[0].symbol, "TX");
+
+        // The entry point goes with the routine holding it.
+        let members = Prog::new("file", ".global A, B\n.entry B + 4\nA: nop\nB: nop\n stop").unwrap().members("m").unwrap();
+        let entries: Vec<Option<(String, i64)>> = members.iter().map(|x| x.object.entry.clone()).collect();
+        assert_eq!(entries, vec![None, Some((String::from(".text"), 4))]);
+        let members = Prog::new("file", ".global A, B\n.entry 8\nA: nop\nB: nop").unwrap().members("m").unwrap();
+        assert_eq!(members[0].object.entry, Some((String::from(ABSOLUTE_SECTION), 8)));
+        assert_eq!(members[1].object.entry, None);
+
+        let members = Prog::new("file", "START: nop\n.global A\nA: ret").unwrap().members("main").unwrap();
+        let names: Vec<&str> = members.iter().map(|x| x.name.as_str()).collect();
+        assert_eq!(names, vec!["main", "A"]);
+
+        let message = Prog::new("file", ".global A, B\nA: nop\nLOCAL: nop\nB: lar r1, LOCAL").unwrap().members("m").err().unwrap().to_string();
+        assert_eq!(message, "file:4: Label \"LOCAL\" belongs to another routine; declare it .global to use it here");
+    }
+
+    #[test]
+    fn prog_scratch_test() {
+        let source = "LOOP: jz r1, DONE\n jmp LOOP\nDONE: call SUB\n stop\nSUB: ret";
+        let result = Prog::new("file", source).unwrap();
+        assert!(result.warnings.is_empty());
+        assert_eq!(result.encode().unwrap(), "00000000\n\
+            00000000\t3680000c\n00000004\t40341002\n\
+            00000008\t36bffff4\n0000000c\t40340001\n\
+            00000010\t36800008\n00000014\t4e740001\n\
+            00000018\tf8000000\n0000001c\t40320001\n");
+
+        let result = Prog::new("file", "la r26, 5\njmp END\nEND: stop").unwrap();
+        assert_eq!(result.warnings, vec!["file:2: Branch overwrites scratch register r26, which is also used at file:1"]);
+
+        let result = Prog::new("file", "la r26, 5\n.scratch r20\njmp END\nEND: stop").unwrap();
+        assert!(result.warnings.is_empty());
+        assert!(result.encode().unwrap().contains("\t35000004\n"));
+
+        let invalid_tests = [
+            ".scratch r0",
+            ".scratch r25",
+            ".scratch 5",
+            "jz r26, END\nEND: stop",
+        ];
+        for test in &invalid_tests {
+            let result = Prog::new("file", test);
+            assert!(result.is_err(), "failed with [{}]", test);
+        }
+    }
+
+    #[test]
+    fn prog_alias_test() {
+        let source = "RXFLAG .reg r1\nLOOP: ld RXFLAG, 4(sp)\n addi R2, zero, 1\n.scratch fp\n jmp LOOP";
+        let result = Prog::new("file", source).unwrap();
+        assert_eq!(result.encode().unwrap(), "00000000\n\
+            00000000\t087a0004\n00000004\t68800001\n\
+            00000008\t37bffff4\n0000000c\t403c0001\n");
+
+        let invalid_tests = [
+            "sp: nop",
+            "LOOP: nop\nLOOP .reg r1",
+            "COUNT .reg r1\nCOUNT: nop",
+            "COUNT .reg r1\n.equ COUNT, 4",
+            "COUNT .reg r32",
+            "addi r1, SP, 1",
+            ".scratch lr",
+        ];
+        for test in &invalid_tests {
+            let result = Prog::new("file", test);
+            assert!(result.is_err(), "failed with [{}]", test);
+        }
+
+        let message = Prog::new("file", "nop\nlr: stop").err().unwrap().to_string();
+        assert_eq!(message, "file:2: Label \"lr\" is shadowed by a register alias");
+    }
+
+    #[test]
+    fn prog_local_label_test() {
+        let source = "MAIN: nop\n.loop: la r1, .loop\nOTHER: la r2, .loop\n.loop: la r3, MAIN.loop\n\
+            1: la r4, 1f\n1: la r5, 1b\n la r6, 1b + 4";
+        let result = Prog::new("file", source).unwrap();
+        assert_eq!(result.encode().unwrap(), "00000000\n\
+            00000000\t00000000\n00000004\t28400004\n00000008\t2880000c\n0000000c\t28c00004\n\
+            00000010\t29000014\n00000014\t29400014\n00000018\t29800018\n");
+
+        let invalid_tests = [
+            ".loop: nop",
+            "jmp .loop",
+            "jmp 1b\n1: nop",
+            "MAIN: nop\n.loop: nop\n.loop: nop",
+            ".1x: nop",
+        ];
+        for test in &invalid_tests {
+            let result = Prog::new("file", test);
+            assert!(result.is_err(), "failed with [{}]", test);
+        }
+
+        let message = Prog::new("file", "1: nop\nla r1, 1f").unwrap().encode().err().unwrap().to_string();
+        assert_eq!(message, "file:2: No later definition of numeric label \"1\" for \"1f\"");
+        let message = Prog::new("file", "MAIN: la r1, .missing").unwrap().encode().err().unwrap().to_string();
+        assert_eq!(message, "file:1: Undefined symbol \"MAIN.missing\"");
+    }
+
+    #[test]
+    fn prog_conditional_test() {
+        let source = "\
+.equ BOARD, 2
+.if BOARD == 1
+SKIP: nop
+ nop
+.elseif BOARD == 2 && !defined(SKIP)
+ stop
+.else
+ bogus
+.endif
+.ifdef UART
+UART_LABEL: add r1,r2,r3
+.endif
+END: addi r1,r2,END";
+        let result = Prog::new("file", source).unwrap();
+        assert_eq!(result.encode().unwrap(), "00000000\n00000000\tf8000000\n00000004\t68440004\n");
+
+        let options = Options { defines: vec![("UART".to_string(), 1)], ..Options::default() };
+        let result = Prog::with_options("file", source, &options).unwrap();
+        assert_eq!(result.encode().unwrap(), "00000000\n00000000\tf8000000\n00000004\t60443000\n00000008\t68440008\n");
+
+        let invalid_tests = [
+            ".if 1\nnop",
+            ".endif",
+            ".if MISSING\n.endif",
+            ".equ A, 1\n.equ A, 2",
+            ".equ 1A, 1",
+        ];
+        for test in &invalid_tests {
+            let result = Prog::new("file", test);
+            assert!(result.is_err(), "failed with [{}]", test);
+        }
+    }
+
+    #[test]
+    fn prog_conditional_macro_test() {
+        let source = "\
+.ifdef BOARD_A
+.macro OUT reg
+ add \\reg, r2, r3
+.endm
+.else
+.macro OUT reg
+ stop
+.endm
+.include \"board_b.inc\"
+.endif
+START: OUT r1
+.if START == 0
+.macro TWICE
+ OUT r1
+ OUT r1
+.endm
+.endif
+ TWICE";
+        let result = Prog::new("file", source);
+        assert!(result.err().unwrap().to_string().contains("board_b.inc"));
+
+        let options = Options { defines: vec![("BOARD_A".to_string(), 1)], ..Options::default() };
+        let result = Prog::with_options("file", source, &options).unwrap();
+        assert_eq!(result.encode().unwrap(), "00000000\n00000000\t60443000\n00000004\t60443000\n00000008\t60443000\n");
+
+        let result = Prog::new("file", &source.replace(".include \"board_b.inc\"", "")).unwrap();
+        assert_eq!(result.encode().unwrap(), "00000000\n00000000\tf8000000\n00000004\tf8000000\n00000008\tf8000000\n");
+    }
+
+    #[test]
+    fn prog_repeat_test() {
+        let source = ".irp n, 1, 2\n addi r1, r1, \\n\n.endr";
+        let result = Prog::new("file", source).unwrap();
+        assert_eq!(result.encode().unwrap(), "00000000\n00000000\t68420001\n00000004\t68420002\n");
+
+        let message = Prog::new("file", "nop\n.rept 2\n bogus\n.endr").err().unwrap().to_string();
+        assert!(message.starts_with("file:3 (expanded from file:2): "), "{}", message);
+    }
+
+    #[test]
+    fn prog_io_window_test() {
+        let source = ".org 4294967264\nnop";
+        let result = Prog::new("file", source).unwrap();
+        assert_eq!(result.warnings.len(), 1);
+
+        let options = Options { warn_io_window: false, ..Options::default() };
+        let result = Prog::with_options("file", source, &options).unwrap();
+        assert!(result.warnings.is_empty());
+
+        let result = Prog::new("file", ".org 4294967260\nnop").unwrap();
+        assert!(result.warnings.is_empty());
+    }
+
+    #[test]
+    fn prog_word_test() {
+        let source = "START: .word 1, -1, END\nEND: .word . - START, 0xFFFFFFFF, -0x80000000";
+        let result = Prog::new("file", source).unwrap();
+        assert_eq!(result.encode().unwrap(), "00000000\n\
+            00000000\t00000001\n00000004\tffffffff\n00000008\t0000000c\n0000000c\t0000000c\n00000010\tffffffff\n00000014\t80000000\n");
+
+        let invalid_tests = [
+            ".word",
+            ".word 1,",
+            ".word r1",
+            ".word 0x1FFFFFFFF",
+            ".word -0x80000001",
+            ".equ BIG, 0x100000000\n.word BIG",
+        ];
+        for test in &invalid_tests {
+            let result = Prog::new("file", test).and_then(|x| x.encode());
+            assert!(result.is_err(), "failed with [{}]", test);
+        }
+    }
+
+    #[test]
+    fn prog_checksum_test() {
+        let source = "START: .word 1, 2\nEND:\nSUM: .checksum START, END\nCRC: .crc32 START, CRC";
+        let result = Prog::new("file", source).unwrap();
+        let words: Vec<u32> = result.words().unwrap().iter().map(|x| x.value).collect();
+        assert_eq!(words, vec![1, 2, 3, checksum::crc32(&[0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3])]);
+        let listing = result.listing().unwrap();
+        assert!(listing.contains("file:3                   00000008 00000003 SUM: .checksum START, END\n\
+            ; checksum of 0x00000000-0x00000007 at 0x00000008: 0x00000003\n"), "{}", listing);
+
+        let invalid_tests = [
+            ".crc32 0, 8\nstop",
+            "A: .crc32 B, C\nB: .checksum A, B\nC: stop",
+            "stop\n.crc32 1, 4",
+            "stop\n.checksum 4, 0",
+            "stop\n.checksum 0",
+            "stop\n.checksum 0, MISSING",
+            ".bss\n.crc32 0, 4",
+        ];
+        for test in &invalid_tests {
+            let result = Prog::new("file", test).and_then(|x| x.encode());
+            assert!(result.is_err(), "failed with [{}]", test);
+        }
+    }
+
+    #[test]
+    fn prog_object_test() {
+        let source = "\
+.global START, COUNT
+.extern TX, UNUSED
+.equ SIZE, 8
+START: la r1, SIZE
+       la r2, END
+       lar r3, LOOP
+LOOP:  call TX
+       lar r4, 0x100
+END:   .word END - START, TX + 4, END
+COUNT: .dw 1";
+        let object = Prog::new("file", source).unwrap().object().unwrap();
+        assert_eq!(object.write(), "\
+orange-object 1
+section .text 00000044
+word 00000000 28400008
+word 00000004 28800000
+word 00000008 30c00000
+word 0000000c 36800000
+word 00000010 4e740001
+word 00000014 31000000
+word 00000018 00000018
+word 0000001c 00000000
+word 00000020 00000000
+reloc 00000004 c2 .text 24
+reloc 0000000c c1 TX 0
+reloc 00000014 c1 *ABS* 256
+reloc 0000001c word TX 4
+reloc 00000020 word .text 24
+symbol local *ABS* 00000008 SIZE file:3
+symbol global .text 00000000 START file:4
+symbol local .text 0000000c LOOP file:7
+symbol local .text 00000018 END file:9
+symbol global .text 00000024 COUNT file:10
+symbol extern *UND* 00000000 TX
+symbol extern *UND* 00000000 UNUSED
+");
+
+        let object = Prog::new("file", ".extern INIT\n.entry INIT + 4\nstop").unwrap().object().unwrap();
+        assert_eq!(object.entry, Some((String::from("INIT"), 4)));
+
+        let invalid_tests = [
+            "la r1, MISSING",
+            ".extern X\nla r1, X >> 16",
+            ".entry L >> 2\nL: stop",
+            ".entry 0x100000000\nstop",
+            ".entry -4\nstop",
+            ".extern X\nshl r1, r2, X",
+            "L: la r1, L + L",
+            "stop\n.crc32 0, 4",
+        ];
+        for test in &invalid_tests {
+            let result = Prog::new("file", test).and_then(|x| x.object());
+            assert!(result.is_err(), "failed with [{}]", test);
+        }
+        for test in &[".entry 0x100000000\nstop", ".entry -4\nstop"] {
+            let result = Prog::new("file", test).and_then(|x| x.entry());
+            assert!(result.is_err(), "failed with [{}]", test);
+        }
+
+        let invalid_tests = [
+            ".global MISSING",
+            ".global 1X",
+            ".extern A,",
+        ];
+        for test in &invalid_tests {
+            let result = Prog::new("file", test);
+            assert!(result.is_err(), "failed with [{}]", test);
+        }
+
+        let result = Prog::new("file", ".extern TX\nTX: nop\n.global TX").unwrap().object().unwrap();
+        assert_eq!(result.symbols, vec![ObjectSymbol {
+            name: String::from("TX"),
+            binding: Binding::Global,
+            section: String::from(".text"),
+            value: 0,
+            source: Some(SourceRef { file: PathBuf::from("file"), line: 2 }),
+        }]);
+    }
+
+}
@@ -0,0 +1,408 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::cond::Early;
+use crate::lexer;
+
+/// How many macro invocations `describe_line` names at either end of a longer chain.
+pub const EXPANSION_FRAMES: usize = 3;
+
+/// Position of a line in the program: an index into `Source::files` and a 1-based line number.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Loc {
+    pub file: usize,
+    pub line: usize,
+}
+
+/// A line of a named file, for reporting positions apart from the `Source` they came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceRef {
+    pub file: PathBuf,
+    pub line: usize,
+}
+
+impl SourceRef {
+    /// Parses `file:line`, as written by `Display`.
+    pub fn parse(text: &str) -> Result<SourceRef, Box<dyn Error>> {
+        let (file, line) = match text.rfind(':') {
+            Some(x) => (&text[..x], &text[(x+1)..]),
+            None => bail!(format!("Expected file:line, found \"{}\"", text)),
+        };
+        match line.parse::<usize>() {
+            Ok(line) if !file.is_empty() => Ok(SourceRef { file: PathBuf::from(file), line }),
+            _ => bail!(format!("Expected file:line, found \"{}\"", text)),
+        }
+    }
+}
+
+impl fmt::Display for SourceRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file.display(), self.line)
+    }
+}
+
+#[derive(Clone)]
+pub struct SourceLine {
+    pub text: String,
+    /// Text shown in listings; differs from `text` on macro invocation lines.
+    pub raw: String,
+    pub loc: Loc,
+    /// Macro invocations this line was expanded from, innermost first.
+    pub expansion: Vec<Loc>,
+}
+
+/// A program with every `.include` resolved, flattened into a single list of lines.
+pub struct Source {
+    pub files: Vec<PathBuf>,
+    pub lines: Vec<SourceLine>,
+}
+
+impl Source {
+    pub fn load(path: &Path, include_paths: &[PathBuf]) -> Result<Source, Box<dyn Error>> {
+        let contents = match fs::read_to_string(path) {
+            Ok(x) => x,
+            Err(e) => bail!(format!("Could not read \"{}\": {}", path.display(), e)),
+        };
+        Source::from_str(path, &contents, include_paths)
+    }
+
+    /// Includes are resolved relative to the directory of `path`, then each of `include_paths`.
+    pub fn from_str(path: &Path, contents: &str, include_paths: &[PathBuf]) -> Result<Source, Box<dyn Error>> {
+        Source::from_sources(&[(path.to_path_buf(), contents.to_string())], include_paths, &HashMap::new(), &[])
+    }
+
+    /// Loads each of `sources` in turn as one program. `.include` looks for a file in
+    /// `files` before looking for it on disk, in the same places. An `.include` in a block
+    /// that conditions on `known` symbols skip is not read; see `cond::Early`.
+    pub fn from_sources(sources: &[(PathBuf, String)], include_paths: &[PathBuf], files: &HashMap<PathBuf, String>, known: &[(String, i64)]) -> Result<Source, Box<dyn Error>> {
+        let mut source = Source {
+            files: Vec::new(),
+            lines: Vec::new(),
+        };
+        let search = Search { include_paths, files };
+        let mut early = Early::new(known);
+        for (path, contents) in sources {
+            source.include(path, contents, &search, &mut early, &mut Vec::new())?;
+        }
+        Ok(source)
+    }
+
+    pub fn source_ref(&self, loc: Loc) -> SourceRef {
+        SourceRef {
+            file: self.files[loc.file].clone(),
+            line: loc.line,
+        }
+    }
+
+    pub fn describe(&self, loc: Loc) -> String {
+        format!("{}:{}", self.files[loc.file].display(), loc.line)
+    }
+
+    /// The location of `line` followed by the invocations it was expanded from, leaving out
+    /// all but `EXPANSION_FRAMES` at either end of a long chain.
+    pub fn describe_line(&self, line: &SourceLine) -> String {
+        let mut s = self.describe(line.loc);
+        let count = line.expansion.len();
+        for (index, loc) in line.expansion.iter().enumerate() {
+            match index {
+                x if count <= 2 * EXPANSION_FRAMES + 1 || x < EXPANSION_FRAMES || x >= count - EXPANSION_FRAMES
+                    => s.push_str(&format!(" (expanded from {})", self.describe(*loc))),
+                x if x == EXPANSION_FRAMES => s.push_str(&format!(" (… {} more)", count - 2 * EXPANSION_FRAMES)),
+                _ => (),
+            }
+        }
+        s
+    }
+
+    /// Every file read while loading, without duplicates, in the order first seen.
+    pub fn dependencies(&self) -> Vec<&Path> {
+        let mut deps: Vec<&Path> = Vec::new();
+        for file in &self.files {
+            if !deps.contains(&file.as_path()) {
+                deps.push(file);
+            }
+        }
+        deps
+    }
+
+    /// A make rule for `target` listing every dependency, with an empty rule per
+    /// included file so make does not fail once one of them is deleted.
+    pub fn make_rule(&self, target: &Path) -> String {
+        let deps = self.dependencies();
+        let mut s = format!("{}:", escape_make(target));
+        for dep in &deps {
+            s.push_str(&format!(" \\\n  {}", escape_make(dep)));
+        }
+        s.push('\n');
+        for dep in deps.iter().skip(1) {
+            s.push_str(&format!("\n{}:\n", escape_make(dep)));
+        }
+        s
+    }
+
+    fn include(&mut self, path: &Path, contents: &str, search: &Search, early: &mut Early, stack: &mut Vec<PathBuf>) -> Result<(), Box<dyn Error>> {
+        let file = self.files.len();
+        self.files.push(path.to_path_buf());
+        stack.push(canonical(path));
+
+        for (index, text) in contents.lines().enumerate() {
+            let loc = Loc { file, line: index + 1 };
+            let target = match early.process(text, loc) {
+                Ok(true) => Ok(None),
+                Ok(false) if !early.active() => Ok(None),
+                Ok(false) => include_target(text),
+                Err(e) => Err(e),
+            };
+            let target = match target {
+                Ok(x) => x,
+                Err(e) => bail!(format!("{}: {}", self.describe(loc), e)),
+            };
+            match target {
+                Some(target) => {
+                    let resolved = match search.resolve(target, path) {
+                        Some(x) => x,
+                        None => bail!(format!("{}: Could not find include file \"{}\"", self.describe(loc), target)),
+                    };
+                    if stack.contains(&canonical(&resolved)) {
+                        let mut chain: Vec<String> = stack.iter().map(|p| p.display().to_string()).collect();
+                        chain.push(resolved.display().to_string());
+                        bail!(format!("{}: Include cycle: {}", self.describe(loc), chain.join(" -> ")));
+                    }
+                    let included = match search.files.get(&resolved) {
+                        Some(x) => x.clone(),
+                        None => match fs::read_to_string(&resolved) {
+                            Ok(x) => x,
+                            Err(e) => bail!(format!("{}: Could not read \"{}\": {}", self.describe(loc), resolved.display(), e)),
+                        },
+                    };
+                    self.include(&resolved, &included, search, early, stack)?;
+                },
+                None => self.lines.push(SourceLine {
+                    text: text.to_string(),
+                    raw: text.to_string(),
+                    loc,
+                    expansion: Vec::new(),
+                }),
+            }
+        }
+
+        stack.pop();
+        Ok(())
+    }
+}
+
+/// Splits a line that starts with a directive into the directive and its argument,
+/// dropping any comment.
+pub fn split_directive(text: &str) -> Option<(&str, &str)> {
+    let line = lexer::strip_comment(text).trim();
+    if !line.starts_with('.') {
+        return None;
+    }
+    match line.find(char::is_whitespace) {
+        Some(x) => Some((&line[..x], line[x..].trim())),
+        None => Some((line, "")),
+    }
+}
+
+/// Returns the quoted file name when `text` is an `.include` line.
+fn include_target(text: &str) -> Result<Option<&str>, Box<dyn Error>> {
+    let rest = match text.trim().strip_prefix(".include") {
+        Some(x) if x.is_empty() || x.starts_with(char::is_whitespace) => x.trim(),
+        _ => return Ok(None),
+    };
+    let rest = match rest.strip_prefix('"') {
+        Some(x) => x,
+        None => bail!("Expected quoted file name after .include"),
+    };
+    match rest.find('"') {
+        Some(x) => {
+            let tail = rest[(x+1)..].trim();
+            if !tail.is_empty() && !tail.starts_with(';') {
+                bail!("Unexpected text after .include file name");
+            }
+            Ok(Some(&rest[..x]))
+        },
+        None => bail!("Unterminated .include file name"),
+    }
+}
+
+/// Where `.include` looks for files.
+struct Search<'a> {
+    include_paths: &'a [PathBuf],
+    /// In-memory files, found before any file on disk at the same path.
+    files: &'a HashMap<PathBuf, String>,
+}
+
+impl<'a> Search<'a> {
+    fn exists(&self, path: &Path) -> bool {
+        self.files.contains_key(path) || path.is_file()
+    }
+
+    fn resolve(&self, target: &str, including: &Path) -> Option<PathBuf> {
+        let target = Path::new(target);
+        if target.is_absolute() {
+            return match self.exists(target) {
+                true => Some(target.to_path_buf()),
+                false => None,
+            };
+        }
+
+        let local = including.parent().unwrap_or_else(|| Path::new(""));
+        std::iter::once(local)
+            .chain(self.include_paths.iter().map(|p| p.as_path()))
+            .map(|dir| dir.join(target))
+            .find(|candidate| self.exists(candidate))
+    }
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn escape_make(path: &Path) -> String {
+    path.display().to_string().replace(' ', "\\ ")
+}
+
+/// A directory for a test's fixture files, unique to the test and removed when dropped.
+#[cfg(test)]
+pub(crate) struct TempDir(pub PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!("orange_{}_{}_{}", name, std::process::id(), COUNT.fetch_add(1, Ordering::SeqCst)));
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn include_target_test() {
+        let tests = [
+            (".include \"uart.inc\"", Some("uart.inc")),
+            ("  .include   \"lib/delay.inc\" ; delay loops", Some("lib/delay.inc")),
+            ("add r1, r2, r3", None),
+            (".includes 5", None),
+        ];
+        for test in &tests {
+            let result = include_target(test.0).unwrap();
+            assert_eq!(result, test.1);
+        }
+
+        let invalid_tests = [
+            ".include uart.inc",
+            ".include \"uart.inc",
+            ".include \"uart.inc\" nop",
+            ".include",
+        ];
+        for test in &invalid_tests {
+            let result = include_target(test);
+            assert!(result.is_err(), "failed with [{}]", test);
+        }
+    }
+
+    #[test]
+    fn source_include_test() {
+        let temp = TempDir::new("include");
+        let dir = &temp.0;
+        let lib = dir.join("lib");
+        fs::create_dir_all(&lib).unwrap();
+        fs::write(dir.join("local.inc"), "nop\n.include \"delay.inc\"").unwrap();
+        fs::write(lib.join("delay.inc"), "stop").unwrap();
+
+        let main = dir.join("main.asm");
+        let include_paths = vec![lib.clone()];
+        let source = Source::from_str(&main, "add r1,r2,r3\n.include \"local.inc\"\nnop", &include_paths).unwrap();
+        let lines: Vec<(&str, String)> = source.lines.iter()
+            .map(|l| (l.text.as_str(), source.describe(l.loc)))
+            .collect();
+        assert_eq!(lines, vec![
+            ("add r1,r2,r3", format!("{}:1", main.display())),
+            ("nop", format!("{}:1", dir.join("local.inc").display())),
+            ("stop", format!("{}:1", lib.join("delay.inc").display())),
+            ("nop", format!("{}:3", main.display())),
+        ]);
+        assert_eq!(source.dependencies().len(), 3);
+
+        let rule = source.make_rule(Path::new("main.bin"));
+        assert!(rule.starts_with("main.bin:"));
+        assert!(rule.contains(&format!("\n{}:\n", lib.join("delay.inc").display())));
+
+        let result = Source::from_str(&main, ".include \"missing.inc\"", &include_paths);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn source_include_cycle_test() {
+        let temp = TempDir::new("cycle");
+        let dir = &temp.0;
+        fs::write(dir.join("a.inc"), ".include \"b.inc\"").unwrap();
+        fs::write(dir.join("b.inc"), "nop\n.include \"a.inc\"").unwrap();
+
+        let result = Source::load(&dir.join("a.inc"), &[]);
+        let message = result.err().unwrap().to_string();
+        assert!(message.contains("Include cycle"), "{}", message);
+        assert!(message.contains("b.inc:2"), "{}", message);
+    }
+
+    #[test]
+    fn source_from_sources_test() {
+        let mut files = HashMap::new();
+        files.insert(PathBuf::from("uart.inc"), String::from("nop"));
+        files.insert(PathBuf::from("lib/delay.inc"), String::from("stop\n.include \"timer.inc\""));
+        files.insert(PathBuf::from("lib/timer.inc"), String::from("brnv"));
+        let sources = vec![
+            (PathBuf::from("main.asm"), String::from(".include \"delay.inc\"\nadd r1,r2,r3")),
+            (PathBuf::from("second.asm"), String::from(".include \"uart.inc\"")),
+        ];
+        let source = Source::from_sources(&sources, &[PathBuf::from("lib")], &files, &[]).unwrap();
+        let lines: Vec<(&str, String)> = source.lines.iter()
+            .map(|l| (l.text.as_str(), source.describe(l.loc)))
+            .collect();
+        assert_eq!(lines, vec![
+            ("stop", String::from("lib/delay.inc:1")),
+            ("brnv", String::from("lib/timer.inc:1")),
+            ("add r1,r2,r3", String::from("main.asm:2")),
+            ("nop", String::from("uart.inc:1")),
+        ]);
+
+        files.insert(PathBuf::from("loop.inc"), String::from(".include \"loop.inc\""));
+        let sources = vec![(PathBuf::from("main.asm"), String::from(".include \"loop.inc\""))];
+        let message = Source::from_sources(&sources, &[], &files, &[]).err().unwrap().to_string();
+        assert!(message.contains("Include cycle"), "{}", message);
+
+        // Only includes in blocks being assembled are read.
+        let sources = vec![(PathBuf::from("main.asm"), String::from("\
+.ifdef BOARD
+.include \"missing.inc\"
+.elseif 1
+.include \"uart.inc\"
+.else
+.include \"missing.inc\"
+.endif
+.equ N, 0
+.if N
+.include \"missing.inc\"
+.endif"))];
+        let source = Source::from_sources(&sources, &[], &files, &[]).unwrap();
+        assert_eq!(source.dependencies(), vec![Path::new("main.asm"), Path::new("uart.inc")]);
+        let known = vec![(String::from("BOARD"), 1)];
+        assert!(Source::from_sources(&sources, &[], &files, &known).is_err());
+    }
+}
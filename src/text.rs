@@ -0,0 +1,209 @@
+//! The versioned text format written with `-f text`: an `orange-text 1` heading, a header
+//! describing the program, then one `address<TAB>word` line per word in hexadecimal:
+//!
+//! ```text
+//! orange-text 1
+//! entry 00001004
+//! words 2
+//! checksum 26f0e121
+//! source 1c291ca3 main.asm
+//! 00001000 00000000
+//! 00001004 f8000000
+//! ```
+//!
+//! `entry` is the start address and is left out without one. `words` counts the word lines
+//! and `checksum` is the CRC-32 of the words as big-endian bytes in address order, so that a
+//! loader can tell whether it received the whole program. Each `source` line gives the CRC-32
+//! of the contents of a source assembled, then its name. Readers skip header lines they do
+//! not know, so the version only changes for additions they could not skip.
+//!
+//! Loaders written before the header read the compatibility layout instead, which has a
+//! `00000000` line in place of the heading and header and lists the words in source order.
+//! Programs are still written in that layout by default, so that those loaders keep working.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::PathBuf;
+
+use crate::checksum::crc32;
+use crate::lexer;
+
+/// The first line of every file in this format.
+pub const MAGIC: &str = "orange-text 1";
+
+/// A program read from either layout.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Image {
+    /// Every word, by address.
+    pub words: BTreeMap<usize, u32>,
+    /// The start address; `None` when the compatibility layout holds `00000000`.
+    pub entry: Option<usize>,
+    /// Each source assembled with the CRC-32 of its contents, when known.
+    pub sources: Vec<(PathBuf, u32)>,
+}
+
+/// The CRC-32 of `words` as big-endian bytes in address order.
+pub fn checksum(words: &BTreeMap<usize, u32>) -> u32 {
+    let bytes: Vec<u8> = words.values().flat_map(|x| x.to_be_bytes().to_vec()).collect();
+    crc32(&bytes)
+}
+
+pub fn write(words: &BTreeMap<usize, u32>, entry: Option<usize>, sources: &[(PathBuf, u32)]) -> String {
+    let mut s = format!("{}\n", MAGIC);
+    if let Some(x) = entry {
+        s.push_str(&format!("entry {:08x}\n", x));
+    }
+    s.push_str(&format!("words {}\n", words.len()));
+    s.push_str(&format!("checksum {:08x}\n", checksum(words)));
+    for (path, crc) in sources {
+        s.push_str(&format!("source {:08x} {}\n", crc, path.display()));
+    }
+    s.push_str(&write_words(words));
+    s
+}
+
+/// The layout from before the header, for loaders that expect it, with `words` in the order
+/// given. It has no place for a start address.
+pub fn write_compat(words: &[(usize, u32)]) -> String {
+    let mut s = String::from("00000000\n");
+    for (address, word) in words {
+        s.push_str(&format!("{:08x}\t{:08x}\n", address, word));
+    }
+    s
+}
+
+fn write_words(words: &BTreeMap<usize, u32>) -> String {
+    let mut s = String::new();
+    for (address, word) in words {
+        s.push_str(&format!("{:08x}\t{:08x}\n", address, word));
+    }
+    s
+}
+
+/// Reads either layout, checking the word count and checksum of the versioned one.
+pub fn read(text: &str) -> Result<Image, Box<dyn Error>> {
+    let mut lines = text.lines().enumerate().filter(|x| !x.1.trim().is_empty());
+    let mut image = Image::default();
+    let (count, sum) = match lines.next() {
+        Some((_, x)) if x.trim() == MAGIC => read_header(&mut lines, &mut image)?,
+        Some((_, x)) if x.starts_with("orange-text ") => bail!(format!("Unsupported version \"{}\" (expected \"{}\")", x.trim(), MAGIC)),
+        Some((index, x)) => {
+            image.entry = match parse_hex(x.trim(), index)? {
+                0 => None,
+                x => Some(x),
+            };
+            (None, None)
+        },
+        None => bail!("Expected a program, found an empty file"),
+    };
+
+    for (index, line) in lines {
+        read_word(line, index, &mut image)?;
+    }
+    match count {
+        Some(x) if x != image.words.len() => bail!(format!("Expected {} words, found {}", x, image.words.len())),
+        _ => (),
+    }
+    match sum {
+        Some(x) if x != checksum(&image.words) => bail!(format!("Checksum {:08x} does not match the words ({:08x})", x, checksum(&image.words))),
+        _ => (),
+    }
+    Ok(image)
+}
+
+/// Reads header lines into `image` up to the first word, which is read too. Returns the word
+/// count and checksum the header gives.
+fn read_header<'a, I>(lines: &mut I, image: &mut Image) -> Result<(Option<usize>, Option<u32>), Box<dyn Error>>
+where
+    I: Iterator<Item = (usize, &'a str)>,
+{
+    let (mut count, mut sum) = (None, None);
+    for (index, line) in lines {
+        let (fields, rest) = lexer::split_fields(line, 2);
+        match fields.as_slice() {
+            [x, ..] if x.chars().all(|c| c.is_ascii_hexdigit()) => {
+                read_word(line, index, image)?;
+                break;
+            },
+            ["entry", x] if rest.is_empty() => image.entry = Some(parse_hex(x, index)?),
+            ["words", x] if rest.is_empty() => count = match x.parse::<usize>() {
+                Ok(x) => Some(x),
+                Err(_) => bail!(format!("line {}: Invalid word count \"{}\"", index + 1, x)),
+            },
+            ["checksum", x] if rest.is_empty() => sum = Some(parse_hex(x, index)? as u32),
+            ["source", x] if !rest.is_empty() => image.sources.push((PathBuf::from(rest), parse_hex(x, index)? as u32)),
+            ["entry" | "words" | "checksum" | "source", ..] => bail!(format!("line {}: Could not interpret \"{}\"", index + 1, line.trim())),
+            _ => (),
+        }
+    }
+    Ok((count, sum))
+}
+
+fn read_word(line: &str, index: usize, image: &mut Image) -> Result<(), Box<dyn Error>> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    match fields.as_slice() {
+        [address, word] => {
+            let address = parse_hex(address, index)?;
+            if image.words.insert(address, parse_hex(word, index)? as u32).is_some() {
+                bail!(format!("line {}: Address {:08x} given more than once", index + 1, address));
+            }
+            Ok(())
+        },
+        _ => bail!(format!("line {}: Expected an address and a word", index + 1)),
+    }
+}
+
+fn parse_hex(text: &str, index: usize) -> Result<usize, Box<dyn Error>> {
+    match usize::from_str_radix(text, 16) {
+        Ok(x) if x < 1 << 32 => Ok(x),
+        _ => bail!(format!("line {}: Could not parse \"{}\" as a hexadecimal word", index + 1, text)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn write_read_test() {
+        let words: BTreeMap<usize, u32> = vec![(0x1000, 0), (0x1004, 0xf8000000)].into_iter().collect();
+        let sources = vec![(PathBuf::from("my dir/main.asm"), 0x1c291ca3)];
+        let text = write(&words, Some(0x1004), &sources);
+        assert_eq!(text, format!("\
+orange-text 1
+entry 00001004
+words 2
+checksum {:08x}
+source 1c291ca3 my dir/main.asm
+00001000\t00000000
+00001004\tf8000000
+", checksum(&words)));
+        assert_eq!(read(&text).unwrap(), Image { words: words.clone(), entry: Some(0x1004), sources });
+
+        let compat = write_compat(&[(0x1004, 0xf8000000), (0x1000, 0)]);
+        assert_eq!(compat, "00000000\n00001004\tf8000000\n00001000\t00000000\n");
+        assert_eq!(read(&compat).unwrap(), Image { words: words.clone(), entry: None, sources: Vec::new() });
+        assert_eq!(read("00001004\n00001000\t00000000\n00001004\tf8000000\n").unwrap().entry, Some(0x1004));
+
+        // Header lines a reader does not know are skipped.
+        let result = read("orange-text 1\nwords 1\ncreated today\n00000000\tf8000000\n").unwrap();
+        assert_eq!(result.words.len(), 1);
+
+        let invalid_tests = [
+            "",
+            "orange-text 2\n",
+            "orange-text 1\nwords 3\n00000000\t00000000\n",
+            "orange-text 1\nchecksum 00000000\n00000000\tf8000000\n",
+            "orange-text 1\nentry\n",
+            "orange-text 1\nsource 00000000\n",
+            "orange-text 1\n00000000\t00000000\nentry 00000000\n",
+            "00000000\n00000000\t00000000\n00000000\t00000001\n",
+            "00000000\n00000000\tzz\n",
+            "zz\n",
+        ];
+        for test in &invalid_tests {
+            assert!(read(test).is_err(), "failed with [{}]", test);
+        }
+    }
+}